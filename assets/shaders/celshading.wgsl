@@ -7,6 +7,7 @@
 #import bevy_pbr::lighting
 #import bevy_pbr::shadows
 #import bevy_pbr::pbr_functions
+#import planet::geo
 
 struct FragmentInput {
     @builtin(front_facing) is_front: bool,
@@ -17,6 +18,41 @@ struct FragmentInput {
 @group(1) @binding(16)
 var<uniform> quantizer: f32;
 
+@group(1) @binding(17)
+var river_map: texture_2d<f32>;
+@group(1) @binding(18)
+var river_sampler: sampler;
+@group(1) @binding(19)
+var<uniform> river_strength: f32;
+
+@group(1) @binding(20)
+var water_map: texture_2d<f32>;
+@group(1) @binding(21)
+var water_sampler: sampler;
+
+@group(1) @binding(23)
+var plate_map: texture_2d<f32>;
+@group(1) @binding(24)
+var plate_sampler: sampler;
+@group(1) @binding(25)
+var<uniform> plate_strength: f32;
+
+@group(1) @binding(26)
+var biome_map: texture_2d<f32>;
+@group(1) @binding(27)
+var biome_sampler: sampler;
+@group(1) @binding(28)
+var biome_palette: texture_2d<f32>;
+@group(1) @binding(29)
+var biome_palette_sampler: sampler;
+@group(1) @binding(30)
+var<uniform> biome_strength: f32;
+
+// Coordinates in the planet's maps of a fragment on its surface
+fn map_uv(world_position: vec4<f32>) -> vec2<f32> {
+    return sphere_uv(normalize(world_position.xyz - mesh.model[3].xyz));
+}
+
 fn quantize_one(in: f32) -> f32 {
     if in < 0.3 {
         return f32(i32(in * quantizer * 2.0)) / quantizer / 2.0;
@@ -160,6 +196,27 @@ fn fragment(in: FragmentInput) -> @location(0) vec4<f32> {
     }
 #endif
 
+    let uv = map_uv(in.world_position);
+    // The biome map holds indices into the palette, one texel per biome
+    let biome = i32(round(textureSampleLevel(biome_map, biome_sampler, uv, 0.0).r * 255.0));
+    let biome_color = textureLoad(biome_palette, vec2<i32>(biome, 0), 0);
+    output_color = mix(output_color, vec4<f32>(biome_color.rgb, output_color.a), biome_strength);
+
+    let river = textureSampleLevel(river_map, river_sampler, uv, 0.0).r;
+    output_color = mix(output_color, vec4<f32>(0.1, 0.3, 0.8, output_color.a), river * river_strength);
+
+    // The water mask is black for oceans, grey for lakes and white for land
+    let water = textureSampleLevel(water_map, water_sampler, uv, 0.0).r;
+    if (water < 0.25) {
+        output_color = vec4<f32>(0.05, 0.15, 0.5, output_color.a);
+    } else if (water < 0.75) {
+        output_color = vec4<f32>(0.15, 0.35, 0.7, output_color.a);
+    }
+
+    // Plates are tinted with their boundaries drawn over, alpha holds how strongly
+    let plate = textureSampleLevel(plate_map, plate_sampler, uv, 0.0);
+    output_color = mix(output_color, vec4<f32>(plate.rgb, output_color.a), plate.a * plate_strength);
+
     // NOTE: Unlit bit not set means == 0 is true, so the true case is if lit
     if ((material.flags & STANDARD_MATERIAL_FLAGS_UNLIT_BIT) == 0u) {
         // Prepare a 'processed' StandardMaterial by sampling all textures to resolve
@@ -3,6 +3,7 @@
 
 // NOTE: Bindings must come before functions that use them!
 #import bevy_pbr::mesh_functions
+#import planet::geo
 
 struct Vertex {
     @location(0) position: vec3<f32>,
@@ -34,6 +35,16 @@ var second_sampler: sampler;
 @group(1) @binding(15)
 var<uniform> interp: f32;
 
+@group(1) @binding(20)
+var water_map: texture_2d<f32>;
+@group(1) @binding(21)
+var water_sampler: sampler;
+@group(1) @binding(22)
+var<uniform> sea_level: f32;
+
+@group(1) @binding(31)
+var<uniform> elevation_decode: vec2<f32>;
+
 
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
@@ -51,15 +62,27 @@ fn vertex(vertex: Vertex) -> VertexOutput {
     out.world_normal = mesh_normal_local_to_world(vertex.normal);
 #endif
 
-    let r = sqrt(vertex.position.x*vertex.position.x + vertex.position.y * vertex.position.y + vertex.position.z*vertex.position.z);
-    let phi = acos(vertex.position.z / r);
-    let theta = atan2(vertex.position.y, vertex.position.x);
-
-    let uv = vec2(phi / 6.283185, theta / 3.1415926 + 0.5);
-    let interpolated = (1.0 - interp) * textureSampleLevel(elevation_map, elevation_sampler, uv, 0.0).r + interp * textureSampleLevel(second_map, second_sampler, uv, 0.0).r;
+    let r = length(vertex.position);
+    let direction = vertex.position / r;
+    // Overlays and masks are always equirectangular, elevation may be a cube-sphere atlas
+    let uv = sphere_uv(direction);
+#ifdef CUBE_SPHERE
+    let first_uv = cube_uv(direction, f32(textureDimensions(elevation_map).x) / f32(GEO_CUBE_COLUMNS));
+    let second_uv = cube_uv(direction, f32(textureDimensions(second_map).x) / f32(GEO_CUBE_COLUMNS));
+#else
+    let first_uv = uv;
+    let second_uv = uv;
+#endif
+    var interpolated = (1.0 - interp) * textureSampleLevel(elevation_map, elevation_sampler, first_uv, 0.0).r + interp * textureSampleLevel(second_map, second_sampler, second_uv, 0.0).r;
+    // Both textures share a format, and normalized formats only store elevations within a range
+    interpolated = elevation_decode.x + interpolated * elevation_decode.y;
+    // Oceans and lakes lie flat at sea level, the mask is white on land
+    if (textureSampleLevel(water_map, water_sampler, uv, 0.0).r < 0.75) {
+        interpolated = max(interpolated, sea_level);
+    }
     let new_r = r + interpolated;
-    
-    let position = vec3(new_r * sin(phi) * cos(theta), new_r * sin(theta) * sin(phi), new_r * cos(phi));
+
+    let position = direction * new_r;
     out.world_position = mesh_position_local_to_world(model, vec4<f32>(position, 1.0));
 #ifdef VERTEX_UVS
     out.uv = vertex.uv;
@@ -0,0 +1,76 @@
+#define_import_path planet::geo
+
+// Mirrors src/generate_world/geo.rs, which must be kept in step with this file.
+// Latitude runs from -PI/2 at the south pole (z = -1) to PI/2 at the north pole,
+// longitude from -PI at the left edge of the maps to PI at the right, with 0 on the x axis.
+// Constants carry a prefix so they don't clash with those of the modules importing this one.
+
+let GEO_PI: f32 = 3.14159265358979;
+let GEO_TAU: f32 = 6.28318530717958;
+
+// Latitude and longitude in radians of a point on the unit sphere
+fn lat_lon(direction: vec3<f32>) -> vec2<f32> {
+    return vec2<f32>(asin(clamp(direction.z, -1.0, 1.0)), atan2(direction.y, direction.x));
+}
+
+// Coordinates of a point on the unit sphere in the equirectangular maps
+fn sphere_uv(direction: vec3<f32>) -> vec2<f32> {
+    let angles = lat_lon(direction);
+    return vec2<f32>(angles.y / GEO_TAU + 0.5, angles.x / GEO_PI + 0.5);
+}
+
+let GEO_FRAC_PI_4: f32 = 0.78539816339745;
+let GEO_CUBE_COLUMNS: i32 = 3;
+let GEO_CUBE_ROWS: i32 = 2;
+
+// Cube faces fill the atlas in the order +x, -x, +y, -y, +z, -z, left to right then top to bottom,
+// in GEO_CUBE_COLUMNS columns and GEO_CUBE_ROWS rows.
+// Texel coordinates on a face are spread by angle rather than along the face.
+fn unwarp(s: f32) -> f32 {
+    return atan(s) / GEO_FRAC_PI_4;
+}
+
+// Coordinates of a point on the unit sphere in the cube-sphere atlas, faces `size` texels wide.
+// Samples are kept half a texel inside their face so filtering doesn't bleed into the next one.
+fn cube_uv(direction: vec3<f32>, size: f32) -> vec2<f32> {
+    let a = abs(direction);
+    var face: i32;
+    var s: f32;
+    var t: f32;
+    if (a.x >= a.y && a.x >= a.z) {
+        if (direction.x > 0.0) {
+            face = 0;
+            s = direction.y / a.x;
+        } else {
+            face = 1;
+            s = -direction.y / a.x;
+        }
+        t = direction.z / a.x;
+    } else if (a.y >= a.z) {
+        if (direction.y > 0.0) {
+            face = 2;
+            s = -direction.x / a.y;
+        } else {
+            face = 3;
+            s = direction.x / a.y;
+        }
+        t = direction.z / a.y;
+    } else {
+        if (direction.z > 0.0) {
+            face = 4;
+            t = -direction.x / a.z;
+        } else {
+            face = 5;
+            t = direction.x / a.z;
+        }
+        s = direction.y / a.z;
+    }
+
+    let margin = 0.5 / size;
+    let u = clamp((unwarp(s) + 1.0) / 2.0, margin, 1.0 - margin);
+    let v = clamp((unwarp(t) + 1.0) / 2.0, margin, 1.0 - margin);
+    return vec2<f32>(
+        (f32(face % GEO_CUBE_COLUMNS) + u) / f32(GEO_CUBE_COLUMNS),
+        (f32(face / GEO_CUBE_COLUMNS) + v) / f32(GEO_CUBE_ROWS)
+    );
+}
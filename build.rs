@@ -0,0 +1,45 @@
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+// Fingerprint of the sources and locked dependencies, so worlds cached by one build are never read
+// back by another raising them differently
+fn main() {
+    let root = PathBuf::from(env::var("CARGO_MANIFEST_DIR").expect("Cargo sets the manifest dir"));
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=Cargo.lock");
+
+    let mut files = Vec::new();
+    list_files(&root.join("src"), &mut files).expect("Could not list the sources");
+    files.sort();
+    files.push(root.join("Cargo.lock"));
+
+    // FNV-1a, like the cache keys. Paths are hashed relative to the package, so the same sources
+    // checked out elsewhere share a fingerprint
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for path in files {
+        let contents = match fs::read(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => panic!("Could not read {:?}: {}", path, err),
+        };
+        let name = path.strip_prefix(&root).unwrap_or(&path).to_string_lossy();
+        for byte in name.bytes().chain([0]).chain(contents) {
+            hash = (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    println!("cargo:rustc-env=URBANITE_BUILD_FINGERPRINT={:016x}", hash);
+}
+
+fn list_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            list_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
@@ -1,22 +1,77 @@
-use crate::GameState;
+use crate::{mainmenu::MenuMessage, GameState};
 use bevy::{
+    asset::load_internal_asset,
+    ecs::system::SystemParam,
     input::mouse::{MouseMotion, MouseScrollUnit, MouseWheel},
     prelude::*,
-    tasks::{AsyncComputeTaskPool, Task},
+    render::renderer::RenderDevice,
+    tasks::{AsyncComputeTaskPool, IoTaskPool, Task},
 };
 use futures_lite::future;
 use iyes_loopless::prelude::*;
+use std::{
+    marker::PhantomData,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use self::{
-    generation::{GenerationTask, SimplexGenerator, WorldGenerator},
+    biomes::{Biome, BiomeJob, BiomeMap, BiomeRules},
+    erosion::{HydraulicErosion, ThermalErosion},
+    export::ElevationExport,
+    generation::{GeneratedWorld, GenerationTask, Previews},
+    geo,
+    hypsometry::{HypsometricCurves, Hypsometry},
+    noise_graph::{NoiseGraph, NoiseGraphLoader},
+    rivers::{RiverSettings, Rivers},
     shader::GenerationMaterial,
+    stats::{StatsJob, WorldStats},
+    water::{SeaLevel, WaterJob, WaterMap},
 };
 
-const RADIUS: f32 = 3.0;
-const SIZE: u32 = 6000;
+pub use self::{
+    generation::{GenerationError, MapLayout, Passes, Progress, WorldGenSettings, WorldGenerator},
+    heightmap::{ElevationFormat, Heightmap},
+    planet::{Filter, Planet},
+    registry::{
+        GeneratorChoice, GeneratorEntry, GeneratorParams, GeneratorRegistry, ParamSchema,
+        ParamValue, RegistryError,
+    },
+    tectonics::{Boundary, Plate, PlateMap},
+    world_data::{ClimateSettings, Layer, WorldData},
+};
 
+pub(crate) use self::{cache::WorldCache, continents::PRESETS as CONTINENT_PRESETS};
+
+const RADIUS: f32 = 3.0;
+// Previews shown while the full map is generated, as divisors of its width and height
+const PREVIEW_SCALES: [u32; 2] = [16, 4];
+// Noise graph raising the world by default, hot-reloaded when edited
+const NOISE_GRAPH: &str = "worldgen/default.worldgen.ron";
+
+mod biomes;
+mod cache;
+mod continents;
+mod craters;
+mod erosion;
+mod export;
+#[cfg(test)]
+mod fixtures;
 mod generation;
+mod geo;
+mod heightmap;
+mod hypsometry;
+mod import;
+mod noise_graph;
+mod planet;
+mod registry;
+mod rivers;
 mod shader;
+mod sphere;
+mod stats;
+mod tectonics;
+mod water;
+mod world_data;
 
 // Tag for entities belonging to the game state
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Component)]
@@ -26,8 +81,39 @@ struct WorldTag;
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Component)]
 struct InterpTag;
 
+// Marker component for the generation progress text
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Component)]
+struct ProgressText;
+
+// Marker component for the panel of world statistics
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Component)]
+struct StatsPanel;
+
+#[derive(Component)]
+struct GenerateTask {
+    task: Task<Result<GeneratedWorld, GenerationError>>,
+    progress: Arc<Progress>,
+    previews: Previews,
+}
+
+// Oceans, lakes and rivers being worked out again for a new map or sea level
+#[derive(Component)]
+struct WaterTask(Task<Result<(WaterMap, Option<Rivers>), GenerationError>>);
+
+// Statistics being measured again at a new sea level
 #[derive(Component)]
-struct GenerateTask(Task<Option<Image>>);
+struct StatsTask(Task<Result<WorldStats, GenerationError>>);
+
+// Biomes being worked out again for a new map, new rules or a new sea level
+#[derive(Component)]
+struct BiomeTask(Task<Result<BiomeMap, GenerationError>>);
+
+// Sent when generation ends without a world, unless it was cancelled
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct GenerationFailed(GenerationError);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct NoiseGraphHandle(Handle<NoiseGraph>);
 
 // Tag for orbit camera
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Component)]
@@ -38,9 +124,65 @@ pub(crate) struct WorldGenerate;
 
 impl Plugin for WorldGenerate {
     fn build(&self, app: &mut App) {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time moved backwards")
+            .as_secs() as u32;
+
+        // Picked on the command line, or a heightmap file to raise the world from,
+        // e.g. a hand-made one or real elevation data, or else the noise graph
+        let choice = GeneratorChoice::from_args(std::env::args().skip(1))
+            .or_else(|| {
+                let path = std::env::var("URBANITE_HEIGHTMAP").ok()?;
+                Some(GeneratorChoice {
+                    name: "import".into(),
+                    params: GeneratorParams::default().with("path", ParamValue::Text(path)),
+                })
+            })
+            .unwrap_or_default();
+
+        // Maps keep the land they were raised with, unless a share of land is asked for. The
+        // curves it is remapped along can be changed on their own.
+        if let Some(hypsometry) = Hypsometry::from_args(std::env::args().skip(1)) {
+            app.insert_resource(hypsometry);
+        }
+        app.init_resource::<HypsometricCurves>();
+
+        // Worlds raised before are read back from the cache, if the platform has somewhere to
+        // keep it
+        match WorldCache::in_cache_dir() {
+            Some(cache) => {
+                app.insert_resource(cache);
+            }
+            None => warn!("No cache directory, worlds will be raised every time"),
+        }
+
+        load_internal_asset!(
+            app,
+            shader::GEO_SHADER_HANDLE,
+            "../assets/shaders/geo.wgsl",
+            Shader::from_wgsl
+        );
+
         app.add_plugin(MaterialPlugin::<shader::GenerationMaterial>::default())
+            .insert_resource(WorldGenSettings { seed, ..default() })
+            .init_resource::<GeneratorRegistry>()
+            .insert_resource(choice)
+            .add_event::<GenerationFailed>()
+            .add_asset::<NoiseGraph>()
+            .init_asset_loader::<NoiseGraphLoader>()
+            .add_startup_system(load_noise_graph)
+            .add_system(reload_noise_graph)
+            .insert_resource(HydraulicErosion::default())
+            .insert_resource(ThermalErosion::default())
+            .insert_resource(RiverSettings::default())
+            .insert_resource(SeaLevel::default())
+            .insert_resource(ClimateSettings::default())
+            .insert_resource(BiomeRules::default())
             .add_enter_system(GameState::WorldGenerate, game_startup)
             .add_enter_system(GameState::WorldGenerate, grab_cursor)
+            .add_enter_system(GameState::WorldGenerate, spawn_progress_text)
+            .add_enter_system(GameState::WorldGenerate, spawn_stats_panel)
             .add_system_set(
                 ConditionSet::new()
                     .run_in_state(GameState::WorldGenerate)
@@ -48,12 +190,24 @@ impl Plugin for WorldGenerate {
                     .with_system(movement)
                     .with_system(interpolate)
                     .with_system(poll_task)
+                    .with_system(return_on_failure)
+                    .with_system(show_progress)
+                    .with_system(adjust_sea_level)
+                    .with_system(classify_water)
+                    .with_system(classify_biomes)
+                    .with_system(compute_stats)
+                    .with_system(toggle_stats)
+                    .with_system(show_stats)
+                    .with_system(toggle_plates)
+                    .with_system(export_map)
                     .into(),
             )
+            .add_exit_system(GameState::WorldGenerate, cancel_tasks)
             .add_exit_system(GameState::WorldGenerate, crate::teardown::<GameTag>)
             .add_exit_system(GameState::WorldGenerate, release_cursor)
             .add_exit_system(GameState::WorldGenerate, remove_ambient)
-            .add_exit_system(GameState::WorldGenerate, remove_orbit);
+            .add_exit_system(GameState::WorldGenerate, remove_orbit)
+            .add_exit_system(GameState::WorldGenerate, remove_world_data);
     }
 }
 
@@ -61,34 +215,540 @@ fn remove_ambient(mut commands: Commands) {
     commands.insert_resource(AmbientLight { ..default() });
 }
 
+fn remove_world_data(mut commands: Commands) {
+    commands.remove_resource::<Pipeline>();
+    commands.remove_resource::<WorldData>();
+    commands.remove_resource::<Rivers>();
+    commands.remove_resource::<WaterMap>();
+    commands.remove_resource::<BiomeMap>();
+    commands.remove_resource::<WorldStats>();
+    commands.remove_resource::<Planet>();
+    commands.remove_resource::<Passes>();
+}
+
 fn return_on_esc(mut commands: Commands, keys: Res<Input<KeyCode>>) {
     if keys.just_pressed(KeyCode::Escape) {
         commands.insert_resource(NextState(GameState::MainMenu));
     }
 }
 
+// Tell the player why no world was raised back in the menu, rather than leave them looking
+// at a bare sphere
+fn return_on_failure(mut commands: Commands, mut events: EventReader<GenerationFailed>) {
+    if let Some(GenerationFailed(err)) = events.iter().last() {
+        warn!("World generation failed: {}", err);
+        commands.insert_resource(MenuMessage(format!("World generation failed: {}", err)));
+        commands.insert_resource(NextState(GameState::MainMenu));
+    }
+}
+
+// Morph the planet from its previous elevation texture to the latest one over a second
 fn interpolate(
     mut materials: ResMut<Assets<GenerationMaterial>>,
     q: Query<&Handle<GenerationMaterial>, With<InterpTag>>,
-    mut dir: Local<f32>,
     t: Res<Time>,
 ) {
     for handle in &q {
-        if *dir == 0.0 {
-            *dir = 1.0;
+        if let Some(mat) = materials.get_mut(handle) {
+            mat.interp = (mat.interp + t.delta().as_secs_f32()).min(1.0);
         }
+    }
+}
 
-        if let Some(mat) = materials.get_mut(handle) {
-            mat.interp += *dir * t.delta().as_secs_f32();
-            if mat.interp > 1.0 {
-                mat.interp = 1.0;
-                *dir = -1.0;
-            } else if mat.interp < 0.0 {
-                mat.interp = 0.0;
-                *dir = 1.0;
+// Start morphing from the elevation shown last to a new, more detailed one
+fn morph_to(mat: &mut GenerationMaterial, elevation: Handle<Image>) {
+    if let Some(previous) = mat.elevation_other.replace(elevation) {
+        mat.elevation_texture = Some(previous);
+    }
+    mat.interp = 0.0;
+}
+
+// Stop any running generation, the thread would otherwise run to completion
+fn cancel_tasks(q: Query<&GenerateTask>) {
+    for task in &q {
+        task.progress.cancel();
+    }
+}
+
+// Show how far generation got, then the climate of the point below the camera once done
+fn show_progress(
+    tasks: Query<&GenerateTask>,
+    data: Option<Res<WorldData>>,
+    camera: Query<&Transform, With<Orbit>>,
+    mut q: Query<&mut Text, With<ProgressText>>,
+) {
+    let status = tasks
+        .iter()
+        .next()
+        .map(|task| {
+            format!(
+                "Generating {}: {:.0}%",
+                task.progress.stage(),
+                task.progress.fraction() * 100.0
+            )
+        })
+        .or_else(|| {
+            let data = data?;
+            let position = camera.iter().next()?.translation.normalize();
+            let (latitude, longitude) = geo::lat_lon(position);
+
+            Some(format!(
+                "{:.1}, {:.1}: elevation {:.2}, {:.0}°C, humidity {:.0}%",
+                latitude.to_degrees(),
+                longitude.to_degrees(),
+                data.sample(Layer::Elevation, position),
+                data.sample(Layer::Temperature, position),
+                data.sample(Layer::Moisture, position) * 100.0
+            ))
+        });
+
+    for mut text in &mut q {
+        for section in &mut text.sections {
+            section.value = status.clone().unwrap_or_default();
+        }
+    }
+}
+
+fn spawn_progress_text(
+    mut commands: Commands,
+    uifont: Res<crate::UiFont>,
+    q: Query<Entity, With<crate::UiRoot>>,
+) {
+    let text = commands
+        .spawn_bundle(
+            TextBundle::from_section(
+                "",
+                TextStyle {
+                    color: Color::WHITE,
+                    font: uifont.0.clone(),
+                    font_size: 24.0,
+                },
+            )
+            .with_style(Style {
+                position_type: PositionType::Absolute,
+                position: UiRect {
+                    top: Val::Px(5.0),
+                    left: Val::Px(30.0),
+                    ..default()
+                },
+                ..default()
+            }),
+        )
+        .insert(GameTag)
+        .insert(ProgressText)
+        .id();
+
+    commands.entity(q.single()).add_child(text);
+}
+
+// Hidden until toggled
+fn spawn_stats_panel(
+    mut commands: Commands,
+    uifont: Res<crate::UiFont>,
+    q: Query<Entity, With<crate::UiRoot>>,
+) {
+    let panel = commands
+        .spawn_bundle(
+            TextBundle::from_section(
+                "",
+                TextStyle {
+                    color: Color::WHITE,
+                    font: uifont.0.clone(),
+                    font_size: 16.0,
+                },
+            )
+            .with_style(Style {
+                position_type: PositionType::Absolute,
+                position: UiRect {
+                    top: Val::Px(40.0),
+                    right: Val::Px(30.0),
+                    ..default()
+                },
+                ..default()
+            }),
+        )
+        .insert(Visibility { is_visible: false })
+        .insert(GameTag)
+        .insert(StatsPanel)
+        .id();
+
+    commands.entity(q.single()).add_child(panel);
+}
+
+fn toggle_stats(keys: Res<Input<KeyCode>>, mut q: Query<&mut Visibility, With<StatsPanel>>) {
+    if !keys.just_pressed(KeyCode::F3) {
+        return;
+    }
+
+    for mut visibility in &mut q {
+        visibility.is_visible = !visibility.is_visible;
+    }
+}
+
+// Measure the map again in the background whenever the sea level changes, one measurement at a
+// time. Worlds arrive measured at the sea level they were raised at.
+fn compute_stats(
+    mut commands: Commands,
+    sea_level: Res<SeaLevel>,
+    data: Option<Res<WorldData>>,
+    stats: Option<Res<WorldStats>>,
+    mut tasks: Query<(Entity, &mut StatsTask)>,
+) {
+    let data = match data {
+        Some(data) => data,
+        None => return,
+    };
+
+    // Statistics of the previous map are of no use for a new one
+    if data.is_changed() {
+        for (entity, _) in &tasks {
+            commands.entity(entity).despawn();
+        }
+    } else if let Some((entity, mut task)) = tasks.iter_mut().next() {
+        // A finished task must not be polled again
+        match future::block_on(future::poll_once(&mut task.0)) {
+            Some(Ok(stats)) => commands.insert_resource(stats),
+            Some(Err(_)) => {}
+            None => return,
+        }
+        commands.entity(entity).despawn();
+        return;
+    }
+
+    // Statistics left over from the previous map don't count, unless they came with the new one
+    let current = stats.map_or(false, |stats| {
+        stats.sea_level == sea_level.0 && (stats.is_changed() || !data.is_changed())
+    });
+    if current {
+        return;
+    }
+
+    // Plates aren't measured, so they stay behind
+    let job = StatsJob {
+        data: WorldData {
+            elevation: data.elevation.clone(),
+            temperature: data.temperature.clone(),
+            moisture: data.moisture.clone(),
+            plates: None,
+        },
+        sea_level: sea_level.0,
+    };
+    let task = AsyncComputeTaskPool::get().spawn(GenerationTask::from_job(job));
+    commands.spawn().insert(StatsTask(task)).insert(GameTag);
+}
+
+fn show_stats(stats: Option<Res<WorldStats>>, mut q: Query<&mut Text, With<StatsPanel>>) {
+    let stats = match stats {
+        Some(stats) if stats.is_changed() => stats,
+        _ => return,
+    };
+
+    for mut text in &mut q {
+        for section in &mut text.sections {
+            section.value = stats.to_string();
+        }
+    }
+}
+
+fn adjust_sea_level(keys: Res<Input<KeyCode>>, mut sea_level: ResMut<SeaLevel>) {
+    let step = 0.02;
+
+    if keys.just_pressed(KeyCode::PageUp) {
+        sea_level.0 += step;
+    } else if keys.just_pressed(KeyCode::PageDown) {
+        sea_level.0 -= step;
+    }
+}
+
+fn toggle_plates(
+    keys: Res<Input<KeyCode>>,
+    mut mats: ResMut<Assets<GenerationMaterial>>,
+    m: Query<&Handle<GenerationMaterial>>,
+) {
+    if !keys.just_pressed(KeyCode::P) {
+        return;
+    }
+
+    for handle in &m {
+        if let Some(mat) = mats.get_mut(handle) {
+            mat.plate_strength = 1.0 - mat.plate_strength;
+        }
+    }
+}
+
+// Write the elevation map to the user's data directory, without holding up the game
+fn export_map(
+    keys: Res<Input<KeyCode>>,
+    settings: Res<WorldGenSettings>,
+    data: Option<Res<WorldData>>,
+    stats: Option<Res<WorldStats>>,
+) {
+    let data = match data {
+        Some(data) if keys.just_pressed(KeyCode::F12) => data,
+        _ => return,
+    };
+
+    let map = data.elevation.clone();
+    let stats = stats.map(|stats| stats.clone());
+    let seed = settings.seed;
+    // Exports go into the data directory, never wherever the game was started from
+    let directory = match dirs::data_dir() {
+        Some(directory) => directory.join("urbanite").join("exports"),
+        None => {
+            warn!("Could not export map: no data directory to export it to");
+            return;
+        }
+    };
+    IoTaskPool::get()
+        .spawn(async move {
+            let name = format!("world-{}", seed);
+            let mut export = ElevationExport::new(&map, seed);
+            if let Some(stats) = &stats {
+                export = export.with_stats(stats);
+            }
+            match export.save(&directory, &name) {
+                Ok(paths) => info!("Exported map to {:?}", paths),
+                Err(err) => warn!("Could not export map: {}", err),
+            }
+        })
+        .detach();
+}
+
+fn load_noise_graph(mut commands: Commands, assets: Res<AssetServer>) {
+    commands.insert_resource(NoiseGraphHandle(assets.load(NOISE_GRAPH)));
+}
+
+// The bundled noise graph and news of it loading or being edited
+#[derive(SystemParam)]
+struct NoiseGraphAsset<'w, 's> {
+    events: EventReader<'w, 's, AssetEvent<NoiseGraph>>,
+    graphs: Res<'w, Assets<NoiseGraph>>,
+    handle: Option<Res<'w, NoiseGraphHandle>>,
+}
+
+impl NoiseGraphAsset<'_, '_> {
+    // The graph, if it loaded or changed since last asked
+    fn changed(&mut self) -> Option<NoiseGraph> {
+        let handle = self.handle.as_ref()?;
+        let changed = self.events.iter().any(|event| match event {
+            AssetEvent::Created { handle: changed } | AssetEvent::Modified { handle: changed } => {
+                *changed == handle.0
+            }
+            AssetEvent::Removed { .. } => false,
+        });
+        match self.graphs.get(&handle.0) {
+            Some(graph) if changed => Some(graph.clone()),
+            _ => None,
+        }
+    }
+}
+
+// How the world shown was raised, to raise it again
+#[derive(SystemParam)]
+struct Regeneration<'w, 's> {
+    choice: Res<'w, GeneratorChoice>,
+    pipeline: Option<Res<'w, Pipeline>>,
+    cache: Option<Res<'w, WorldCache>>,
+    failed: EventWriter<'w, 's, GenerationFailed>,
+}
+
+// Register the noise graph as the "graph" generator whenever it loads, and raise the world
+// again when it is edited while the world is shown
+fn reload_noise_graph(
+    mut commands: Commands,
+    mut asset: NoiseGraphAsset,
+    mut registry: ResMut<GeneratorRegistry>,
+    mut regeneration: Regeneration,
+    tasks: Query<Entity, With<GenerateTask>>,
+) {
+    let graph = match asset.changed() {
+        Some(graph) => graph,
+        None => return,
+    };
+
+    registry.register_graph(graph);
+
+    let choice = &regeneration.choice;
+    let pipeline = match &regeneration.pipeline {
+        Some(pipeline) if choice.name == "graph" => pipeline,
+        _ => return,
+    };
+    info!("Noise graph changed, raising the world again");
+    for task in &tasks {
+        commands.entity(task).despawn_recursive();
+    }
+    match pipeline.start(&registry, choice, regeneration.cache.as_deref()) {
+        Ok(task) => {
+            commands.spawn().insert(task).insert(GameTag);
+        }
+        Err(err) => regeneration.failed.send(GenerationFailed(err)),
+    }
+}
+
+// The world shown, how it was raised and the sea level it is shown at
+#[derive(SystemParam)]
+struct ShownWorld<'w, 's> {
+    sea_level: Res<'w, SeaLevel>,
+    data: Option<Res<'w, WorldData>>,
+    pipeline: Option<Res<'w, Pipeline>>,
+    passes: Option<Res<'w, Passes>>,
+    #[system_param(ignore)]
+    marker: PhantomData<&'s ()>,
+}
+
+// Textures of the planet's material, to swap in layers worked out anew
+#[derive(SystemParam)]
+struct MaterialTextures<'w, 's> {
+    imgs: ResMut<'w, Assets<Image>>,
+    mats: ResMut<'w, Assets<GenerationMaterial>>,
+    m: Query<'w, 's, &'static Handle<GenerationMaterial>>,
+}
+
+// Split the map into land, oceans and lakes whenever the map or the sea level changes, and let
+// rivers run down to the new coast. The whole map is flooded in the background, one flood at a
+// time, so a sea level stepped through quickly only floods the map again once the last is done.
+// Worlds whose generator has no water stay dry.
+fn classify_water(
+    mut commands: Commands,
+    world: ShownWorld,
+    water: Option<Res<WaterMap>>,
+    rivers: Option<Res<Rivers>>,
+    planet: Option<ResMut<Planet>>,
+    mut tasks: Query<(Entity, &mut WaterTask)>,
+    mut textures: MaterialTextures,
+) {
+    let sea_level = world.sea_level.0;
+    let data = match &world.data {
+        Some(data) if world.passes.as_deref().map_or(true, |passes| passes.water) => data,
+        _ => return,
+    };
+
+    // Water of the previous map is of no use for a new one
+    if data.is_changed() {
+        for (entity, _) in &tasks {
+            commands.entity(entity).despawn();
+        }
+    } else if let Some((entity, mut task)) = tasks.iter_mut().next() {
+        // A finished task must not be polled again
+        let (water, rivers) = match future::block_on(future::poll_once(&mut task.0)) {
+            Some(Ok(flooded)) => flooded,
+            Some(Err(_)) => {
+                commands.entity(entity).despawn();
+                return;
+            }
+            None => return,
+        };
+        commands.entity(entity).despawn();
+
+        for handle in &textures.m {
+            if let Some(mat) = textures.mats.get_mut(handle) {
+                mat.water_texture = Some(textures.imgs.add(water.to_image()));
+                mat.sea_level = water.sea_level;
+                if let Some(rivers) = &rivers {
+                    mat.river_texture = Some(textures.imgs.add(rivers.to_image()));
+                }
             }
         }
+        if let Some(mut planet) = planet {
+            planet.set_water(&water);
+        }
+        if let Some(rivers) = rivers {
+            commands.insert_resource(rivers);
+        }
+        commands.insert_resource(water);
+        return;
     }
+
+    let current = water.map_or(false, |water| water.sea_level == sea_level);
+    if current && !data.is_changed() {
+        return;
+    }
+
+    // Rivers extracted with the world already end at this sea level
+    let moved = rivers.map_or(true, |rivers| rivers.sea_level != sea_level);
+    let job = WaterJob {
+        elevation: data.elevation.clone(),
+        sea_level,
+        rivers: world
+            .pipeline
+            .as_deref()
+            .and_then(|pipeline| pipeline.rivers)
+            .filter(|_| moved),
+    };
+    let task = AsyncComputeTaskPool::get().spawn(GenerationTask::from_job(job));
+    commands.spawn().insert(WaterTask(task)).insert(GameTag);
+}
+
+// Paint the planet by biome whenever the climate, the rules or the sea level change, on worlds
+// with a climate. Biomes are worked out in the background, one map at a time, like the water.
+fn classify_biomes(
+    mut commands: Commands,
+    world: ShownWorld,
+    rules: Res<BiomeRules>,
+    biomes: Option<Res<BiomeMap>>,
+    mut tasks: Query<(Entity, &mut BiomeTask)>,
+    mut textures: MaterialTextures,
+) {
+    let sea_level = world.sea_level.0;
+    let data = match &world.data {
+        Some(data)
+            if world
+                .passes
+                .as_deref()
+                .map_or(true, |passes| passes.climate) =>
+        {
+            data
+        }
+        _ => return,
+    };
+
+    // Biomes of the previous map or rules are of no use for new ones
+    if data.is_changed() || rules.is_changed() {
+        for (entity, _) in &tasks {
+            commands.entity(entity).despawn();
+        }
+    } else if let Some((entity, mut task)) = tasks.iter_mut().next() {
+        // A finished task must not be polled again
+        let biomes = match future::block_on(future::poll_once(&mut task.0)) {
+            Some(Ok(biomes)) => Some(biomes),
+            Some(Err(_)) => None,
+            None => return,
+        };
+        commands.entity(entity).despawn();
+
+        if let Some(biomes) = biomes {
+            for handle in &textures.m {
+                if let Some(mat) = textures.mats.get_mut(handle) {
+                    mat.biome_texture = Some(textures.imgs.add(biomes.to_image()));
+                    if mat.biome_palette.is_none() {
+                        mat.biome_palette = Some(textures.imgs.add(Biome::palette()));
+                    }
+                    mat.biome_strength = 1.0;
+                }
+            }
+            commands.insert_resource(biomes);
+        }
+        return;
+    }
+
+    let current = biomes.map_or(false, |biomes| biomes.sea_level == sea_level);
+    if current && !data.is_changed() && !rules.is_changed() {
+        return;
+    }
+
+    // Plates play no part in biomes, so they stay behind
+    let job = BiomeJob {
+        data: WorldData {
+            elevation: data.elevation.clone(),
+            temperature: data.temperature.clone(),
+            moisture: data.moisture.clone(),
+            plates: None,
+        },
+        rules: rules.clone(),
+        sea_level,
+    };
+    let task = AsyncComputeTaskPool::get().spawn(GenerationTask::from_job(job));
+    commands.spawn().insert(BiomeTask(task)).insert(GameTag);
 }
 
 fn poll_task(
@@ -98,16 +758,204 @@ fn poll_task(
     mut mats: ResMut<Assets<shader::GenerationMaterial>>,
     m: Query<&Handle<GenerationMaterial>>,
     world: Query<Entity, With<WorldTag>>,
+    mut failed: EventWriter<GenerationFailed>,
 ) {
     let genmat = m.single();
     let world = world.single();
     for (entity, mut task) in &mut q {
-        if let Some(Some(img)) = future::block_on(future::poll_once(&mut task.0)) {
+        if let Some(preview) = task.previews.latest() {
             if let Some(mat) = mats.get_mut(genmat) {
-                mat.elevation_other = Some(imgs.add(img));
+                morph_to(mat, imgs.add(preview));
             }
             commands.entity(world).insert(InterpTag);
-            commands.entity(entity).despawn_recursive();
+        }
+
+        // A finished task must not be polled again
+        let generated = match future::block_on(future::poll_once(&mut task.task)) {
+            Some(Ok(generated)) => generated,
+            Some(Err(GenerationError::Cancelled)) => {
+                commands.entity(entity).despawn_recursive();
+                continue;
+            }
+            Some(Err(err)) => {
+                failed.send(GenerationFailed(err));
+                commands.entity(entity).despawn_recursive();
+                continue;
+            }
+            None => continue,
+        };
+
+        commands.insert_resource(generated.planet);
+        if let Some(mat) = mats.get_mut(genmat) {
+            morph_to(mat, imgs.add(generated.elevation));
+            mat.elevation_decode = generated.format.decode();
+            mat.cube_sphere = generated.layout == MapLayout::CubeSphere;
+            if let Some(rivers) = &generated.rivers {
+                mat.river_texture = Some(imgs.add(rivers.to_image()));
+                mat.river_strength = 1.0;
+            }
+            if let Some(plates) = &generated.data.plates {
+                mat.plate_texture = Some(imgs.add(plates.to_image()));
+            }
+        }
+        if let Some(rivers) = generated.rivers {
+            info!("Extracted {} rivers", rivers.polylines.len());
+            commands.insert_resource(rivers);
+        }
+        commands.insert_resource(generated.data);
+        commands.insert_resource(generated.stats);
+        commands.insert_resource(generated.passes);
+        commands.entity(world).insert(InterpTag);
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
+// Passes and settings applied to whichever generator raises the map, kept while the world is
+// shown to raise it again
+#[derive(Debug, Clone)]
+struct Pipeline {
+    settings: WorldGenSettings,
+    hydraulic: Option<HydraulicErosion>,
+    thermal: Option<ThermalErosion>,
+    hypsometry: Option<Hypsometry>,
+    rivers: Option<RiverSettings>,
+    climate: ClimateSettings,
+    layout: MapLayout,
+    format: ElevationFormat,
+}
+
+impl Pipeline {
+    // The chosen generator along with the choice made, or why it can't be made
+    fn generator(
+        &self,
+        registry: &GeneratorRegistry,
+        choice: &GeneratorChoice,
+    ) -> Result<(GeneratorChoice, Box<dyn WorldGenerator>), GenerationError> {
+        // Resolved first, so the cache knows choices spelling out defaults as the same world
+        let create = || -> Result<_, RegistryError> {
+            let choice = registry.resolve(choice)?;
+            let generator = registry.create(&choice.name, &self.settings, &choice.params)?;
+            Ok((choice, generator))
+        };
+        create().map_err(|err| {
+            warn!("{}, generators are:\n{}", err, registry.usage());
+            GenerationError::InvalidSettings(err.to_string())
+        })
+    }
+
+    // Chain previews and the configured passes onto the chosen generator and run it in the
+    // background, reading the world back from the cache if it was raised before
+    fn start(
+        &self,
+        registry: &GeneratorRegistry,
+        choice: &GeneratorChoice,
+        cache: Option<&WorldCache>,
+    ) -> Result<GenerateTask, GenerationError> {
+        if let Some(hypsometry) = &self.hypsometry {
+            hypsometry.validate()?;
+        }
+        let (choice, generator) = self.generator(registry, choice)?;
+        let passes = generator.passes();
+        let settings = self.settings;
+        let previews: Vec<_> = PREVIEW_SCALES
+            .iter()
+            .map(|scale| {
+                generator.with_size(
+                    (settings.width / scale).max(1),
+                    (settings.height / scale).max(1),
+                )
+            })
+            .collect();
+
+        let mut task = GenerationTask::new(generator)
+            .with_climate(self.climate)
+            .with_layout(self.layout)
+            .with_format(self.format);
+        for preview in previews {
+            task = task.with_preview(preview);
+        }
+        if let Some(hydraulic) = self.hydraulic.filter(|_| passes.erosion) {
+            task = task.with_pass(hydraulic);
+        }
+        if let Some(thermal) = self.thermal.filter(|_| passes.erosion) {
+            task = task.with_pass(thermal);
+        }
+        // Last, so the requested land fraction holds for the finished map
+        if let Some(hypsometry) = self.hypsometry.as_ref().filter(|_| passes.hypsometry) {
+            task = task.with_pass(hypsometry.clone());
+        }
+        if let Some(rivers) = self.rivers {
+            task = task.with_rivers(rivers);
+        }
+        if let Some(cache) = cache {
+            task = task.with_cache(cache.clone(), format!("{} {:?}", choice, self));
+        }
+
+        let progress = task.progress();
+        let previews = task.previews();
+        let task = AsyncComputeTaskPool::get().spawn(task);
+        Ok(GenerateTask {
+            task,
+            progress,
+            previews,
+        })
+    }
+}
+
+// What the world is raised from: its settings, the generator picked and where worlds are cached,
+// along with the GPU it is shown on
+#[derive(SystemParam)]
+struct GenerationInputs<'w, 's> {
+    settings: Res<'w, WorldGenSettings>,
+    registry: Res<'w, GeneratorRegistry>,
+    choice: Option<Res<'w, GeneratorChoice>>,
+    cache: Option<Res<'w, WorldCache>>,
+    device: Option<Res<'w, RenderDevice>>,
+    #[system_param(ignore)]
+    marker: PhantomData<&'s ()>,
+}
+
+// Passes run over whichever map is raised, and the sea level they work to
+#[derive(SystemParam)]
+struct PassSettings<'w, 's> {
+    hydraulic: Option<Res<'w, HydraulicErosion>>,
+    thermal: Option<Res<'w, ThermalErosion>>,
+    hypsometry: Option<Res<'w, Hypsometry>>,
+    curves: Option<Res<'w, HypsometricCurves>>,
+    rivers: Option<Res<'w, RiverSettings>>,
+    climate: Option<Res<'w, ClimateSettings>>,
+    sea_level: Res<'w, SeaLevel>,
+    #[system_param(ignore)]
+    marker: PhantomData<&'s ()>,
+}
+
+impl PassSettings<'_, '_> {
+    // The passes configured, all working to the current sea level. Droplets fall where the
+    // world's seed has them, so the same settings always erode the same map.
+    fn pipeline(&self, settings: WorldGenSettings) -> Pipeline {
+        let sea_level = self.sea_level.0;
+        Pipeline {
+            settings,
+            hydraulic: self.hydraulic.as_deref().map(|hydraulic| HydraulicErosion {
+                seed: settings.seed as u64,
+                ..*hydraulic
+            }),
+            thermal: self.thermal.as_deref().copied(),
+            hypsometry: self.hypsometry.as_deref().map(|hypsometry| Hypsometry {
+                sea_level,
+                curves: self.curves.as_deref().cloned().unwrap_or_default(),
+                ..hypsometry.clone()
+            }),
+            rivers: self.rivers.as_deref().map(|rivers| RiverSettings {
+                sea_level,
+                ..*rivers
+            }),
+            climate: ClimateSettings {
+                sea_level,
+                ..self.climate.as_deref().copied().unwrap_or_default()
+            },
+            layout: settings.layout,
+            format: settings.format,
         }
     }
 }
@@ -117,18 +965,53 @@ fn game_startup(
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<shader::GenerationMaterial>>,
     mut q: Query<(Entity, &mut Transform), With<crate::PlayerTag>>,
+    inputs: GenerationInputs,
+    passes: PassSettings,
+    mut failed: EventWriter<GenerationFailed>,
 ) {
-    // Spawn sphere
-    let material: GenerationMaterial = Color::rgb(0.4, 0.1, 0.8).into();
-    let gen = SimplexGenerator::new(SIZE, 2 * SIZE);
+    let mut settings = inputs.settings.within_budget();
+    // GPUs that can't sample 16-bit normalized textures are given half floats instead
+    if let Some(device) = &inputs.device {
+        settings.max_texture_size = device.limits().max_texture_dimension_2d;
+        let format = settings.format.supported(device.features());
+        if format != settings.format {
+            warn!(
+                "{:?} is not supported by the GPU, using {:?}",
+                settings.format, format
+            );
+            settings.format = format;
+        }
+    }
+    if settings.memory() > settings.memory_budget {
+        warn!("World does not fit the memory budget even at the lowest resolution");
+    }
 
-    let task = AsyncComputeTaskPool::get().spawn(GenerationTask::new(gen));
-    commands.spawn().insert(GenerateTask(task));
+    // Spawn sphere
+    let material = GenerationMaterial {
+        // Previews arrive before the world, already laid out and encoded
+        cube_sphere: settings.layout == MapLayout::CubeSphere,
+        elevation_decode: settings.format.decode(),
+        ..Color::rgb(0.4, 0.1, 0.8).into()
+    };
+    let choice = inputs.choice.as_deref().cloned().unwrap_or_default();
+    info!("Generating world with {:?} and {:?}", choice, settings);
+
+    let pipeline = passes.pipeline(settings);
+    let task = settings
+        .validate()
+        .and_then(|()| pipeline.start(&inputs.registry, &choice, inputs.cache.as_deref()));
+    match task {
+        Ok(task) => {
+            commands.spawn().insert(task).insert(GameTag);
+            commands.insert_resource(pipeline);
+        }
+        Err(err) => failed.send(GenerationFailed(err)),
+    }
 
     commands
         .spawn_bundle(MaterialMeshBundle {
             mesh: meshes.add(Mesh::from(shape::Icosphere {
-                radius: 3.0,
+                radius: RADIUS,
                 subdivisions: 32,
             })),
             material: materials.add(material.clone()),
@@ -251,7 +1134,9 @@ mod test {
     use bevy::prelude::*;
 
     fn generate_app() -> App {
-        use super::GenerationMaterial;
+        use super::{
+            GenerationFailed, GenerationMaterial, GeneratorRegistry, SeaLevel, WorldGenSettings,
+        };
         use crate::PlayerTag;
         use bevy::asset::AssetPlugin;
 
@@ -259,6 +1144,14 @@ mod test {
 
         app.add_plugins(MinimalPlugins).add_plugin(AssetPlugin);
         app.add_asset::<Mesh>().add_asset::<GenerationMaterial>();
+        app.insert_resource(WorldGenSettings {
+            width: 64,
+            height: 128,
+            ..default()
+        });
+        app.init_resource::<SeaLevel>();
+        app.init_resource::<GeneratorRegistry>();
+        app.add_event::<GenerationFailed>();
         app.world
             .spawn()
             .insert_bundle(Camera3dBundle::default())
@@ -361,8 +1254,519 @@ mod test {
 
         assert_eq!(task.len(), 1);
 
-        let result = futures_lite::future::block_on(&mut task[0].0);
-        assert!(result.is_some());
+        let result = futures_lite::future::block_on(&mut task[0].task);
+        assert!(result.is_ok());
+        assert_eq!(task[0].progress.fraction(), 1.0);
+    }
+
+    #[test]
+    fn setup_runs_tectonic_generator() {
+        use super::{
+            game_startup, GenerateTask, GeneratorChoice, GeneratorParams, ParamValue,
+            WorldGenSettings,
+        };
+
+        let mut app = generate_app();
+        app.insert_resource(WorldGenSettings {
+            width: 64,
+            height: 32,
+            ..default()
+        });
+        app.insert_resource(GeneratorChoice {
+            name: "tectonic".into(),
+            params: GeneratorParams::default().with("plates", ParamValue::Int(6)),
+        });
+        app.add_startup_system(game_startup);
+
+        app.update();
+
+        let mut task: Vec<_> = app
+            .world
+            .query::<&mut GenerateTask>()
+            .iter_mut(&mut app.world)
+            .collect();
+
+        let result = futures_lite::future::block_on(&mut task[0].task).unwrap();
+        assert_eq!(result.data.plates.unwrap().plates.len(), 6);
+    }
+
+    #[test]
+    fn setup_sends_previews() {
+        use super::{game_startup, GenerateTask, PREVIEW_SCALES};
+
+        let mut app = generate_app();
+        app.add_startup_system(game_startup);
+
+        app.update();
+
+        let mut task: Vec<_> = app
+            .world
+            .query::<&mut GenerateTask>()
+            .iter_mut(&mut app.world)
+            .collect();
+
+        futures_lite::future::block_on(&mut task[0].task).unwrap();
+        let preview = task[0].previews.latest().unwrap();
+        assert_eq!(
+            preview.size(),
+            Vec2::new(
+                64. / PREVIEW_SCALES[1] as f32,
+                128. / PREVIEW_SCALES[1] as f32
+            )
+        );
+    }
+
+    #[test]
+    fn morph_starts_from_last_elevation() {
+        use super::{morph_to, GenerationMaterial};
+        use bevy::asset::HandleId;
+
+        let handle = || Handle::<Image>::weak(HandleId::random::<Image>());
+        let (first, second) = (handle(), handle());
+        let mut mat = GenerationMaterial::default();
+
+        morph_to(&mut mat, first.clone());
+        assert_eq!(mat.elevation_other, Some(first.clone()));
+        mat.interp = 1.0;
+
+        morph_to(&mut mat, second.clone());
+        assert_eq!(mat.elevation_texture, Some(first));
+        assert_eq!(mat.elevation_other, Some(second));
+        assert_eq!(mat.interp, 0.0);
+    }
+
+    #[test]
+    fn setup_imports_heightmap() {
+        use super::{
+            game_startup, GenerateTask, GenerationError, GeneratorChoice, GeneratorParams,
+            ParamValue, WorldGenSettings,
+        };
+
+        let mut app = generate_app();
+        app.insert_resource(WorldGenSettings {
+            width: 64,
+            height: 32,
+            ..default()
+        });
+        app.insert_resource(GeneratorChoice {
+            name: "import".into(),
+            params: GeneratorParams::default()
+                .with("path", ParamValue::Text("no/such/heightmap.png".into())),
+        });
+        app.add_startup_system(game_startup);
+
+        app.update();
+
+        let mut task: Vec<_> = app
+            .world
+            .query::<&mut GenerateTask>()
+            .iter_mut(&mut app.world)
+            .collect();
+
+        // The missing file ends generation instead of panicking
+        let result = futures_lite::future::block_on(&mut task[0].task);
+        assert!(matches!(result, Err(GenerationError::Io(_))));
+        assert_eq!(task[0].progress.stage(), "import");
+    }
+
+    #[test]
+    fn setup_fails_on_unknown_generator() {
+        use super::{
+            game_startup, GenerateTask, GenerationError, GenerationFailed, GeneratorChoice,
+            GeneratorParams,
+        };
+        use bevy::ecs::event::Events;
+
+        let mut app = generate_app();
+        app.insert_resource(GeneratorChoice {
+            name: "no such generator".into(),
+            params: GeneratorParams::default(),
+        });
+        app.add_startup_system(game_startup);
+
+        app.update();
+
+        assert_eq!(
+            app.world.query::<&GenerateTask>().iter(&app.world).count(),
+            0
+        );
+        let events = app.world.resource::<Events<GenerationFailed>>();
+        let sent: Vec<_> = events.get_reader().iter(events).cloned().collect();
+        assert_eq!(
+            sent,
+            [GenerationFailed(GenerationError::InvalidSettings(
+                "No generator is called no such generator".into()
+            ))]
+        );
+    }
+
+    #[test]
+    fn noise_graph_registers_and_regenerates() {
+        use super::{
+            game_startup, noise_graph::NoiseGraph, noise_graph::NoiseNode, reload_noise_graph,
+            GenerateTask, GeneratorChoice, GeneratorParams, NoiseGraphHandle,
+        };
+
+        let mut app = generate_app();
+        app.add_asset::<NoiseGraph>();
+        app.insert_resource(GeneratorChoice {
+            name: "graph".into(),
+            params: GeneratorParams::default(),
+        });
+        let handle = app
+            .world
+            .resource_mut::<Assets<NoiseGraph>>()
+            .add(NoiseGraph {
+                root: NoiseNode::Constant(0.5),
+            });
+        app.insert_resource(NoiseGraphHandle(handle));
+        app.add_startup_system(game_startup);
+        app.add_system(reload_noise_graph);
+
+        // The bundled graph raises the world before the asset has loaded
+        let tasks = |app: &mut App| app.world.query::<&GenerateTask>().iter(&app.world).count();
+        app.update();
+        assert_eq!(tasks(&mut app), 1);
+
+        // The asset loads a frame later, and the world is raised again from it
+        app.update();
+        let mut task: Vec<_> = app
+            .world
+            .query::<&mut GenerateTask>()
+            .iter_mut(&mut app.world)
+            .collect();
+        assert_eq!(task.len(), 1);
+
+        let result = futures_lite::future::block_on(&mut task[0].task).unwrap();
+        assert!(result.data.elevation.data.iter().all(|f| *f == 0.4));
+    }
+
+    #[test]
+    fn setup_reuses_cached_world() {
+        use super::{game_startup, import::test::Scratch, GenerateTask, WorldCache};
+
+        let dir = Scratch::new("setup-cache");
+        let cache = WorldCache {
+            directory: dir.0.clone(),
+            max_bytes: u64::MAX,
+        };
+
+        let generate = || {
+            let mut app = generate_app();
+            app.insert_resource(cache.clone());
+            app.add_startup_system(game_startup);
+            app.update();
+
+            let mut task: Vec<_> = app
+                .world
+                .query::<&mut GenerateTask>()
+                .iter_mut(&mut app.world)
+                .collect();
+            let result = futures_lite::future::block_on(&mut task[0].task).unwrap();
+            (result.data, task[0].progress.stage())
+        };
+
+        let (raised, stage) = generate();
+        assert_ne!(stage, "cache");
+        let (cached, stage) = generate();
+        assert_eq!(stage, "cache");
+        assert_eq!(cached, raised);
+        assert!(cache.size().unwrap() > 0);
+    }
+
+    #[test]
+    fn erosion_follows_the_world_seed() {
+        use super::{game_startup, GenerateTask, HydraulicErosion, Pipeline, WorldGenSettings};
+
+        let generate = || {
+            let mut app = generate_app();
+            app.insert_resource(WorldGenSettings {
+                width: 64,
+                height: 32,
+                seed: 7,
+                ..default()
+            });
+            app.insert_resource(HydraulicErosion {
+                iterations: 2000,
+                ..default()
+            });
+            app.add_startup_system(game_startup);
+            app.update();
+            assert_eq!(app.world.resource::<Pipeline>().hydraulic.unwrap().seed, 7);
+
+            let mut task: Vec<_> = app
+                .world
+                .query::<&mut GenerateTask>()
+                .iter_mut(&mut app.world)
+                .collect();
+            futures_lite::future::block_on(&mut task[0].task)
+                .unwrap()
+                .data
+                .elevation
+        };
+
+        assert_eq!(generate(), generate());
+    }
+
+    #[test]
+    fn failed_generation_returns_to_menu() {
+        use super::{
+            game_startup, poll_task, return_on_failure, GeneratorChoice, GeneratorParams,
+            MenuMessage, ParamValue,
+        };
+        use crate::GameState;
+        use iyes_loopless::prelude::*;
+
+        let mut app = generate_app();
+        app.add_asset::<Image>();
+        app.insert_resource(GeneratorChoice {
+            name: "import".into(),
+            params: GeneratorParams::default()
+                .with("path", ParamValue::Text("no/such/heightmap.png".into())),
+        });
+        app.add_startup_system(game_startup);
+        app.add_system(poll_task);
+        app.add_system(return_on_failure);
+
+        for _ in 0..200 {
+            app.update();
+            if app.world.contains_resource::<NextState<GameState>>() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        assert_eq!(
+            app.world.resource::<NextState<GameState>>().0,
+            GameState::MainMenu
+        );
+        assert!(app
+            .world
+            .resource::<MenuMessage>()
+            .0
+            .contains("no/such/heightmap.png"));
+    }
+
+    #[test]
+    fn finished_world_becomes_planet() {
+        use super::{
+            classify_water, game_startup, poll_task, Filter, Layer, Planet, SeaLevel, WaterMap,
+            WorldData, RADIUS,
+        };
+
+        let mut app = generate_app();
+        app.add_asset::<Image>();
+        app.insert_resource(SeaLevel(0.05));
+        app.add_startup_system(game_startup);
+        app.add_system(poll_task);
+        app.add_system(classify_water.after(poll_task));
+
+        for _ in 0..500 {
+            app.update();
+            if app.world.contains_resource::<WaterMap>() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        app.update();
+
+        let data = app.world.resource::<WorldData>();
+        let planet = app.world.resource::<Planet>();
+        assert_eq!(planet.radius, RADIUS);
+
+        // Texel centres read back the world's elevation, up to half precision
+        let map = &data.elevation;
+        for (x, y) in [(3, 5), (40, 100), (63, 127)] {
+            let direction = map.direction(x as f32, y as f32);
+            let sampled = planet.sample(Layer::Elevation, direction, Filter::Bilinear);
+            assert!((sampled - map.get(x, y)).abs() < 1e-3);
+
+            let surface = planet.surface_elevation(direction);
+            if planet.is_water(direction) {
+                assert!(surface >= 0.05);
+            } else {
+                assert!((surface - sampled).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn sea_level_floods_in_the_background() {
+        use super::{
+            classify_water, game_startup, poll_task, RiverSettings, Rivers, SeaLevel, WaterMap,
+            WaterTask,
+        };
+
+        let mut app = generate_app();
+        app.add_asset::<Image>();
+        app.init_resource::<RiverSettings>();
+        app.add_startup_system(game_startup);
+        app.add_system(poll_task);
+        app.add_system(classify_water.after(poll_task));
+
+        let flooded = |app: &mut App, sea_level: f32| {
+            for _ in 0..500 {
+                app.update();
+                let water = app.world.get_resource::<WaterMap>();
+                if water.map_or(false, |water| water.sea_level == sea_level) {
+                    return;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+            panic!("Water was never classified at {}", sea_level);
+        };
+        flooded(&mut app, 0.0);
+        assert_eq!(app.world.resource::<Rivers>().sea_level, 0.0);
+
+        // Stepping the sea level while the map floods waits for the flood to finish
+        app.world.resource_mut::<SeaLevel>().0 = 0.1;
+        app.update();
+        app.world.resource_mut::<SeaLevel>().0 = 0.2;
+        app.update();
+        assert!(app.world.query::<&WaterTask>().iter(&app.world).count() <= 1);
+
+        flooded(&mut app, 0.2);
+        assert_eq!(app.world.resource::<Rivers>().sea_level, 0.2);
+    }
+
+    #[test]
+    fn invalid_settings_fail_before_starting() {
+        use super::{game_startup, GenerateTask, GenerationError, GenerationFailed};
+        use bevy::ecs::event::Events;
+
+        let mut app = generate_app();
+        app.insert_resource(super::WorldGenSettings {
+            width: 0,
+            ..default()
+        });
+        app.add_startup_system(game_startup);
+        app.update();
+
+        assert_eq!(
+            app.world.query::<&GenerateTask>().iter(&app.world).count(),
+            0
+        );
+        let events = app.world.resource::<Events<GenerationFailed>>();
+        let sent: Vec<_> = events.get_reader().iter(events).cloned().collect();
+        assert!(matches!(
+            sent[..],
+            [GenerationFailed(GenerationError::InvalidSettings(_))]
+        ));
+    }
+
+    #[test]
+    fn invalid_land_fraction_fails_before_starting() {
+        use super::{game_startup, GenerateTask, GenerationError, GenerationFailed, Hypsometry};
+        use bevy::ecs::event::Events;
+
+        let mut app = generate_app();
+        app.insert_resource(Hypsometry {
+            land_fraction: 1.5,
+            ..default()
+        });
+        app.add_startup_system(game_startup);
+        app.update();
+
+        assert_eq!(
+            app.world.query::<&GenerateTask>().iter(&app.world).count(),
+            0
+        );
+        let events = app.world.resource::<Events<GenerationFailed>>();
+        let sent: Vec<_> = events.get_reader().iter(events).cloned().collect();
+        assert!(matches!(
+            sent[..],
+            [GenerationFailed(GenerationError::InvalidSettings(_))]
+        ));
+    }
+
+    #[test]
+    fn stats_follow_sea_level() {
+        use super::{
+            compute_stats, heightmap::Heightmap, SeaLevel, StatsTask, WorldData, WorldStats,
+        };
+
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins);
+        app.init_resource::<SeaLevel>();
+        app.add_system(compute_stats);
+        app.update();
+        assert!(!app.world.contains_resource::<WorldStats>());
+
+        let measured = |app: &mut App, sea_level: f32| {
+            for _ in 0..500 {
+                app.update();
+                let stats = app.world.get_resource::<WorldStats>();
+                if let Some(stats) = stats.filter(|stats| stats.sea_level == sea_level) {
+                    return stats.land_fraction;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+            panic!("Statistics were never measured at {}", sea_level);
+        };
+
+        let data: Vec<_> = (0..64).map(|i| i as f32 / 32.0 - 1.0).collect();
+        app.insert_resource(WorldData::new(Heightmap::new(64, 1, data)));
+        assert_eq!(measured(&mut app, 0.0), 0.5);
+
+        // Measured in the background, one sea level at a time
+        app.world.resource_mut::<SeaLevel>().0 = 0.25;
+        app.update();
+        app.world.resource_mut::<SeaLevel>().0 = 0.5;
+        app.update();
+        assert!(app.world.query::<&StatsTask>().iter(&app.world).count() <= 1);
+        assert_eq!(measured(&mut app, 0.5), 0.25);
+    }
+
+    #[test]
+    fn biomes_follow_sea_level_in_the_background() {
+        use super::{
+            classify_biomes, heightmap::Heightmap, Biome, BiomeMap, BiomeRules, BiomeTask,
+            SeaLevel, WorldData,
+        };
+
+        let mut app = generate_app();
+        app.add_asset::<Image>();
+        app.init_resource::<BiomeRules>();
+        app.add_system(classify_biomes);
+
+        let oceans = |app: &mut App, sea_level: f32| {
+            for _ in 0..500 {
+                app.update();
+                let biomes = app.world.get_resource::<BiomeMap>();
+                if let Some(biomes) = biomes.filter(|biomes| biomes.sea_level == sea_level) {
+                    return biomes.cells.iter().filter(|b| **b == Biome::Ocean).count();
+                }
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+            panic!("Biomes were never classified at {}", sea_level);
+        };
+
+        let data: Vec<_> = (0..64).map(|i| i as f32 / 32.0 - 1.0).collect();
+        app.insert_resource(WorldData::new(Heightmap::new(64, 1, data)));
+        assert_eq!(oceans(&mut app, 0.0), 32);
+
+        app.world.resource_mut::<SeaLevel>().0 = 0.25;
+        app.update();
+        app.world.resource_mut::<SeaLevel>().0 = 0.5;
+        app.update();
+        assert!(app.world.query::<&BiomeTask>().iter(&app.world).count() <= 1);
+        assert_eq!(oceans(&mut app, 0.5), 48);
+    }
+
+    #[test]
+    fn exit_cancels_task() {
+        use super::{cancel_tasks, game_startup, GenerateTask};
+
+        let mut app = generate_app();
+        app.add_startup_system(game_startup);
+        app.update();
+
+        app.add_system(cancel_tasks);
+        app.update();
+
+        for task in app.world.query::<&GenerateTask>().iter(&app.world) {
+            assert!(task.progress.is_cancelled());
+        }
     }
 
     // TODO: Test orbit controls
@@ -0,0 +1,308 @@
+use std::sync::mpsc::Sender;
+
+use bevy::{
+    prelude::*,
+    render::{
+        render_resource::{Extent3d, TextureDimension, TextureFormat},
+        texture::ImageSampler,
+    },
+};
+
+use super::{
+    generation::{GenerationError, Job, Progress},
+    world_data::WorldData,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Biome {
+    // Below sea level, drawn by the water overlay
+    Ocean,
+    Ice,
+    Tundra,
+    Taiga,
+    TemperateForest,
+    Grassland,
+    Desert,
+    Rainforest,
+    Beach,
+    Mountain,
+}
+
+impl Biome {
+    // Every biome, in the order of their index in the biome texture
+    pub const ALL: [Biome; 10] = [
+        Biome::Ocean,
+        Biome::Ice,
+        Biome::Tundra,
+        Biome::Taiga,
+        Biome::TemperateForest,
+        Biome::Grassland,
+        Biome::Desert,
+        Biome::Rainforest,
+        Biome::Beach,
+        Biome::Mountain,
+    ];
+
+    pub fn index(self) -> u8 {
+        Biome::ALL.iter().position(|b| *b == self).unwrap_or(0) as u8
+    }
+
+    pub fn color(self) -> Color {
+        match self {
+            Biome::Ocean => Color::rgb(0.05, 0.15, 0.5),
+            Biome::Ice => Color::rgb(0.95, 0.97, 1.0),
+            Biome::Tundra => Color::rgb(0.6, 0.62, 0.55),
+            Biome::Taiga => Color::rgb(0.2, 0.38, 0.3),
+            Biome::TemperateForest => Color::rgb(0.2, 0.5, 0.15),
+            Biome::Grassland => Color::rgb(0.55, 0.7, 0.3),
+            Biome::Desert => Color::rgb(0.9, 0.8, 0.5),
+            Biome::Rainforest => Color::rgb(0.05, 0.4, 0.1),
+            Biome::Beach => Color::rgb(0.95, 0.9, 0.65),
+            Biome::Mountain => Color::rgb(0.5, 0.45, 0.4),
+        }
+    }
+
+    // One texel per biome, looked up by the index in the biome texture
+    pub fn palette() -> Image {
+        let data = Biome::ALL
+            .iter()
+            .flat_map(|biome| biome.color().as_rgba_u32().to_le_bytes())
+            .collect();
+
+        let mut image = Image::new(
+            Extent3d {
+                width: Biome::ALL.len() as u32,
+                height: 1,
+                ..default()
+            },
+            TextureDimension::D2,
+            data,
+            TextureFormat::Rgba8UnormSrgb,
+        );
+        image.sampler_descriptor = ImageSampler::nearest();
+        image
+    }
+}
+
+// Cells whose climate falls within all three ranges get the biome. Ranges are half open.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct BiomeRule {
+    pub biome: Biome,
+    // Degrees Celsius
+    pub temperature: (f32, f32),
+    // Relative humidity, in [0, 1]
+    pub moisture: (f32, f32),
+    // Relative to sea level
+    pub elevation: (f32, f32),
+}
+
+const ANY: (f32, f32) = (f32::NEG_INFINITY, f32::INFINITY);
+
+impl BiomeRule {
+    pub fn matches(&self, temperature: f32, moisture: f32, elevation: f32) -> bool {
+        let within = |(min, max): (f32, f32), value: f32| min <= value && value < max;
+        within(self.temperature, temperature)
+            && within(self.moisture, moisture)
+            && within(self.elevation, elevation)
+    }
+}
+
+// Rules checked in order, the first match wins. Cells matching none are grassland.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct BiomeRules(pub Vec<BiomeRule>);
+
+impl Default for BiomeRules {
+    fn default() -> Self {
+        let rule = |biome, temperature, moisture, elevation| BiomeRule {
+            biome,
+            temperature,
+            moisture,
+            elevation,
+        };
+
+        // Roughly after Whittaker's diagram, with the sea, coasts and peaks taken out first
+        BiomeRules(vec![
+            rule(Biome::Ice, (f32::NEG_INFINITY, -10.0), ANY, ANY),
+            rule(Biome::Ocean, ANY, ANY, (f32::NEG_INFINITY, 0.0)),
+            rule(Biome::Beach, ANY, ANY, (0.0, 0.02)),
+            rule(Biome::Mountain, ANY, ANY, (0.45, f32::INFINITY)),
+            rule(Biome::Tundra, (f32::NEG_INFINITY, 0.0), ANY, ANY),
+            rule(Biome::Taiga, (0.0, 8.0), ANY, ANY),
+            rule(Biome::Desert, ANY, (0.0, 0.1), ANY),
+            rule(
+                Biome::Rainforest,
+                (20.0, f32::INFINITY),
+                (0.5, f32::INFINITY),
+                ANY,
+            ),
+            rule(Biome::Grassland, ANY, (0.1, 0.3), ANY),
+            rule(Biome::TemperateForest, ANY, ANY, ANY),
+        ])
+    }
+}
+
+impl BiomeRules {
+    pub fn classify(&self, temperature: f32, moisture: f32, elevation: f32) -> Biome {
+        self.0
+            .iter()
+            .find(|rule| rule.matches(temperature, moisture, elevation))
+            .map_or(Biome::Grassland, |rule| rule.biome)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct BiomeMap {
+    pub width: u32,
+    pub height: u32,
+    pub sea_level: f32,
+    pub cells: Vec<Biome>,
+}
+
+impl BiomeMap {
+    pub fn classify(data: &WorldData, rules: &BiomeRules, sea_level: f32) -> Self {
+        let cells = data
+            .elevation
+            .data
+            .iter()
+            .zip(&data.temperature.data)
+            .zip(&data.moisture.data)
+            .map(|((elevation, temperature), moisture)| {
+                rules.classify(*temperature, *moisture, elevation - sea_level)
+            })
+            .collect();
+
+        BiomeMap {
+            width: data.elevation.width,
+            height: data.elevation.height,
+            sea_level,
+            cells,
+        }
+    }
+
+    // Biome indices for the material, stored as unorm so a missing texture still binds
+    pub fn to_image(&self) -> Image {
+        let data = self.cells.iter().map(|biome| biome.index()).collect();
+
+        let mut image = Image::new(
+            Extent3d {
+                width: self.width,
+                height: self.height,
+                ..default()
+            },
+            TextureDimension::D2,
+            data,
+            TextureFormat::R8Unorm,
+        );
+        image.sampler_descriptor = ImageSampler::nearest();
+        image
+    }
+}
+
+// Biomes of a map under other rules or at another sea level, worked out on a thread of its own
+pub(crate) struct BiomeJob {
+    pub data: WorldData,
+    pub rules: BiomeRules,
+    pub sea_level: f32,
+}
+
+impl Job for BiomeJob {
+    type Output = BiomeMap;
+
+    fn run(self, progress: &Progress, _: &Sender<Image>) -> Result<BiomeMap, GenerationError> {
+        progress.start("biomes", 1);
+        let biomes = BiomeMap::classify(&self.data, &self.rules, self.sea_level);
+        progress.advance(1);
+        Ok(biomes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::super::{heightmap::Heightmap, world_data::WorldData};
+    use super::{Biome, BiomeMap, BiomeRules};
+
+    // One texel per (elevation, temperature, moisture) combination given
+    fn world(cells: &[(f32, f32, f32)]) -> WorldData {
+        let layer = |f: fn(&(f32, f32, f32)) -> f32| {
+            Heightmap::new(cells.len() as u32, 1, cells.iter().map(f).collect())
+        };
+
+        WorldData {
+            temperature: Arc::new(layer(|c| c.1)),
+            moisture: Arc::new(layer(|c| c.2)),
+            ..WorldData::new(layer(|c| c.0))
+        }
+    }
+
+    #[test]
+    fn biomes_follow_climate() {
+        let rules = BiomeRules::default();
+
+        assert_eq!(rules.classify(-20.0, 0.5, 0.2), Biome::Ice);
+        assert_eq!(rules.classify(-20.0, 0.5, -0.2), Biome::Ice);
+        assert_eq!(rules.classify(15.0, 0.5, -0.2), Biome::Ocean);
+        assert_eq!(rules.classify(15.0, 0.5, 0.01), Biome::Beach);
+        assert_eq!(rules.classify(15.0, 0.5, 0.6), Biome::Mountain);
+        assert_eq!(rules.classify(-5.0, 0.5, 0.2), Biome::Tundra);
+        assert_eq!(rules.classify(5.0, 0.5, 0.2), Biome::Taiga);
+        assert_eq!(rules.classify(25.0, 0.05, 0.2), Biome::Desert);
+        assert_eq!(rules.classify(25.0, 0.8, 0.2), Biome::Rainforest);
+        assert_eq!(rules.classify(15.0, 0.2, 0.2), Biome::Grassland);
+        assert_eq!(rules.classify(15.0, 0.6, 0.2), Biome::TemperateForest);
+    }
+
+    #[test]
+    fn biome_rules_are_data() {
+        use super::BiomeRule;
+
+        // Without rules everything is grassland, and a single rule paints what it matches
+        let rules = BiomeRules(vec![BiomeRule {
+            biome: Biome::Desert,
+            temperature: (30.0, 50.0),
+            moisture: super::ANY,
+            elevation: super::ANY,
+        }]);
+        assert_eq!(rules.classify(40.0, 0.9, 0.1), Biome::Desert);
+        assert_eq!(rules.classify(20.0, 0.9, 0.1), Biome::Grassland);
+        assert_eq!(
+            BiomeRules(vec![]).classify(40.0, 0.0, 0.0),
+            Biome::Grassland
+        );
+    }
+
+    #[test]
+    fn biome_map_uses_sea_level() {
+        let data = world(&[(0.1, 15.0, 0.6), (-0.1, 15.0, 0.6)]);
+
+        let map = BiomeMap::classify(&data, &BiomeRules::default(), 0.0);
+        assert_eq!(map.cells, vec![Biome::TemperateForest, Biome::Ocean]);
+
+        let flooded = BiomeMap::classify(&data, &BiomeRules::default(), 0.5);
+        assert_eq!(flooded.cells, vec![Biome::Ocean, Biome::Ocean]);
+    }
+
+    #[test]
+    fn biome_map_to_image() {
+        use bevy::prelude::*;
+
+        let data = world(&[(0.1, 15.0, 0.6), (0.6, 15.0, 0.6), (-0.1, 15.0, 0.6)]);
+        let img = BiomeMap::classify(&data, &BiomeRules::default(), 0.0).to_image();
+        assert_eq!(img.size(), Vec2::new(3., 1.));
+        assert_eq!(
+            img.data,
+            vec![
+                Biome::TemperateForest.index(),
+                Biome::Mountain.index(),
+                Biome::Ocean.index()
+            ]
+        );
+
+        let palette = Biome::palette();
+        assert_eq!(palette.size(), Vec2::new(Biome::ALL.len() as f32, 1.));
+        for biome in Biome::ALL {
+            assert_eq!(Biome::ALL[biome.index() as usize], biome);
+        }
+    }
+}
@@ -0,0 +1,486 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::PathBuf,
+    sync::Arc,
+    time::SystemTime,
+};
+
+use bevy::prelude::*;
+
+use super::{
+    heightmap::Heightmap,
+    tectonics::{Boundary, Plate, PlateMap},
+    world_data::WorldData,
+};
+
+// Bump whenever the file layout changes
+const FORMAT_VERSION: u32 = 2;
+const MAGIC: &[u8; 4] = b"URBW";
+const EXTENSION: &str = "world";
+
+// Identifies a generated world by everything it was raised from, and the build raising it.
+// Files are named after the hash, but hold the whole description, so worlds whose hashes collide
+// are never mistaken for each other.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct CacheKey {
+    hash: u64,
+    description: String,
+}
+
+impl CacheKey {
+    // `description` must name the generator, its parameters and every setting changing the world
+    pub fn new(description: &str) -> Self {
+        // Fingerprint of the sources and dependencies, from build.rs
+        let description = format!("{} {}", env!("URBANITE_BUILD_FINGERPRINT"), description);
+        // FNV-1a, which unlike the standard hasher stays the same from one build to the next
+        let hash = description
+            .bytes()
+            .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+                (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+            });
+        CacheKey { hash, description }
+    }
+}
+
+// Generated worlds kept on disk, so raising the same world again only reads it back.
+// The least recently used worlds are removed once the cache grows past `max_bytes`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct WorldCache {
+    pub directory: PathBuf,
+    pub max_bytes: u64,
+}
+
+impl WorldCache {
+    // Cache in the user's cache directory, None on platforms without one so worlds are never
+    // cached wherever the game was started from
+    pub fn in_cache_dir() -> Option<Self> {
+        Some(WorldCache {
+            directory: dirs::cache_dir()?.join("urbanite").join("worlds"),
+            max_bytes: 4 << 30,
+        })
+    }
+
+    fn path(&self, key: &CacheKey) -> PathBuf {
+        self.directory
+            .join(format!("{:016x}.{}", key.hash, EXTENSION))
+    }
+
+    // The world stored under a key, None if there is none
+    pub fn load(&self, key: &CacheKey) -> io::Result<Option<WorldData>> {
+        let path = self.path(key);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let mut r = Bounded {
+            left: file.metadata()?.len(),
+            r: BufReader::new(&file),
+        };
+        if !read_header(&mut r, key)? {
+            // Another world whose description hashes the same
+            return Ok(None);
+        }
+        let data = read_world(&mut r)?;
+
+        // Mark it as recently used, so eviction removes other worlds first. Writing the magic
+        // number over itself updates the modification time. The world read is good either way
+        let touched = File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(MAGIC));
+        if let Err(err) = touched {
+            warn!("Could not mark {:?} as used: {}", path, err);
+        }
+        Ok(Some(data))
+    }
+
+    // Store a world under a key, then evict worlds until the cache fits its budget again
+    pub fn store(&self, key: &CacheKey, data: &WorldData) -> io::Result<()> {
+        fs::create_dir_all(&self.directory)?;
+
+        // Written aside and moved in place, so a world cut off halfway is never read back
+        let path = self.path(key);
+        let partial = path.with_extension("partial");
+        let mut w = BufWriter::new(File::create(&partial)?);
+        write_header(&mut w, key)?;
+        write_world(&mut w, data)?;
+        w.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+        fs::rename(&partial, &path)?;
+
+        self.evict()
+    }
+
+    // Every cached world, oldest first, with its size
+    fn entries(&self) -> io::Result<Vec<(SystemTime, u64, PathBuf)>> {
+        let dir = match fs::read_dir(&self.directory) {
+            Ok(dir) => dir,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+
+        let mut entries = Vec::new();
+        for entry in dir {
+            let path = entry?.path();
+            if path.extension().map_or(true, |ext| ext != EXTENSION) {
+                continue;
+            }
+            let metadata = fs::metadata(&path)?;
+            entries.push((metadata.modified()?, metadata.len(), path));
+        }
+        entries.sort();
+        Ok(entries)
+    }
+
+    // Total size of the cached worlds, in bytes
+    pub fn size(&self) -> io::Result<u64> {
+        Ok(self.entries()?.iter().map(|(_, len, _)| len).sum())
+    }
+
+    // Remove the least recently used worlds until the cache fits its budget
+    pub fn evict(&self) -> io::Result<()> {
+        let entries = self.entries()?;
+        let mut size: u64 = entries.iter().map(|(_, len, _)| len).sum();
+        for (_, len, path) in entries {
+            if size <= self.max_bytes {
+                break;
+            }
+            info!("Evicting {:?} from the world cache", path);
+            fs::remove_file(path)?;
+            size -= len;
+        }
+        Ok(())
+    }
+
+    // Remove every cached world
+    pub fn clear(&self) -> io::Result<()> {
+        for (_, _, path) in self.entries()? {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Little-endian throughout: the magic number, the format version and the key's description
+fn write_header(mut w: impl Write, key: &CacheKey) -> io::Result<()> {
+    w.write_all(MAGIC)?;
+    w.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    w.write_all(&(key.description.len() as u32).to_le_bytes())?;
+    w.write_all(key.description.as_bytes())
+}
+
+// The three layers, then the plates if there are any
+fn write_world(mut w: impl Write, data: &WorldData) -> io::Result<()> {
+    for map in [&data.elevation, &data.temperature, &data.moisture] {
+        write_heightmap(&mut w, map)?;
+    }
+
+    let plates = match &data.plates {
+        Some(plates) => plates,
+        None => return w.write_all(&[0]),
+    };
+    w.write_all(&[1])?;
+    w.write_all(&plates.width.to_le_bytes())?;
+    w.write_all(&plates.height.to_le_bytes())?;
+    w.write_all(&(plates.plates.len() as u32).to_le_bytes())?;
+    for plate in &plates.plates {
+        for f in plate
+            .center
+            .to_array()
+            .iter()
+            .chain(&plate.rotation.to_array())
+        {
+            w.write_all(&f.to_le_bytes())?;
+        }
+        w.write_all(&[plate.continental as u8])?;
+    }
+    for id in &plates.plate_ids {
+        w.write_all(&id.to_le_bytes())?;
+    }
+    let boundaries: Vec<_> = plates.boundaries.iter().map(|b| *b as u8).collect();
+    w.write_all(&boundaries)
+}
+
+fn write_heightmap(mut w: impl Write, map: &Heightmap) -> io::Result<()> {
+    w.write_all(&map.width.to_le_bytes())?;
+    w.write_all(&map.height.to_le_bytes())?;
+    let bytes: Vec<_> = map.data.iter().flat_map(|f| f.to_le_bytes()).collect();
+    w.write_all(&bytes)
+}
+
+// Reader knowing how many bytes are left in the file, so sizes read from a corrupt file are
+// checked before anything that large is allocated
+struct Bounded<R> {
+    r: R,
+    left: u64,
+}
+
+impl<R: Read> Read for Bounded<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.r.read(buf)?;
+        self.left = self.left.saturating_sub(read as u64);
+        Ok(read)
+    }
+}
+
+fn read_bytes<const N: usize>(mut r: impl Read) -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    r.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_u32(r: impl Read) -> io::Result<u32> {
+    read_bytes(r).map(u32::from_le_bytes)
+}
+
+fn read_f32(r: impl Read) -> io::Result<f32> {
+    read_bytes(r).map(f32::from_le_bytes)
+}
+
+// `len` values of `N` bytes each, failing before allocating them if the file is shorter
+fn read_values<const N: usize, T>(
+    r: &mut Bounded<impl Read>,
+    len: u64,
+    value: impl Fn([u8; N]) -> T,
+) -> io::Result<Vec<T>> {
+    let size = len
+        .checked_mul(N as u64)
+        .filter(|&size| size <= r.left)
+        .ok_or_else(|| invalid("Cached world is cut short"))?;
+    let mut bytes = vec![0; size as usize];
+    r.read_exact(&mut bytes)?;
+    Ok(bytes
+        .chunks_exact(N)
+        .map(|chunk| value(chunk.try_into().expect("Chunk of the wrong size")))
+        .collect())
+}
+
+// Whether the file holds the world `key` describes
+fn read_header(r: &mut Bounded<impl Read>, key: &CacheKey) -> io::Result<bool> {
+    if &read_bytes::<4>(&mut *r)? != MAGIC {
+        return Err(invalid("Not a cached world"));
+    }
+    if read_u32(&mut *r)? != FORMAT_VERSION {
+        return Err(invalid("Cached world of another version"));
+    }
+    let len = read_u32(&mut *r)?;
+    if len as usize != key.description.len() {
+        return Ok(false);
+    }
+    Ok(read_values(r, len as u64, |[b]| b)? == key.description.as_bytes())
+}
+
+fn read_world(r: &mut Bounded<impl Read>) -> io::Result<WorldData> {
+    let elevation = read_heightmap(r)?;
+    let temperature = read_heightmap(r)?;
+    let moisture = read_heightmap(r)?;
+
+    let plates = match read_bytes::<1>(&mut *r)? {
+        [0] => None,
+        [1] => Some(read_plates(r)?),
+        _ => return Err(invalid("Corrupt plates")),
+    };
+
+    Ok(WorldData {
+        elevation: Arc::new(elevation),
+        temperature: Arc::new(temperature),
+        moisture: Arc::new(moisture),
+        plates,
+    })
+}
+
+fn read_heightmap(r: &mut Bounded<impl Read>) -> io::Result<Heightmap> {
+    let width = read_u32(&mut *r)?;
+    let height = read_u32(&mut *r)?;
+    let data = read_values(r, width as u64 * height as u64, f32::from_le_bytes)?;
+    Ok(Heightmap::new(width, height, data))
+}
+
+fn read_plates(r: &mut Bounded<impl Read>) -> io::Result<PlateMap> {
+    let width = read_u32(&mut *r)?;
+    let height = read_u32(&mut *r)?;
+
+    let mut plates = Vec::new();
+    for _ in 0..read_u32(&mut *r)? {
+        let mut vector = || -> io::Result<Vec3> {
+            Ok(Vec3::new(
+                read_f32(&mut *r)?,
+                read_f32(&mut *r)?,
+                read_f32(&mut *r)?,
+            ))
+        };
+        let center = vector()?;
+        let rotation = vector()?;
+        let continental = read_bytes::<1>(&mut *r)? != [0];
+        plates.push(Plate {
+            center,
+            rotation,
+            continental,
+        });
+    }
+
+    let len = width as u64 * height as u64;
+    let plate_ids = read_values(r, len, u16::from_le_bytes)?;
+    let boundaries = read_values(r, len, |[b]| match b {
+        0 => Some(Boundary::Interior),
+        1 => Some(Boundary::Convergent),
+        2 => Some(Boundary::Divergent),
+        3 => Some(Boundary::Transform),
+        _ => None,
+    })?
+    .into_iter()
+    .collect::<Option<_>>()
+    .ok_or_else(|| invalid("Corrupt plate boundaries"))?;
+
+    Ok(PlateMap {
+        width,
+        height,
+        plates,
+        plate_ids,
+        boundaries,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::WorldCache;
+    use crate::generate_world::{
+        heightmap::Heightmap, import::test::Scratch, world_data::WorldData,
+    };
+
+    // Cache in a scratch directory of its own, removed with it
+    fn cache(name: &str, max_bytes: u64) -> (Scratch, WorldCache) {
+        let dir = Scratch::new(&format!("cache-{}", name));
+        let cache = WorldCache {
+            directory: dir.0.clone(),
+            max_bytes,
+        };
+        (dir, cache)
+    }
+
+    fn world(fill: f32) -> WorldData {
+        let mut data = WorldData::new(Heightmap::new(8, 4, vec![fill; 32]));
+        Arc::make_mut(&mut data.temperature).data[3] = 12.5;
+        Arc::make_mut(&mut data.moisture).data[5] = 0.25;
+        data
+    }
+
+    #[test]
+    fn keys_follow_description() {
+        use super::CacheKey;
+
+        assert_eq!(
+            CacheKey::new("simplex seed 1"),
+            CacheKey::new("simplex seed 1")
+        );
+        assert_ne!(
+            CacheKey::new("simplex seed 1"),
+            CacheKey::new("simplex seed 2")
+        );
+        assert_ne!(CacheKey::new(""), CacheKey::new("tectonic"));
+    }
+
+    #[test]
+    fn world_round_trips() {
+        use super::CacheKey;
+        use crate::generate_world::{
+            tectonics::TectonicGenerator, WorldGenSettings, WorldGenerator,
+        };
+
+        let (_dir, cache) = cache("round-trip", u64::MAX);
+        let key = CacheKey::new("round trip");
+        assert_eq!(cache.load(&key).unwrap(), None);
+
+        let flat = world(0.5);
+        cache.store(&key, &flat).unwrap();
+        assert_eq!(cache.load(&key).unwrap(), Some(flat));
+
+        // Plates come back too
+        let settings = WorldGenSettings {
+            width: 32,
+            height: 16,
+            ..Default::default()
+        };
+        let tectonic = TectonicGenerator::new(&settings, 5)
+            .get_world_data(&Default::default())
+            .unwrap();
+        cache.store(&key, &tectonic).unwrap();
+        assert_eq!(cache.load(&key).unwrap(), Some(tectonic));
+    }
+
+    #[test]
+    fn corrupt_world_is_an_error() {
+        use super::CacheKey;
+
+        let (_dir, cache) = cache("corrupt", u64::MAX);
+        let key = CacheKey::new("corrupt");
+        cache.store(&key, &world(0.0)).unwrap();
+
+        let path = cache.path(&key);
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &bytes[..bytes.len() / 2]).unwrap();
+        assert!(cache.load(&key).is_err());
+
+        std::fs::write(&path, b"not a world").unwrap();
+        assert!(cache.load(&key).is_err());
+
+        // Sizes past the end of the file are refused before anything is allocated for them
+        let mut huge = bytes.clone();
+        let elevation = 12 + key.description.len();
+        huge[elevation..elevation + 8].copy_from_slice(&[0xff; 8]);
+        std::fs::write(&path, &huge).unwrap();
+        let err = cache.load(&key).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn colliding_key_is_another_world() {
+        use super::CacheKey;
+
+        let (_dir, cache) = cache("collision", u64::MAX);
+        let (key, other) = (CacheKey::new("stored"), CacheKey::new("colliding"));
+        cache.store(&key, &world(0.0)).unwrap();
+
+        // As if both descriptions hashed the same
+        std::fs::copy(cache.path(&key), cache.path(&other)).unwrap();
+        assert_eq!(cache.load(&other).unwrap(), None);
+        assert!(cache.load(&key).unwrap().is_some());
+    }
+
+    #[test]
+    fn eviction_removes_least_recently_used() {
+        use super::CacheKey;
+        use filetime::FileTime;
+
+        let (_dir, mut cache) = cache("eviction", u64::MAX);
+        let keys = [CacheKey::new("a"), CacheKey::new("b"), CacheKey::new("c")];
+        // Stored a minute apart long ago, oldest first
+        for (i, key) in keys.iter().enumerate() {
+            cache.store(key, &world(1.0)).unwrap();
+            let stored = FileTime::from_unix_time(1_600_000_000 + 60 * i as i64, 0);
+            filetime::set_file_mtime(cache.path(key), stored).unwrap();
+        }
+        let world_size = cache.size().unwrap() / 3;
+
+        // Reading the oldest world makes it the most recently used
+        cache.load(&keys[0]).unwrap().unwrap();
+        cache.max_bytes = 2 * world_size;
+        cache.evict().unwrap();
+
+        assert!(cache.load(&keys[0]).unwrap().is_some());
+        assert!(cache.load(&keys[1]).unwrap().is_none());
+        assert!(cache.load(&keys[2]).unwrap().is_some());
+        assert_eq!(cache.size().unwrap(), 2 * world_size);
+
+        cache.clear().unwrap();
+        assert_eq!(cache.size().unwrap(), 0);
+        assert!(cache.load(&keys[0]).unwrap().is_none());
+    }
+}
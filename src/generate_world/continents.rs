@@ -0,0 +1,323 @@
+use bevy::prelude::*;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use super::{
+    generation::{
+        bands, fill_rows, GenerationError, Progress, SimplexGenerator, WorldGenSettings,
+        WorldGenerator,
+    },
+    geo,
+    heightmap::Heightmap,
+    sphere::{random_direction, smoothstep, Warp},
+};
+
+// Share of a seed's radius rising from the coast to its full height
+const SHORE: f32 = 0.5;
+// How far coastlines wander from the plain circles, on the unit sphere
+const WARP: f32 = 0.25;
+// Share of the amplitude left to noise detail on top of the mask
+const DETAIL: f32 = 0.5;
+// Gap between neighbouring islands of a chain, in island radii
+const ISLAND_SPACING: f32 = 2.5;
+
+// Land masses placed on the sphere before noise detail is added
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct ContinentLayout {
+    pub continents: u32,
+    // Angular radius of a continent, in radians
+    pub continent_size: f32,
+    pub island_chains: u32,
+    // Islands strung along each chain
+    pub chain_length: u32,
+    // Angular radius of an island, in radians
+    pub island_size: f32,
+}
+
+// Layouts picked by name, the first being the default
+pub(crate) const PRESETS: [(&str, ContinentLayout); 4] = [
+    (
+        "continents",
+        ContinentLayout {
+            continents: 5,
+            continent_size: 0.5,
+            island_chains: 4,
+            chain_length: 5,
+            island_size: 0.05,
+        },
+    ),
+    (
+        "pangaea",
+        ContinentLayout {
+            continents: 1,
+            continent_size: 1.1,
+            island_chains: 2,
+            chain_length: 4,
+            island_size: 0.05,
+        },
+    ),
+    (
+        "two-continents",
+        ContinentLayout {
+            continents: 2,
+            continent_size: 0.8,
+            island_chains: 3,
+            chain_length: 5,
+            island_size: 0.05,
+        },
+    ),
+    (
+        "archipelago",
+        ContinentLayout {
+            continents: 0,
+            continent_size: 0.0,
+            island_chains: 12,
+            chain_length: 6,
+            island_size: 0.08,
+        },
+    ),
+];
+
+impl ContinentLayout {
+    pub fn preset(name: &str) -> Option<Self> {
+        PRESETS
+            .iter()
+            .find(|(preset, _)| *preset == name)
+            .map(|(_, layout)| *layout)
+    }
+}
+
+// Centre of a continent or island, with the angular radius of its coast
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Seed {
+    center: Vec3,
+    radius: f32,
+    // Cosine of the radius, points closer to the centre having a greater dot product
+    reach: f32,
+}
+
+impl Seed {
+    fn new(center: Vec3, radius: f32) -> Self {
+        Seed {
+            center,
+            radius,
+            reach: radius.min(std::f32::consts::PI).cos(),
+        }
+    }
+}
+
+// Land placed from a layout of continents and island chains: a mask is raised from the distance
+// to their centres, then modulates simplex noise so coasts and relief aren't perfect circles
+#[derive(Clone, Debug)]
+pub(super) struct ContinentGenerator {
+    settings: WorldGenSettings,
+    preset: String,
+    layout: Option<ContinentLayout>,
+    seeds: Vec<Seed>,
+    warp: Warp,
+    detail: SimplexGenerator,
+}
+
+impl ContinentGenerator {
+    // Generator for a preset, with its numbers of continents and island chains optionally
+    // replaced. An unknown preset fails once the world is raised.
+    pub fn new(
+        settings: &WorldGenSettings,
+        preset: &str,
+        continents: Option<u32>,
+        island_chains: Option<u32>,
+    ) -> Self {
+        let layout = ContinentLayout::preset(preset).map(|layout| ContinentLayout {
+            continents: continents.unwrap_or(layout.continents),
+            island_chains: island_chains.unwrap_or(layout.island_chains),
+            ..layout
+        });
+
+        let mut rng = ChaCha8Rng::seed_from_u64(settings.seed as u64);
+        let seeds = layout.map_or_else(Vec::new, |layout| Self::place(&layout, &mut rng));
+        let warp = Warp::new(&mut rng, WARP);
+
+        ContinentGenerator {
+            settings: *settings,
+            preset: preset.into(),
+            layout,
+            seeds,
+            warp,
+            detail: SimplexGenerator::new(&WorldGenSettings {
+                amplitude: settings.amplitude * DETAIL,
+                ..*settings
+            }),
+        }
+    }
+
+    // Continents at random, then island chains strung along great circles
+    fn place(layout: &ContinentLayout, rng: &mut ChaCha8Rng) -> Vec<Seed> {
+        let mut seeds: Vec<_> = (0..layout.continents)
+            .map(|_| {
+                let radius = layout.continent_size * rng.gen_range(0.8..1.2);
+                Seed::new(random_direction(rng), radius)
+            })
+            .collect();
+
+        let spacing = layout.island_size * ISLAND_SPACING;
+        for _ in 0..layout.island_chains {
+            let start = random_direction(rng);
+            let towards = random_direction(rng);
+            let heading = (towards - start * towards.dot(start)).normalize_or_zero();
+            for i in 0..layout.chain_length {
+                let angle = i as f32 * spacing;
+                let jitter = random_direction(rng) * layout.island_size * 0.5;
+                let center = (start * angle.cos() + heading * angle.sin() + jitter).normalize();
+                let radius = layout.island_size * rng.gen_range(0.6..1.0);
+                seeds.push(Seed::new(center, radius));
+            }
+        }
+
+        seeds
+    }
+
+    // How much a point lies within land, from 0 in open ocean to 1 inland of the shore
+    fn mask(&self, point: Vec3) -> f32 {
+        // Wobbled so coastlines aren't circles
+        let point = self.warp.apply(point);
+        self.seeds
+            .iter()
+            .filter(|seed| seed.center.dot(point) > seed.reach)
+            .map(|seed| {
+                let distance = seed.center.dot(point).clamp(-1.0, 1.0).acos() / seed.radius;
+                smoothstep(SHORE, 1.0 - distance)
+            })
+            .fold(0.0, f32::max)
+    }
+
+    // Elevation at a point on the unit sphere, calmer over the sea floor than on land
+    fn elevation(&self, point: Vec3) -> f32 {
+        let amplitude = self.settings.amplitude;
+        let mask = self.mask(point);
+        let detail = self.detail.octaves(point.x, point.y, point.z);
+
+        ((mask - 0.5) * amplitude + detail * (0.5 + 0.5 * mask)).clamp(-amplitude, amplitude)
+    }
+}
+
+impl WorldGenerator for ContinentGenerator {
+    fn get_heightmap(&self, progress: &Progress) -> Result<Heightmap, GenerationError> {
+        if self.layout.is_none() {
+            let presets: Vec<_> = PRESETS.iter().map(|(name, _)| *name).collect();
+            return Err(GenerationError::InvalidSettings(format!(
+                "No continent layout is called {}, presets are {}",
+                self.preset,
+                presets.join(", ")
+            )));
+        }
+
+        let WorldGenSettings { width, height, .. } = self.settings;
+        let mut data = vec![0.0; (width * height) as usize];
+        progress.start("continents", height);
+        fill_rows(&mut data, width, bands(), progress, |y, row| {
+            let latitude = geo::latitude(y as f32, height);
+            for (x, texel) in row.iter_mut().enumerate() {
+                let longitude = geo::longitude(x as f32, width);
+                *texel = self.elevation(geo::direction(latitude, longitude));
+            }
+        })?;
+
+        Ok(Heightmap::new(width, height, data))
+    }
+
+    // Land is placed the same way whatever the size of the map
+    fn with_size(&self, width: u32, height: u32) -> Box<dyn WorldGenerator> {
+        Box::new(ContinentGenerator {
+            settings: self.settings.resized(width, height),
+            ..self.clone()
+        })
+    }
+
+    fn sample(&self, point: Vec3) -> Option<f32> {
+        Some(self.elevation(point))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{fixtures::settings, generation::Progress};
+
+    // Share of the surface above zero elevation
+    fn land(map: &super::Heightmap) -> f32 {
+        use super::super::{stats::WorldStats, world_data::WorldData};
+
+        WorldStats::compute(&WorldData::new(map.clone()), 0.0).land_fraction
+    }
+
+    #[test]
+    fn layouts_place_seeds() {
+        use super::{ContinentGenerator, ContinentLayout, PRESETS};
+
+        for (name, layout) in PRESETS {
+            let gen = ContinentGenerator::new(&settings(7, 64, 32), name, None, None);
+            assert_eq!(gen.layout, Some(layout));
+            assert_eq!(
+                gen.seeds.len() as u32,
+                layout.continents + layout.island_chains * layout.chain_length
+            );
+        }
+
+        let gen = ContinentGenerator::new(&settings(7, 64, 32), "pangaea", Some(3), Some(0));
+        assert_eq!(gen.seeds.len(), 3);
+        assert_eq!(
+            ContinentLayout::preset("pangaea").map(|layout| layout.continents),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn continents_rise_at_their_seeds() {
+        use super::{ContinentGenerator, WorldGenerator};
+
+        let gen = ContinentGenerator::new(&settings(7, 128, 64), "two-continents", None, Some(0));
+        let map = gen.get_heightmap(&Progress::default()).unwrap();
+        for seed in &gen.seeds {
+            assert!(gen.mask(seed.center) > 0.0);
+        }
+
+        // Previews place the same land at a lower resolution
+        let preview = gen.with_size(64, 32);
+        let small = preview.get_heightmap(&Progress::default()).unwrap();
+        assert!((land(&map) - land(&small)).abs() < 0.05);
+        assert_eq!(gen.get_heightmap(&Progress::default()).unwrap(), map);
+    }
+
+    #[test]
+    fn presets_differ_in_land() {
+        use super::{ContinentGenerator, WorldGenerator};
+
+        let raise = |preset| {
+            ContinentGenerator::new(&settings(7, 128, 64), preset, None, None)
+                .get_heightmap(&Progress::default())
+                .unwrap()
+        };
+        let (pangaea, archipelago) = (land(&raise("pangaea")), land(&raise("archipelago")));
+        assert!(
+            pangaea > archipelago,
+            "Pangaea has {} land, archipelago {}",
+            pangaea,
+            archipelago
+        );
+        assert!(archipelago > 0.0);
+    }
+
+    #[test]
+    fn unknown_preset_is_invalid() {
+        use super::super::generation::GenerationError;
+        use super::{ContinentGenerator, WorldGenerator};
+
+        let gen = ContinentGenerator::new(&settings(7, 16, 8), "atlantis", None, None);
+        match gen.get_heightmap(&Progress::default()) {
+            Err(GenerationError::InvalidSettings(message)) => {
+                assert!(message.contains("atlantis"));
+                assert!(message.contains("pangaea"));
+            }
+            other => panic!("Expected invalid settings, got {:?}", other),
+        }
+    }
+}
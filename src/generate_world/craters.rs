@@ -0,0 +1,321 @@
+use std::{
+    f32::consts::{PI, TAU},
+    ops::Range,
+};
+
+use bevy::prelude::*;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use super::{
+    generation::{
+        bands, fill_rows, GenerationError, Passes, Progress, SimplexGenerator, WorldGenSettings,
+        WorldGenerator,
+    },
+    geo,
+    heightmap::Heightmap,
+    sphere::random_direction,
+};
+
+// Angular radii of the smallest and largest craters, in radians
+const MIN_RADIUS: f32 = 0.004;
+const MAX_RADIUS: f32 = 0.5;
+// Depth of the largest crater, relative to the amplitude. Depth grows slower than size,
+// so basins are shallow for their width.
+const DEPTH: f32 = 0.6;
+// Height of a fresh rim, relative to the crater's depth
+const RIM: f32 = 0.25;
+// Distance out to which ejecta are thrown, in crater radii
+const EJECTA: f32 = 2.5;
+// Craters larger than this collapse into a flat floor around a central peak
+const COMPLEX_RADIUS: f32 = 0.04;
+// Share of the depth left to the worn-out oldest craters
+const WORN: f32 = 0.3;
+// Share of the amplitude left to the ground the craters strike
+const GROUND: f32 = 0.1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Crater {
+    center: Vec3,
+    latitude: f32,
+    longitude: f32,
+    // Angular radius of the rim, in radians
+    radius: f32,
+    depth: f32,
+    // From 0 for the oldest, most worn crater to 1 for a fresh one
+    freshness: f32,
+}
+
+impl Crater {
+    // Height of the crater's surface over the ground it struck, `r` radii from its centre
+    fn profile(&self, r: f32) -> f32 {
+        let depth = self.depth * (WORN + (1.0 - WORN) * self.freshness);
+        // Rims wear down faster than bowls fill up
+        let rim = depth * RIM * self.freshness;
+
+        if r < 1.0 {
+            let bowl = -depth + (depth + rim) * r * r;
+            if self.radius < COMPLEX_RADIUS {
+                return bowl;
+            }
+            let peak = depth * 0.4 * (-(r / 0.12).powi(2)).exp();
+            bowl.max(-0.8 * depth) + peak
+        } else if r < EJECTA {
+            // Ejecta thin out with the cube of the distance, reaching nothing at their edge
+            let edge = EJECTA.powi(-3);
+            rim * (r.powi(-3) - edge) / (1.0 - edge)
+        } else {
+            0.0
+        }
+    }
+
+    // Columns of a row at a latitude within reach of the ejecta, possibly past either edge
+    fn columns(&self, latitude: f32, width: u32) -> Option<Range<i64>> {
+        let reach = self.radius * EJECTA;
+        if (latitude - self.latitude).abs() > reach {
+            return None;
+        }
+
+        // Longitude either side of the centre where the row leaves the reach
+        let cos = (reach.cos() - latitude.sin() * self.latitude.sin())
+            / (latitude.cos() * self.latitude.cos());
+        let half = if cos.is_nan() || cos <= -1.0 {
+            PI
+        } else {
+            cos.min(1.0).acos()
+        };
+
+        let column = |longitude: f32| (longitude / TAU + 0.5) * width as f32 - 0.5;
+        let start = column(self.longitude - half).floor() as i64;
+        let end = column(self.longitude + half).ceil() as i64 + 1;
+        if end - start >= width as i64 {
+            return Some(0..width as i64);
+        }
+        Some(start..end)
+    }
+
+    // Strike a texel: relief inside the rim is blasted back to the ground, most of it at the
+    // centre, then the crater's own profile is laid on top
+    fn stamp(&self, elevation: &mut f32, ground: f32, point: Vec3) {
+        let r = self.center.dot(point).clamp(-1.0, 1.0).acos() / self.radius;
+        if r >= EJECTA {
+            return;
+        }
+
+        let excavated = (1.0 - r * r).max(0.0);
+        *elevation += (ground - *elevation) * excavated + self.profile(r);
+    }
+}
+
+// Airless worlds battered by impacts: craters with a power-law size distribution are stamped
+// oldest first onto low noise, younger ones cutting into the worn rims of older ones
+#[derive(Clone, Debug)]
+pub(super) struct CraterGenerator {
+    settings: WorldGenSettings,
+    craters: Vec<Crater>,
+    ground: SimplexGenerator,
+}
+
+impl CraterGenerator {
+    // `exponent` is the slope of the cumulative size distribution: there are 2^exponent times
+    // as many craters larger than any radius as larger than twice it
+    pub fn new(settings: &WorldGenSettings, craters: u32, exponent: f32) -> Self {
+        let mut rng = ChaCha8Rng::seed_from_u64(settings.seed as u64);
+        let exponent = exponent.max(0.1);
+        let truncated = 1.0 - (MIN_RADIUS / MAX_RADIUS).powf(exponent);
+
+        let mut craters: Vec<_> = (0..craters)
+            .map(|_| {
+                let center = random_direction(&mut rng);
+                let (latitude, longitude) = geo::lat_lon(center);
+                // Inverse of the power law's cumulative distribution, cut off at the largest
+                let share: f32 = rng.gen();
+                let radius = MIN_RADIUS * (1.0 - share * truncated).powf(-1.0 / exponent);
+                Crater {
+                    center,
+                    latitude,
+                    longitude,
+                    radius,
+                    depth: DEPTH * settings.amplitude * (radius / MAX_RADIUS).sqrt(),
+                    freshness: rng.gen(),
+                }
+            })
+            .collect();
+        craters.sort_by(|a, b| a.freshness.total_cmp(&b.freshness));
+
+        CraterGenerator {
+            settings: *settings,
+            craters,
+            ground: SimplexGenerator::new(&WorldGenSettings {
+                amplitude: settings.amplitude * GROUND,
+                ..*settings
+            }),
+        }
+    }
+}
+
+impl WorldGenerator for CraterGenerator {
+    fn get_heightmap(&self, progress: &Progress) -> Result<Heightmap, GenerationError> {
+        let WorldGenSettings {
+            width,
+            height,
+            amplitude,
+            ..
+        } = self.settings;
+        let mut data = vec![0.0; (width * height) as usize];
+
+        progress.start("craters", height);
+        fill_rows(&mut data, width, bands(), progress, |y, row| {
+            let latitude = geo::latitude(y as f32, height);
+            let points: Vec<_> = (0..width)
+                .map(|x| geo::direction(latitude, geo::longitude(x as f32, width)))
+                .collect();
+            let ground: Vec<_> = points
+                .iter()
+                .map(|point| self.ground.octaves(point.x, point.y, point.z))
+                .collect();
+            row.copy_from_slice(&ground);
+
+            for crater in &self.craters {
+                for x in crater.columns(latitude, width).into_iter().flatten() {
+                    let x = x.rem_euclid(width as i64) as usize;
+                    crater.stamp(&mut row[x], ground[x], points[x]);
+                }
+            }
+            for texel in row {
+                *texel = texel.clamp(-amplitude, amplitude);
+            }
+        })?;
+
+        Ok(Heightmap::new(width, height, data))
+    }
+
+    // Craters are placed the same way whatever the size of the map
+    fn with_size(&self, width: u32, height: u32) -> Box<dyn WorldGenerator> {
+        Box::new(CraterGenerator {
+            settings: self.settings.resized(width, height),
+            ..self.clone()
+        })
+    }
+
+    // Airless, so neither weathered nor flooded
+    fn passes(&self) -> Passes {
+        Passes::NONE
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{fixtures::settings, generation::Progress};
+
+    #[test]
+    fn crater_sizes_follow_power_law() {
+        use super::{CraterGenerator, MAX_RADIUS, MIN_RADIUS};
+
+        let gen = CraterGenerator::new(&settings(3, 16, 8), 20000, 2.0);
+        assert_eq!(gen.craters.len(), 20000);
+        assert!(gen
+            .craters
+            .iter()
+            .all(|crater| (MIN_RADIUS..=MAX_RADIUS).contains(&crater.radius)));
+        assert!(gen
+            .craters
+            .windows(2)
+            .all(|pair| pair[0].freshness <= pair[1].freshness));
+
+        // A quarter as many craters are larger than twice a radius
+        let larger = |radius: f32| {
+            gen.craters
+                .iter()
+                .filter(|crater| crater.radius > radius)
+                .count() as f32
+        };
+        let ratio = larger(MIN_RADIUS * 4.0) / larger(MIN_RADIUS * 2.0);
+        assert!((ratio - 0.25).abs() < 0.03, "Ratio was {}", ratio);
+
+        assert_eq!(
+            CraterGenerator::new(&settings(3, 16, 8), 20000, 2.0).craters,
+            gen.craters
+        );
+    }
+
+    #[test]
+    fn crater_profile_has_bowl_rim_and_ejecta() {
+        use super::{CraterGenerator, EJECTA};
+
+        let gen = CraterGenerator::new(&settings(3, 16, 8), 200, 2.0);
+        for crater in &gen.craters {
+            assert!(crater.profile(0.0) < 0.0);
+            assert!(crater.profile(0.9) > crater.profile(0.0));
+            assert!(crater.profile(1.0) >= 0.0);
+            assert_eq!(crater.profile(EJECTA), 0.0);
+            assert!(crater.profile(1.5) >= crater.profile(2.0));
+        }
+
+        // Fresh craters stand taller and dig deeper than worn ones of the same size
+        let crater = gen.craters[0];
+        let (worn, fresh) = (
+            super::Crater {
+                freshness: 0.0,
+                ..crater
+            },
+            super::Crater {
+                freshness: 1.0,
+                ..crater
+            },
+        );
+        assert!(fresh.profile(1.0) > worn.profile(1.0));
+        assert!(fresh.profile(0.0) < worn.profile(0.0));
+    }
+
+    #[test]
+    fn crater_columns_wrap_around() {
+        use super::CraterGenerator;
+
+        let mut crater = CraterGenerator::new(&settings(3, 16, 8), 1, 2.0).craters[0];
+        crater.radius = 0.1;
+        crater.latitude = 0.0;
+        crater.longitude = std::f32::consts::PI - 0.05;
+
+        let columns = crater.columns(0.0, 360).unwrap();
+        assert!(columns.start < 360 && columns.end > 360);
+        assert_eq!(crater.columns(0.5, 360), None);
+
+        // Rows near a pole covered by the crater are covered all the way round
+        crater.latitude = std::f32::consts::FRAC_PI_2 - 0.01;
+        assert_eq!(crater.columns(1.56, 360), Some(0..360));
+    }
+
+    #[test]
+    fn craters_dig_into_the_map() {
+        use super::{geo, Crater, CraterGenerator, Vec3, WorldGenerator};
+
+        let mut gen = CraterGenerator::new(&settings(3, 512, 256), 1, 2.0);
+        let crater = Crater {
+            center: geo::direction(0.3, 1.0),
+            latitude: 0.3,
+            longitude: 1.0,
+            radius: 0.3,
+            depth: 0.3,
+            freshness: 1.0,
+        };
+        gen.craters = vec![crater];
+
+        let map = gen.get_heightmap(&Progress::default()).unwrap();
+        let tangent = crater.center.cross(Vec3::Z).normalize();
+        let at = |r: f32| {
+            let angle = r * crater.radius;
+            map.interpolate(crater.center * angle.cos() + tangent * angle.sin())
+        };
+        // A flat floor with a central peak, rising to the rim and falling away over the ejecta
+        assert!(at(0.5) < at(0.0));
+        assert!(at(0.0) < at(1.0));
+        assert!(at(0.5) < at(0.9));
+        assert!(at(1.0) > at(2.0));
+
+        // Previews show the same crater
+        let preview = gen.with_size(128, 64);
+        let small = preview.get_heightmap(&Progress::default()).unwrap();
+        assert!((small.interpolate(crater.center) - at(0.0)).abs() < 0.05);
+    }
+}
@@ -0,0 +1,508 @@
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use super::{
+    generation::{GenerationError, MapPass, Progress},
+    heightmap::Heightmap,
+};
+
+// Droplet based hydraulic erosion, following Hans Theobald Beyer's
+// "Implementation of a method for hydraulic erosion"
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct HydraulicErosion {
+    pub seed: u64,
+    // Number of droplets simulated
+    pub iterations: u32,
+    // Maximum number of steps taken by a single droplet
+    pub lifetime: u32,
+    // How much a droplet keeps its direction instead of following the slope, in [0, 1]
+    pub inertia: f32,
+    // Sediment a droplet can carry per unit of slope, speed and water
+    pub capacity: f32,
+    // Lowest slope used for the carry capacity, so flat ground still erodes
+    pub min_slope: f32,
+    // Fraction of the free capacity eroded every step
+    pub erosion_rate: f32,
+    // Fraction of the excess sediment deposited every step
+    pub deposition_rate: f32,
+    // Fraction of water evaporating every step
+    pub evaporation: f32,
+    pub gravity: f32,
+}
+
+impl Default for HydraulicErosion {
+    fn default() -> Self {
+        HydraulicErosion {
+            seed: 0,
+            iterations: 1_000_000,
+            lifetime: 30,
+            inertia: 0.05,
+            capacity: 4.0,
+            min_slope: 0.01,
+            erosion_rate: 0.3,
+            deposition_rate: 0.3,
+            evaporation: 0.01,
+            gravity: 4.0,
+        }
+    }
+}
+
+// Height and gradient of the map at a point, interpolated between the four surrounding texels
+fn height_and_gradient(map: &Heightmap, x: f32, y: f32) -> (f32, f32, f32) {
+    let (x0, y0) = (x.floor(), y.floor());
+    let (u, v) = (x - x0, y - y0);
+    let (x0, y0) = (x0 as isize, y0 as isize);
+
+    let h00 = map.get(x0, y0);
+    let h10 = map.get(x0 + 1, y0);
+    let h01 = map.get(x0, y0 + 1);
+    let h11 = map.get(x0 + 1, y0 + 1);
+
+    let height =
+        h00 * (1.0 - u) * (1.0 - v) + h10 * u * (1.0 - v) + h01 * (1.0 - u) * v + h11 * u * v;
+    let gradient_x = (h10 - h00) * (1.0 - v) + (h11 - h01) * v;
+    let gradient_y = (h01 - h00) * (1.0 - u) + (h11 - h10) * u;
+
+    (height, gradient_x, gradient_y)
+}
+
+// Add an amount to the four texels surrounding a point, weighted by distance
+fn spread(map: &mut Heightmap, x: f32, y: f32, amount: f32) {
+    let (x0, y0) = (x.floor(), y.floor());
+    let (u, v) = (x - x0, y - y0);
+    let (x0, y0) = (x0 as isize, y0 as isize);
+
+    for (dx, dy, weight) in [
+        (0, 0, (1.0 - u) * (1.0 - v)),
+        (1, 0, u * (1.0 - v)),
+        (0, 1, (1.0 - u) * v),
+        (1, 1, u * v),
+    ] {
+        let idx = map.index(x0 + dx, y0 + dy);
+        map.data[idx] += amount * weight;
+    }
+}
+
+impl HydraulicErosion {
+    fn droplet(&self, map: &mut Heightmap, rng: &mut ChaCha8Rng) {
+        let width = map.width as f32;
+        let last_row = (map.height - 1) as f32;
+
+        // Pick the starting point uniformly over the sphere, not over the texels
+        let mut x = rng.gen_range(0.0..width);
+        let mut y = map
+            .row(rng.gen_range(-1.0f32..1.0).asin())
+            .clamp(0.0, last_row);
+
+        let (mut dir_x, mut dir_y) = (0.0, 0.0);
+        let mut speed = 1.0;
+        let mut water = 1.0;
+        let mut sediment = 0.0;
+
+        for _ in 0..self.lifetime {
+            let (height, gradient_x, gradient_y) = height_and_gradient(map, x, y);
+
+            // Columns get narrower towards the poles, so a column step covers less ground
+            let stretch = 1.0 / map.latitude(y).cos().max(0.1);
+
+            dir_x = dir_x * self.inertia - gradient_x * stretch * (1.0 - self.inertia);
+            dir_y = dir_y * self.inertia - gradient_y * (1.0 - self.inertia);
+
+            let len = (dir_x * dir_x + dir_y * dir_y).sqrt();
+            if len < f32::EPSILON {
+                break;
+            }
+            dir_x /= len;
+            dir_y /= len;
+
+            let (old_x, old_y) = (x, y);
+            x += dir_x * stretch;
+            y += dir_y;
+
+            // Crossing a pole continues down the opposite meridian
+            if y < 0.0 || y > last_row {
+                y = if y < 0.0 { -y } else { 2.0 * last_row - y };
+                x += width / 2.0;
+                dir_y = -dir_y;
+            }
+            x = x.rem_euclid(width);
+
+            let (new_height, _, _) = height_and_gradient(map, x, y);
+            let delta = new_height - height;
+
+            let capacity = (-delta).max(self.min_slope) * speed * water * self.capacity;
+
+            if sediment > capacity || delta > 0.0 {
+                // Fill the pit we are climbing out of, or drop what we can't carry
+                let deposit = if delta > 0.0 {
+                    delta.min(sediment)
+                } else {
+                    (sediment - capacity) * self.deposition_rate
+                };
+                sediment -= deposit;
+                spread(map, old_x, old_y, deposit);
+            } else {
+                // Never dig deeper than the ground we are flowing to
+                let erode = ((capacity - sediment) * self.erosion_rate).min(-delta);
+                sediment += erode;
+                spread(map, old_x, old_y, -erode);
+            }
+
+            speed = (speed * speed - delta * self.gravity).max(0.0).sqrt();
+            water *= 1.0 - self.evaporation;
+        }
+
+        // Whatever is still carried settles where the droplet dies
+        spread(map, x, y, sediment);
+    }
+}
+
+impl MapPass for HydraulicErosion {
+    fn apply(&self, mut map: Heightmap, progress: &Progress) -> Result<Heightmap, GenerationError> {
+        let mut rng = ChaCha8Rng::seed_from_u64(self.seed);
+
+        progress.start("hydraulic erosion", self.iterations);
+        for _ in 0..self.iterations {
+            if progress.is_cancelled() {
+                return Err(GenerationError::Cancelled);
+            }
+
+            self.droplet(&mut map, &mut rng);
+            progress.advance(1);
+        }
+
+        Ok(map)
+    }
+}
+
+// Thermal weathering, moving material downhill wherever a slope is steeper than the talus angle
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct ThermalErosion {
+    // Number of passes over the whole map
+    pub iterations: u32,
+    // Steepest stable slope, in radians
+    pub talus_angle: f32,
+    // Fraction of the excess material moved every pass, in (0, 0.5]
+    pub rate: f32,
+    // Radius of the sphere the map is wrapped around, in elevation units
+    pub radius: f32,
+}
+
+impl Default for ThermalErosion {
+    fn default() -> Self {
+        ThermalErosion {
+            iterations: 50,
+            talus_angle: 35f32.to_radians(),
+            rate: 0.5,
+            radius: super::RADIUS,
+        }
+    }
+}
+
+impl ThermalErosion {
+    // Material leaving each texel in one pass, along with what its neighbours receive
+    fn weather(&self, map: &Heightmap, delta: &mut [f32], y: isize) {
+        // Distance between texel centres, rows are evenly spaced while columns shrink at the poles
+        let row_spacing = std::f32::consts::PI * self.radius / map.height as f32;
+        let column_spacing = std::f32::consts::TAU * self.radius / map.width as f32
+            * map.latitude(y as f32).cos().abs();
+
+        let talus = self.talus_angle.tan();
+        let row_talus = talus * row_spacing;
+        let column_talus = talus * column_spacing;
+
+        for x in 0..map.width as isize {
+            let idx = map.index(x, y);
+            let height = map.data[idx];
+
+            let mut excess = [(0, 0.0); 4];
+            let mut total = 0.0;
+            let mut steepest = 0.0f32;
+
+            for (i, (dx, dy, threshold)) in [
+                (-1, 0, column_talus),
+                (1, 0, column_talus),
+                (0, -1, row_talus),
+                (0, 1, row_talus),
+            ]
+            .into_iter()
+            .enumerate()
+            {
+                let neighbour = map.index(x + dx, y + dy);
+                // Rows are clamped at the poles, which would make a texel its own neighbour
+                if neighbour == idx {
+                    continue;
+                }
+
+                let diff = height - map.data[neighbour] - threshold;
+                if diff > 0.0 {
+                    excess[i] = (neighbour, diff);
+                    total += diff;
+                    steepest = steepest.max(diff);
+                }
+            }
+
+            if total == 0.0 {
+                continue;
+            }
+
+            let moved = self.rate * steepest;
+            delta[idx] -= moved;
+            for (neighbour, diff) in excess {
+                if diff > 0.0 {
+                    delta[neighbour] += moved * diff / total;
+                }
+            }
+        }
+    }
+}
+
+impl MapPass for ThermalErosion {
+    fn apply(&self, mut map: Heightmap, progress: &Progress) -> Result<Heightmap, GenerationError> {
+        let mut delta = vec![0.0; map.data.len()];
+
+        progress.start("thermal erosion", self.iterations * map.height);
+        for _ in 0..self.iterations {
+            // All texels see the same heights within a pass, so scan order doesn't matter
+            delta.iter_mut().for_each(|d| *d = 0.0);
+
+            for y in 0..map.height as isize {
+                if progress.is_cancelled() {
+                    return Err(GenerationError::Cancelled);
+                }
+
+                self.weather(&map, &mut delta, y);
+                progress.advance(1);
+            }
+
+            map.data
+                .iter_mut()
+                .zip(delta.iter())
+                .for_each(|(h, d)| *h += d);
+        }
+
+        Ok(map)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{generation::Progress, heightmap::Heightmap};
+
+    // Deterministic rolling hills, with a ridge crossing the wrapping seam
+    fn hills(width: u32, height: u32) -> Heightmap {
+        let mut data = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                let (x, y) = (x as f32, y as f32);
+                data.push(
+                    0.3 * (x * 0.2).cos() * (y * 0.15).sin() + 0.1 * (x * 0.9 + y * 0.6).sin(),
+                );
+            }
+        }
+
+        Heightmap::new(width, height, data)
+    }
+
+    fn statistics(map: &Heightmap) -> (f32, f32) {
+        let n = map.data.len() as f64;
+        let mean = map.data.iter().map(|f| *f as f64).sum::<f64>() / n;
+        let variance = map
+            .data
+            .iter()
+            .map(|f| (*f as f64 - mean).powi(2))
+            .sum::<f64>()
+            / n;
+
+        (mean as f32, variance.sqrt() as f32)
+    }
+
+    #[test]
+    fn hydraulic_erosion_is_deterministic() {
+        use super::{HydraulicErosion, MapPass};
+
+        let erosion = HydraulicErosion {
+            seed: 42,
+            iterations: 2000,
+            ..Default::default()
+        };
+        let first = erosion.apply(hills(96, 48), &Progress::default()).unwrap();
+        let second = erosion.apply(hills(96, 48), &Progress::default()).unwrap();
+        assert_eq!(first, second);
+
+        let other = HydraulicErosion {
+            seed: 43,
+            ..erosion
+        }
+        .apply(hills(96, 48), &Progress::default())
+        .unwrap();
+        assert_ne!(first, other);
+    }
+
+    #[test]
+    fn hydraulic_erosion_matches_reference() {
+        use super::{HydraulicErosion, MapPass};
+
+        let original = hills(128, 64);
+        let (mean, deviation) = statistics(&original);
+
+        let eroded = HydraulicErosion {
+            seed: 7,
+            iterations: 5000,
+            ..Default::default()
+        }
+        .apply(original, &Progress::default())
+        .unwrap();
+        let (eroded_mean, eroded_deviation) = statistics(&eroded);
+
+        // Material is moved around, not created or destroyed
+        assert!((eroded_mean - mean).abs() < 1e-5);
+        // Peaks are worn down and valleys filled
+        assert!(eroded_deviation < deviation);
+
+        // Recorded from a known good run
+        assert!((eroded_deviation - 0.07788).abs() < 1e-4);
+    }
+
+    #[test]
+    fn hydraulic_erosion_keeps_flat_ground() {
+        use super::{HydraulicErosion, MapPass};
+
+        let flat = Heightmap::new(32, 16, vec![0.25; 32 * 16]);
+        let eroded = HydraulicErosion {
+            iterations: 500,
+            ..Default::default()
+        }
+        .apply(flat.clone(), &Progress::default())
+        .unwrap();
+
+        for (a, b) in flat.data.iter().zip(eroded.data.iter()) {
+            assert!((a - b).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn hydraulic_erosion_stops_when_cancelled() {
+        use super::{GenerationError, HydraulicErosion, MapPass};
+
+        let progress = Progress::default();
+        progress.cancel();
+        assert!(matches!(
+            HydraulicErosion::default().apply(hills(32, 16), &progress),
+            Err(GenerationError::Cancelled)
+        ));
+    }
+
+    // Flat ground with a single needle
+    fn spike(width: u32, height: u32, x: isize, y: isize) -> Heightmap {
+        let mut map = Heightmap::new(width, height, vec![0.0; (width * height) as usize]);
+        let idx = map.index(x, y);
+        map.data[idx] = 1.0;
+        map
+    }
+
+    // Steepest slope of the map, in radians
+    fn steepest(map: &Heightmap, radius: f32) -> f32 {
+        let row_spacing = std::f32::consts::PI * radius / map.height as f32;
+        let mut steepest = 0.0f32;
+
+        for y in 0..map.height as isize {
+            let column_spacing =
+                std::f32::consts::TAU * radius / map.width as f32 * map.latitude(y as f32).cos();
+            for x in 0..map.width as isize {
+                let h = map.get(x, y);
+                steepest = steepest
+                    .max(((h - map.get(x + 1, y)).abs() / column_spacing).atan())
+                    .max(
+                        ((h - map.get(x, (y + 1).min(map.height as isize - 1))).abs()
+                            / row_spacing)
+                            .atan(),
+                    );
+            }
+        }
+
+        steepest
+    }
+
+    #[test]
+    fn thermal_erosion_flattens_spikes() {
+        use super::{MapPass, ThermalErosion};
+
+        let erosion = ThermalErosion {
+            iterations: 500,
+            ..Default::default()
+        };
+        let map = spike(64, 32, 20, 16);
+        let (mean, _) = statistics(&map);
+
+        let eroded = erosion.apply(map, &Progress::default()).unwrap();
+        let (eroded_mean, _) = statistics(&eroded);
+
+        assert!((eroded_mean - mean).abs() < 1e-6);
+        // The needle slumps into a cone standing at the talus angle
+        assert!(eroded.get(20, 16) < 0.5);
+        assert!(steepest(&eroded, erosion.radius) < erosion.talus_angle + 0.01);
+    }
+
+    #[test]
+    fn thermal_erosion_keeps_stable_slopes() {
+        use super::{MapPass, ThermalErosion};
+
+        let gentle = hills(64, 32);
+        let erosion = ThermalErosion {
+            talus_angle: 89f32.to_radians(),
+            ..Default::default()
+        };
+        assert!(steepest(&gentle, erosion.radius) < erosion.talus_angle);
+
+        let eroded = erosion.apply(gentle.clone(), &Progress::default()).unwrap();
+        assert_eq!(gentle, eroded);
+    }
+
+    #[test]
+    fn thermal_erosion_wraps_around() {
+        use super::{MapPass, ThermalErosion};
+
+        let eroded = ThermalErosion {
+            iterations: 1,
+            ..Default::default()
+        }
+        .apply(spike(64, 32, 0, 16), &Progress::default())
+        .unwrap();
+
+        assert!(eroded.get(-1, 16) > 0.0);
+        assert_eq!(eroded.get(-1, 16), eroded.get(1, 16));
+    }
+
+    #[test]
+    fn erosion_passes_compose() {
+        use super::{HydraulicErosion, MapPass, ThermalErosion};
+
+        let progress = Progress::default();
+        let hydraulic = HydraulicErosion {
+            iterations: 1000,
+            ..Default::default()
+        };
+        let thermal = ThermalErosion::default();
+
+        let map = hydraulic.apply(hills(64, 32), &progress).unwrap();
+        let map = thermal.apply(map, &progress).unwrap();
+        let (mean, _) = statistics(&hills(64, 32));
+        let (eroded_mean, _) = statistics(&map);
+
+        assert!((eroded_mean - mean).abs() < 1e-5);
+        assert_eq!(progress.stage(), "thermal erosion");
+    }
+
+    #[test]
+    fn thermal_erosion_stops_when_cancelled() {
+        use super::{GenerationError, MapPass, ThermalErosion};
+
+        let progress = Progress::default();
+        progress.cancel();
+        assert!(matches!(
+            ThermalErosion::default().apply(hills(32, 16), &progress),
+            Err(GenerationError::Cancelled)
+        ));
+    }
+}
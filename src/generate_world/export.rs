@@ -0,0 +1,243 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{heightmap::Heightmap, stats::WorldStats};
+
+// JSON sidecar written next to exported maps and read back by import. Sidecars written by
+// other tools may leave anything out, and non-finite numbers are written as null.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub(crate) struct Sidecar {
+    pub seed: Option<u32>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub projection: Option<String>,
+    pub rows: Option<String>,
+    pub min: Option<f32>,
+    pub max: Option<f32>,
+    // Only written, import has no use for them
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub stats: Option<WorldStats>,
+}
+
+// Elevation map written out for other tools: a 16-bit grayscale PNG stretched between the lowest
+// and highest elevation, raw little-endian f32 in `.r32`, and a JSON sidecar describing both.
+// Rows are written from the north pole down, as images are viewed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct ElevationExport<'a> {
+    pub map: &'a Heightmap,
+    pub seed: u32,
+    // Written into the sidecar when known
+    pub stats: Option<&'a WorldStats>,
+}
+
+impl<'a> ElevationExport<'a> {
+    pub fn new(map: &'a Heightmap, seed: u32) -> Self {
+        ElevationExport {
+            map,
+            seed,
+            stats: None,
+        }
+    }
+
+    pub fn with_stats(self, stats: &'a WorldStats) -> Self {
+        ElevationExport {
+            stats: Some(stats),
+            ..self
+        }
+    }
+
+    // Lowest and highest elevation of the map
+    pub fn range(&self) -> (f32, f32) {
+        self.map
+            .data
+            .iter()
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), f| {
+                (min.min(*f), max.max(*f))
+            })
+    }
+
+    // Rows of the map from north to south
+    fn rows(&self) -> impl Iterator<Item = &'a [f32]> {
+        self.map.data.chunks(self.map.width as usize).rev()
+    }
+
+    pub fn write_png(&self, w: impl Write) -> io::Result<()> {
+        let (min, max) = self.range();
+        let scale = if max > min { 1.0 / (max - min) } else { 0.0 };
+
+        let mut bytes = Vec::with_capacity(self.map.data.len() * 2);
+        for f in self.rows().flatten() {
+            let gray = ((f - min) * scale * u16::MAX as f32).round() as u16;
+            bytes.extend_from_slice(&gray.to_be_bytes());
+        }
+
+        let mut encoder = png::Encoder::new(w, self.map.width, self.map.height);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Sixteen);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&bytes)?;
+        Ok(())
+    }
+
+    pub fn write_r32(&self, mut w: impl Write) -> io::Result<()> {
+        for f in self.rows().flatten() {
+            w.write_all(&f.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    pub fn sidecar(&self) -> Sidecar {
+        let (min, max) = self.range();
+        Sidecar {
+            seed: Some(self.seed),
+            width: Some(self.map.width),
+            height: Some(self.map.height),
+            projection: Some("equirectangular".into()),
+            rows: Some("north to south".into()),
+            min: Some(min),
+            max: Some(max),
+            stats: self.stats.cloned(),
+        }
+    }
+
+    pub fn write_sidecar(&self, mut w: impl Write) -> io::Result<()> {
+        serde_json::to_writer_pretty(&mut w, &self.sidecar())?;
+        writeln!(w)
+    }
+
+    // Write all three files into a directory, named after `name`. Returns the paths written
+    pub fn save(&self, directory: &Path, name: &str) -> io::Result<Vec<PathBuf>> {
+        std::fs::create_dir_all(directory)?;
+
+        let mut paths = Vec::new();
+        for extension in ["png", "r32", "json"] {
+            let path = directory.join(format!("{}.{}", name, extension));
+            let mut file = BufWriter::new(File::create(&path)?);
+            match extension {
+                "png" => self.write_png(&mut file)?,
+                "r32" => self.write_r32(&mut file)?,
+                _ => self.write_sidecar(&mut file)?,
+            }
+            file.flush()?;
+            paths.push(path);
+        }
+
+        Ok(paths)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{heightmap::Heightmap, world_data::WorldData};
+    use super::{ElevationExport, WorldStats};
+
+    // Rising from -1 at the south pole to 1 at the north pole
+    fn map() -> Heightmap {
+        Heightmap::new(
+            4,
+            3,
+            vec![
+                -1.0, -1.0, -1.0, -1.0, 0.0, 0.5, 0.0, 0.0, 1.0, 1.0, 1.0, 1.0,
+            ],
+        )
+    }
+
+    #[test]
+    fn export_range() {
+        let map = map();
+        assert_eq!(ElevationExport::new(&map, 7).range(), (-1.0, 1.0));
+    }
+
+    #[test]
+    fn export_png_header() {
+        let map = map();
+        let mut png = Vec::new();
+        ElevationExport::new(&map, 7).write_png(&mut png).unwrap();
+
+        assert_eq!(&png[0..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[16..20], &4u32.to_be_bytes());
+        assert_eq!(&png[20..24], &3u32.to_be_bytes());
+        // 16-bit grayscale
+        assert_eq!(png[24], 16);
+        assert_eq!(png[25], 0);
+    }
+
+    #[test]
+    fn export_r32_north_up() {
+        let map = map();
+        let mut raw = Vec::new();
+        ElevationExport::new(&map, 7).write_r32(&mut raw).unwrap();
+
+        assert_eq!(raw.len(), 4 * 3 * 4);
+        let values: Vec<f32> = raw
+            .chunks(4)
+            .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        assert_eq!(&values[0..4], &[1.0; 4]);
+        assert_eq!(values[5], 0.5);
+        assert_eq!(&values[8..12], &[-1.0; 4]);
+    }
+
+    #[test]
+    fn export_sidecar() {
+        use super::Sidecar;
+
+        let map = map();
+        let mut json = Vec::new();
+        ElevationExport::new(&map, 7)
+            .write_sidecar(&mut json)
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+
+        assert_eq!(json["seed"], 7);
+        assert_eq!(json["width"], 4);
+        assert_eq!(json["height"], 3);
+        assert_eq!(json["projection"], "equirectangular");
+        assert_eq!(json["min"], -1.0);
+        assert_eq!(json["max"], 1.0);
+        assert!(json.get("stats").is_none());
+
+        // Statistics are written along with the range when given
+        let stats = WorldStats::compute(&WorldData::new(map.clone()), 0.0);
+        let mut json = Vec::new();
+        ElevationExport::new(&map, 7)
+            .with_stats(&stats)
+            .write_sidecar(&mut json)
+            .unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(value["stats"]["land_fraction"], stats.land_fraction);
+        let sidecar: Sidecar = serde_json::from_slice(&json).unwrap();
+        assert_eq!((sidecar.min, sidecar.max), (Some(-1.0), Some(1.0)));
+
+        // Maps that aren't all finite still make valid JSON
+        let broken = Heightmap::new(2, 1, vec![f32::NAN, f32::INFINITY]);
+        let mut json = Vec::new();
+        ElevationExport::new(&broken, 7)
+            .write_sidecar(&mut json)
+            .unwrap();
+        let sidecar: Sidecar = serde_json::from_slice(&json).unwrap();
+        assert_eq!(sidecar.max, None);
+    }
+
+    #[test]
+    fn export_saves_files() {
+        let map = map();
+        let directory = std::env::temp_dir().join(format!("export-{}", std::process::id()));
+        let paths = ElevationExport::new(&map, 7)
+            .save(&directory, "world")
+            .unwrap();
+
+        assert_eq!(paths.len(), 3);
+        assert_eq!(std::fs::metadata(&paths[1]).unwrap().len(), 4 * 3 * 4);
+        for path in &paths {
+            assert!(path.exists());
+        }
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
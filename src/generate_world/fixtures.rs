@@ -0,0 +1,357 @@
+use naga::{
+    front::wgsl, BinaryOperator, Block, Constant, ConstantInner, Expression, Function, Handle,
+    LocalVariable, MathFunction, Module, ScalarKind, ScalarValue, Statement, TypeInner,
+    UnaryOperator,
+};
+
+use super::generation::WorldGenSettings;
+
+// Settings for the small maps tests raise, seeded so every run raises the same world
+pub(super) fn settings(seed: u32, width: u32, height: u32) -> WorldGenSettings {
+    WorldGenSettings {
+        seed,
+        width,
+        height,
+        ..Default::default()
+    }
+}
+
+// A shader from the assets, its functions run on the CPU one invocation at a time, so tests can
+// hold the shader's math against the code it mirrors. Only what the shaders use is known.
+pub(super) struct Shader {
+    module: Module,
+}
+
+impl Shader {
+    // Parse a shader, without the import path naga doesn't know about
+    pub fn load(path: &str) -> Self {
+        let source =
+            std::fs::read_to_string(format!("{}/assets/{}", env!("CARGO_MANIFEST_DIR"), path))
+                .expect("Missing shader");
+        let source = source
+            .lines()
+            .filter(|line| !line.starts_with("#define_import_path"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let module = wgsl::parse_str(&source).unwrap_or_else(|err| {
+            err.emit_to_stderr(&source);
+            panic!("{} doesn't parse", path);
+        });
+        Shader { module }
+    }
+
+    // Result of a function of the shader, the arguments and result given component by component
+    pub fn call(&self, name: &str, args: &[&[f32]]) -> Vec<f32> {
+        let (function, declared) = self
+            .module
+            .functions
+            .iter()
+            .find(|(_, function)| function.name.as_deref() == Some(name))
+            .unwrap_or_else(|| panic!("The shader has no function {}", name));
+        let args = declared
+            .arguments
+            .iter()
+            .zip(args)
+            .map(
+                |(declared, arg)| match self.module.types[declared.ty].inner {
+                    TypeInner::Scalar { kind, .. } | TypeInner::Vector { kind, .. } => {
+                        Value::Number(kind, arg.to_vec())
+                    }
+                    ref other => panic!("Can't pass a {:?}", other),
+                },
+            )
+            .collect();
+        match self.run(function, args) {
+            Some(Value::Number(_, components)) => components,
+            other => panic!("{} returned {:?}", name, other),
+        }
+    }
+
+    fn run(&self, function: Handle<Function>, args: Vec<Value>) -> Option<Value> {
+        let function = &self.module.functions[function];
+        let mut frame = Frame {
+            shader: self,
+            function,
+            args,
+            values: vec![None; function.expressions.len()],
+            locals: function
+                .local_variables
+                .iter()
+                .map(|(_, local)| local.init.map(|init| self.constant(init)))
+                .collect(),
+        };
+        frame.block(&function.body).flatten()
+    }
+
+    fn constant(&self, constant: Handle<Constant>) -> Value {
+        match self.module.constants[constant].inner {
+            ConstantInner::Scalar { value, .. } => match value {
+                ScalarValue::Float(f) => Value::Number(ScalarKind::Float, vec![f as f32]),
+                ScalarValue::Sint(i) => Value::Number(ScalarKind::Sint, vec![i as f32]),
+                ScalarValue::Uint(u) => Value::Number(ScalarKind::Uint, vec![u as f32]),
+                ScalarValue::Bool(b) => Value::Number(ScalarKind::Bool, vec![b as u8 as f32]),
+            },
+            ref composite => panic!("Can't evaluate {:?}", composite),
+        }
+    }
+}
+
+// Numbers are held as f32 whatever their kind, as the GPU computes, with booleans 0 or 1.
+// Integers in the shaders are small enough to be exact.
+#[derive(Clone, Debug)]
+enum Value {
+    Number(ScalarKind, Vec<f32>),
+    Pointer(Handle<LocalVariable>),
+}
+
+// One invocation of a function, with the expressions evaluated and the variables written so far
+struct Frame<'a> {
+    shader: &'a Shader,
+    function: &'a Function,
+    args: Vec<Value>,
+    values: Vec<Option<Value>>,
+    locals: Vec<Option<Value>>,
+}
+
+impl Frame<'_> {
+    // Run statements until one returns, with what it returns
+    fn block(&mut self, block: &Block) -> Option<Option<Value>> {
+        for statement in block {
+            match *statement {
+                // Expressions reading variables see them as they are when emitted
+                Statement::Emit(ref range) => {
+                    for expression in range.clone() {
+                        self.values[expression.index()] = None;
+                        self.eval(expression);
+                    }
+                }
+                Statement::Block(ref block) => {
+                    if let Some(result) = self.block(block) {
+                        return Some(result);
+                    }
+                }
+                Statement::If {
+                    condition,
+                    ref accept,
+                    ref reject,
+                } => {
+                    let block = if self.number(condition).1[0] != 0.0 {
+                        accept
+                    } else {
+                        reject
+                    };
+                    if let Some(result) = self.block(block) {
+                        return Some(result);
+                    }
+                }
+                Statement::Store { pointer, value } => {
+                    let value = self.eval(value);
+                    match self.eval(pointer) {
+                        Value::Pointer(local) => self.locals[local.index()] = Some(value),
+                        other => panic!("Can't store to {:?}", other),
+                    }
+                }
+                Statement::Call {
+                    function,
+                    ref arguments,
+                    result,
+                } => {
+                    let args = arguments.iter().map(|&arg| self.eval(arg)).collect();
+                    let value = self.shader.run(function, args);
+                    if let Some(result) = result {
+                        self.values[result.index()] = value;
+                    }
+                }
+                Statement::Return { value } => return Some(value.map(|value| self.eval(value))),
+                ref other => panic!("Can't run {:?}", other),
+            }
+        }
+        None
+    }
+
+    fn number(&mut self, expression: Handle<Expression>) -> (ScalarKind, Vec<f32>) {
+        match self.eval(expression) {
+            Value::Number(kind, components) => (kind, components),
+            other => panic!("{:?} is not a number", other),
+        }
+    }
+
+    fn eval(&mut self, expression: Handle<Expression>) -> Value {
+        if let Some(value) = &self.values[expression.index()] {
+            return value.clone();
+        }
+
+        let value = match self.function.expressions[expression] {
+            Expression::Constant(constant) => self.shader.constant(constant),
+            Expression::FunctionArgument(index) => self.args[index as usize].clone(),
+            Expression::LocalVariable(local) => Value::Pointer(local),
+            Expression::Load { pointer } => match self.eval(pointer) {
+                Value::Pointer(local) => self.locals[local.index()]
+                    .clone()
+                    .expect("Variable read before it is written"),
+                other => panic!("Can't load from {:?}", other),
+            },
+            Expression::AccessIndex { base, index } => {
+                let (kind, components) = self.number(base);
+                Value::Number(kind, vec![components[index as usize]])
+            }
+            Expression::Access { base, index } => {
+                let index = self.number(index).1[0] as usize;
+                let (kind, components) = self.number(base);
+                Value::Number(kind, vec![components[index]])
+            }
+            Expression::Swizzle {
+                size,
+                vector,
+                pattern,
+            } => {
+                let (kind, components) = self.number(vector);
+                let swizzled = pattern[..size as usize]
+                    .iter()
+                    .map(|&component| components[component as usize])
+                    .collect();
+                Value::Number(kind, swizzled)
+            }
+            Expression::Splat { size, value } => {
+                let (kind, components) = self.number(value);
+                Value::Number(kind, vec![components[0]; size as usize])
+            }
+            Expression::Compose { ref components, .. } => {
+                let mut kind = ScalarKind::Float;
+                let mut composed = Vec::new();
+                for &component in components {
+                    let (component_kind, values) = self.number(component);
+                    kind = component_kind;
+                    composed.extend(values);
+                }
+                Value::Number(kind, composed)
+            }
+            Expression::Unary { op, expr } => {
+                let (kind, components) = self.number(expr);
+                let apply = |x: f32| match op {
+                    UnaryOperator::Negate => -x,
+                    UnaryOperator::Not if kind == ScalarKind::Bool => 1.0 - x,
+                    UnaryOperator::Not => panic!("Can't complement bits"),
+                };
+                Value::Number(kind, components.into_iter().map(apply).collect())
+            }
+            Expression::Binary { op, left, right } => {
+                let (kind, a) = self.number(left);
+                let (_, b) = self.number(right);
+                binary(op, kind, &a, &b)
+            }
+            Expression::Select {
+                condition,
+                accept,
+                reject,
+            } => {
+                let (_, condition) = self.number(condition);
+                let (kind, accept) = self.number(accept);
+                let (_, reject) = self.number(reject);
+                let selected = (0..accept.len())
+                    .map(|i| {
+                        if component(&condition, i) != 0.0 {
+                            accept[i]
+                        } else {
+                            reject[i]
+                        }
+                    })
+                    .collect();
+                Value::Number(kind, selected)
+            }
+            Expression::Math {
+                fun,
+                arg,
+                arg1,
+                arg2,
+                ..
+            } => {
+                let (kind, first) = self.number(arg);
+                let rest: Vec<_> = [arg1, arg2]
+                    .into_iter()
+                    .flatten()
+                    .map(|arg| self.number(arg).1)
+                    .collect();
+                Value::Number(kind, math(fun, &first, &rest))
+            }
+            Expression::As {
+                expr,
+                kind,
+                convert: Some(_),
+            } => {
+                let (_, components) = self.number(expr);
+                let converted = match kind {
+                    ScalarKind::Float => components,
+                    _ => components.into_iter().map(f32::trunc).collect(),
+                };
+                Value::Number(kind, converted)
+            }
+            Expression::CallResult(_) => panic!("Call result read before the call"),
+            ref other => panic!("Can't evaluate {:?}", other),
+        };
+        self.values[expression.index()] = Some(value.clone());
+        value
+    }
+}
+
+// Component of a vector, or the value of a scalar used along with vectors
+fn component(values: &[f32], i: usize) -> f32 {
+    values[i.min(values.len() - 1)]
+}
+
+fn binary(op: BinaryOperator, kind: ScalarKind, a: &[f32], b: &[f32]) -> Value {
+    let integer = matches!(kind, ScalarKind::Sint | ScalarKind::Uint);
+    let result = (0..a.len().max(b.len())).map(|i| {
+        let (x, y) = (component(a, i), component(b, i));
+        match op {
+            BinaryOperator::Add => x + y,
+            BinaryOperator::Subtract => x - y,
+            BinaryOperator::Multiply => x * y,
+            BinaryOperator::Divide if integer => (x / y).trunc(),
+            BinaryOperator::Divide => x / y,
+            // Both truncate towards zero, as WGSL does
+            BinaryOperator::Modulo => x % y,
+            BinaryOperator::Equal => (x == y) as u8 as f32,
+            BinaryOperator::NotEqual => (x != y) as u8 as f32,
+            BinaryOperator::Less => (x < y) as u8 as f32,
+            BinaryOperator::LessEqual => (x <= y) as u8 as f32,
+            BinaryOperator::Greater => (x > y) as u8 as f32,
+            BinaryOperator::GreaterEqual => (x >= y) as u8 as f32,
+            BinaryOperator::LogicalAnd => (x != 0.0 && y != 0.0) as u8 as f32,
+            BinaryOperator::LogicalOr => (x != 0.0 || y != 0.0) as u8 as f32,
+            other => panic!("Can't apply {:?}", other),
+        }
+    });
+    let kind = match op {
+        BinaryOperator::Add
+        | BinaryOperator::Subtract
+        | BinaryOperator::Multiply
+        | BinaryOperator::Divide
+        | BinaryOperator::Modulo => kind,
+        _ => ScalarKind::Bool,
+    };
+    Value::Number(kind, result.collect())
+}
+
+fn math(fun: MathFunction, x: &[f32], rest: &[Vec<f32>]) -> Vec<f32> {
+    let arg = |n: usize, i: usize| component(&rest[n], i);
+    (0..x.len())
+        .map(|i| match fun {
+            MathFunction::Abs => x[i].abs(),
+            MathFunction::Min => x[i].min(arg(0, i)),
+            MathFunction::Max => x[i].max(arg(0, i)),
+            MathFunction::Clamp => x[i].max(arg(0, i)).min(arg(1, i)),
+            MathFunction::Sin => x[i].sin(),
+            MathFunction::Cos => x[i].cos(),
+            MathFunction::Tan => x[i].tan(),
+            MathFunction::Asin => x[i].asin(),
+            MathFunction::Acos => x[i].acos(),
+            MathFunction::Atan => x[i].atan(),
+            MathFunction::Atan2 => x[i].atan2(arg(0, i)),
+            MathFunction::Floor => x[i].floor(),
+            MathFunction::Fract => x[i] - x[i].floor(),
+            MathFunction::Sqrt => x[i].sqrt(),
+            other => panic!("Can't apply {:?}", other),
+        })
+        .collect()
+}
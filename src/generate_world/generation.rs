@@ -1,68 +1,724 @@
 use std::{
+    any::Any,
+    fmt,
     future::Future,
+    panic::{self, AssertUnwindSafe},
     pin::Pin,
-    sync::{mpsc::channel, Mutex},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        mpsc::{channel, Receiver, Sender, TryRecvError},
+        Arc, Mutex,
+    },
     task::{Context, Poll},
-    time::{SystemTime, UNIX_EPOCH},
 };
 
 use bevy::{
     prelude::*,
-    render::render_resource::{Extent3d, TextureDimension, TextureFormat},
+    tasks::{ComputeTaskPool, TaskPool},
 };
 use noise::{NoiseFn, OpenSimplex, Seedable};
 
-const SCALE: f32 = 0.8;
+use super::{
+    biomes::Biome,
+    cache::{CacheKey, WorldCache},
+    geo,
+    heightmap::{ElevationFormat, Heightmap},
+    planet::Planet,
+    rivers::{RiverSettings, Rivers},
+    stats::WorldStats,
+    tectonics::Boundary,
+    water::Water,
+    world_data::{ClimateSettings, Layer, WorldData},
+    RADIUS,
+};
+
+// How the elevation texture handed to the material covers the sphere
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapLayout {
+    // Rows of latitude and columns of longitude, crowding texels at the poles
+    Equirectangular,
+    // Six cube faces laid out side by side in one texture, spreading texels evenly
+    CubeSphere,
+}
+
+impl MapLayout {
+    // Elevation texture of a map in this layout
+    pub fn image(self, map: &Heightmap, format: ElevationFormat) -> Image {
+        match self {
+            MapLayout::Equirectangular => map.to_image(format),
+            MapLayout::CubeSphere => map.to_cube_image(format),
+        }
+    }
+}
+
+// Everything needed to reproduce a generated world, along with the `GeneratorChoice`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WorldGenSettings {
+    pub seed: u32,
+    pub layout: MapLayout,
+    pub format: ElevationFormat,
+    // Bytes the finished world may take up, lowering the resolution if it would take more
+    pub memory_budget: u64,
+    // Widest and tallest texture the GPU can hold, its own limit once the render device is known
+    pub max_texture_size: u32,
+    // Samples around the equator and from pole to pole
+    pub width: u32,
+    pub height: u32,
+    // Number of noise layers summed together
+    pub octaves: u32,
+    // Frequency multiplier between octaves
+    pub lacunarity: f32,
+    // Amplitude multiplier between octaves
+    pub persistence: f32,
+    // Maximum absolute elevation of the map
+    pub amplitude: f32,
+    // Frequency of the first octave, relative to the unit sphere
+    pub frequency: f32,
+}
+
+impl Default for WorldGenSettings {
+    fn default() -> Self {
+        WorldGenSettings {
+            seed: 0,
+            layout: MapLayout::Equirectangular,
+            format: ElevationFormat::Float16,
+            memory_budget: 4 << 30,
+            max_texture_size: 16384,
+            width: 12000,
+            height: 6000,
+            octaves: 8,
+            lacunarity: 2.0,
+            persistence: 0.5,
+            amplitude: 0.8,
+            frequency: 1.5,
+        }
+    }
+}
+
+impl WorldGenSettings {
+    // Bytes a world generated with these settings may take up, counting every layer it can keep:
+    // - the layers of the world data, and the elevation the planet decodes from the texture
+    // - rivers, water and biomes derived from the map, and plates if the generator has them
+    // - the elevation texture both in main memory and on the GPU, in either layout, the cube
+    //   faces holding no more texels than the map
+    // - the latest preview, at a quarter of the resolution
+    // - the order hypsometry sorts the map into while it remaps it
+    pub fn memory(&self) -> u64 {
+        use std::mem::size_of;
+
+        let layers = Layer::ALL.len() * size_of::<f32>();
+        let planet = size_of::<f32>();
+        let rivers = size_of::<f32>() + size_of::<u32>();
+        let derived = rivers + size_of::<Water>() + size_of::<Biome>();
+        let plates = size_of::<u16>() + size_of::<Boundary>();
+        let texture = 2 * self.format.size();
+        let hypsometry = size_of::<u32>();
+        let texel = layers + planet + derived + plates + texture + hypsometry;
+
+        let texels = self.width as u64 * self.height as u64;
+        let preview = texels / 16 * (size_of::<f32>() + texture) as u64;
+        texels * texel as u64 + preview
+    }
+
+    // Settings a world can be raised from, with a map small enough to index
+    pub fn validate(&self) -> Result<(), GenerationError> {
+        let invalid = |err: &str| Err(GenerationError::InvalidSettings(err.into()));
+        if self.width == 0 || self.height == 0 {
+            return invalid("the map has no texels");
+        }
+        if self.width as u64 * self.height as u64 > u32::MAX as u64 {
+            return invalid("the map has too many texels");
+        }
+        // Overlays are always equirectangular, elevation is laid out as the settings ask
+        let (width, height) = match self.layout {
+            MapLayout::Equirectangular => (self.width, self.height),
+            MapLayout::CubeSphere => geo::cube_atlas_size(geo::cube_size(self.width, self.height)),
+        };
+        let largest = self.width.max(self.height).max(width).max(height);
+        if largest > self.max_texture_size {
+            return Err(GenerationError::InvalidSettings(format!(
+                "the map needs textures {} texels across, the GPU holds at most {}",
+                largest, self.max_texture_size
+            )));
+        }
+        let factors = [
+            self.lacunarity,
+            self.persistence,
+            self.amplitude,
+            self.frequency,
+        ];
+        if factors.iter().any(|f| !f.is_finite()) {
+            return invalid("noise factors must be finite");
+        }
+        Ok(())
+    }
+
+    // The same settings at the highest resolution fitting the memory budget,
+    // keeping the proportions of the map
+    pub fn within_budget(self) -> Self {
+        let memory = self.memory();
+        if memory <= self.memory_budget {
+            return self;
+        }
+
+        let scale = (self.memory_budget as f64 / memory as f64).sqrt();
+        WorldGenSettings {
+            width: ((self.width as f64 * scale) as u32).max(1),
+            height: ((self.height as f64 * scale) as u32).max(1),
+            ..self
+        }
+    }
+
+    // The same settings for a map of another size, as generators are resized for previews
+    pub fn resized(self, width: u32, height: u32) -> Self {
+        WorldGenSettings {
+            width,
+            height,
+            ..self
+        }
+    }
+}
+
+// Progress of a generation, shared between the generating thread and the game
+#[derive(Debug)]
+pub struct Progress {
+    stage: Mutex<&'static str>,
+    rows_done: AtomicU32,
+    rows_total: AtomicU32,
+    cancelled: AtomicBool,
+}
+
+impl Default for Progress {
+    fn default() -> Self {
+        Progress {
+            stage: Mutex::new("waiting"),
+            rows_done: AtomicU32::new(0),
+            rows_total: AtomicU32::new(0),
+            cancelled: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Progress {
+    // Begin a new stage of generation, made of `rows` units of work
+    pub fn start(&self, stage: &'static str, rows: u32) {
+        *self.stage.lock().expect("Lock failed") = stage;
+        self.rows_done.store(0, Ordering::Relaxed);
+        self.rows_total.store(rows, Ordering::Relaxed);
+    }
+
+    pub fn advance(&self, rows: u32) {
+        self.rows_done.fetch_add(rows, Ordering::Relaxed);
+    }
+
+    pub fn stage(&self) -> &'static str {
+        *self.stage.lock().expect("Lock failed")
+    }
+
+    pub fn rows_done(&self) -> u32 {
+        self.rows_done.load(Ordering::Relaxed)
+    }
+
+    // Fraction of the current stage that is complete, in [0, 1]
+    pub fn fraction(&self) -> f32 {
+        let total = self.rows_total.load(Ordering::Relaxed);
+        if total == 0 {
+            return 0.0;
+        }
+
+        (self.rows_done() as f32 / total as f32).min(1.0)
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+// Why a generation ended without a world
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GenerationError {
+    // Stopped through the progress handle, e.g. by leaving the world
+    Cancelled,
+    // The generator panicked, with the message it panicked with
+    Panicked(String),
+    // Settings or parameters no world can be raised from
+    InvalidSettings(String),
+    // A file the world is raised from could not be read
+    Io(String),
+}
+
+impl fmt::Display for GenerationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GenerationError::Cancelled => write!(f, "Generation was cancelled"),
+            GenerationError::Panicked(message) => write!(f, "Generator crashed: {}", message),
+            GenerationError::InvalidSettings(err) => write!(f, "Invalid settings: {}", err),
+            GenerationError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for GenerationError {}
+
+// Bands of rows handed to each thread of the compute pool, several so that none sits idle
+// while another finishes a slow band
+const BANDS_PER_THREAD: usize = 4;
+
+// Number of bands to split a map into to keep every thread of the compute pool busy
+pub(super) fn bands() -> usize {
+    ComputeTaskPool::init(TaskPool::default).thread_num() * BANDS_PER_THREAD
+}
+
+// Fill a map row by row, split into `bands` bands of rows computed in parallel on the compute
+// pool and written in place. `fill` is given the index of a row and its texels.
+pub(super) fn fill_rows<T: Send>(
+    data: &mut [T],
+    width: u32,
+    bands: usize,
+    progress: &Progress,
+    fill: impl Fn(u32, &mut [T]) + Sync,
+) -> Result<(), GenerationError> {
+    let width = width as usize;
+    let height = data.len() / width;
+    let bands = bands.max(1);
+    let band_rows = ((height + bands - 1) / bands).max(1);
+    let fill = &fill;
+
+    let finished = ComputeTaskPool::init(TaskPool::default).scope(|s| {
+        for (band, chunk) in data.chunks_mut(band_rows * width).enumerate() {
+            s.spawn(async move {
+                for (i, row) in chunk.chunks_mut(width).enumerate() {
+                    if progress.is_cancelled() {
+                        return false;
+                    }
+
+                    fill((band * band_rows + i) as u32, row);
+                    progress.advance(1);
+                }
+                true
+            });
+        }
+    });
+
+    if finished.into_iter().all(|done| done) {
+        Ok(())
+    } else {
+        Err(GenerationError::Cancelled)
+    }
+}
 
-pub(super) trait WorldGenerator {
-    fn new(width: u32, height: u32) -> Self;
-    fn get_elevation_map(&self) -> Image;
+// Which of the steps following the generator suit the worlds it raises
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Passes {
+    // Hydraulic and thermal erosion
+    pub erosion: bool,
+    // Remapping to a share of land
+    pub hypsometry: bool,
+    // Oceans, lakes and rivers
+    pub water: bool,
+    // Temperature and moisture, and the biomes following from them
+    pub climate: bool,
 }
 
-pub(crate) struct GenerationTask<T> {
-    generator: T,
-    sender: std::sync::mpsc::Sender<Image>,
-    receiver: std::sync::mpsc::Receiver<Image>,
-    spawned: Mutex<bool>,
+impl Passes {
+    pub const ALL: Passes = Passes {
+        erosion: true,
+        hypsometry: true,
+        water: true,
+        climate: true,
+    };
+    // Worlds kept as raised, e.g. airless moons
+    pub const NONE: Passes = Passes {
+        erosion: false,
+        hypsometry: false,
+        water: false,
+        climate: false,
+    };
 }
 
-impl<T> GenerationTask<T> {
-    pub fn new(generator: T) -> Self {
+// Raises the world, made by name from the `GeneratorRegistry`
+pub trait WorldGenerator: Send + Sync {
+    fn get_heightmap(&self, progress: &Progress) -> Result<Heightmap, GenerationError>;
+
+    // The same world at another resolution, raised for previews
+    fn with_size(&self, width: u32, height: u32) -> Box<dyn WorldGenerator>;
+
+    // Whatever else changes the world raised besides the generator's name, parameters and
+    // settings, e.g. the file it is read from, to tell cached worlds apart.
+    // None if its worlds can't be cached.
+    fn fingerprint(&self) -> Option<String> {
+        Some(String::new())
+    }
+
+    // Elevation at a point on the unit sphere, for generators defined all over the sphere
+    // rather than texel by texel. Cube faces are then sampled at their own texels instead of
+    // being resampled from the map. None for generators that can't.
+    fn sample(&self, _point: Vec3) -> Option<f32> {
+        None
+    }
+
+    // Steps run on the world once raised, every one unless the generator raises worlds they
+    // don't suit
+    fn passes(&self) -> Passes {
+        Passes::ALL
+    }
+
+    // Every layer the generator knows about, generators simulating more than elevation
+    // (e.g. plates) fill in their own layers. The climate is left to `WorldData::compute_climate`.
+    fn get_world_data(&self, progress: &Progress) -> Result<WorldData, GenerationError> {
+        self.get_heightmap(progress).map(WorldData::new)
+    }
+}
+
+// Post-processing stage run on the output of a generator
+pub(crate) trait MapPass {
+    // Fails with `GenerationError::Cancelled` if generation was cancelled through the progress
+    // handle
+    fn apply(&self, map: Heightmap, progress: &Progress) -> Result<Heightmap, GenerationError>;
+}
+
+// Work a `GenerationTask` runs on a thread of its own
+pub(crate) trait Job: Send + 'static {
+    type Output: Send + 'static;
+
+    // Images showing how the output will look may be sent before it is done
+    fn run(
+        self,
+        progress: &Progress,
+        previews: &Sender<Image>,
+    ) -> Result<Self::Output, GenerationError>;
+}
+
+// Everything a finished generation hands back to the game
+pub(crate) struct GeneratedWorld {
+    pub data: WorldData,
+    // Elevation texture, laid out as `layout` and stored as `format`
+    pub elevation: Image,
+    pub layout: MapLayout,
+    pub format: ElevationFormat,
+    pub rivers: Option<Rivers>,
+    // Measured at the sea level the climate was worked out for
+    pub stats: WorldStats,
+    // Steps the generator asked for, to keep to while the world is shown
+    pub passes: Passes,
+    // For gameplay to sample, sharing the layers of `data`
+    pub planet: Planet,
+}
+
+// Elevation textures of increasing detail, sent by a task before the full map is done
+#[derive(Debug, Clone)]
+pub(crate) struct Previews(Arc<Mutex<Receiver<Image>>>);
+
+impl Previews {
+    // Most detailed preview that arrived since the last call, if any
+    pub fn latest(&self) -> Option<Image> {
+        self.0.lock().expect("Lock failed").try_iter().last()
+    }
+}
+
+// Raises a world with a generator, then runs passes over it and derives the other layers
+pub(crate) struct WorldJob {
+    generator: Arc<dyn WorldGenerator>,
+    // Generators of the same world at lower resolutions, run first from coarsest to finest
+    previews: Vec<Arc<dyn WorldGenerator>>,
+    passes: Vec<Arc<dyn MapPass + Send + Sync>>,
+    rivers: Option<RiverSettings>,
+    climate: ClimateSettings,
+    layout: MapLayout,
+    format: ElevationFormat,
+    // Where finished worlds are kept, and what they were raised from
+    cache: Option<(WorldCache, String)>,
+}
+
+impl WorldJob {
+    // The cache the world is kept in and its key there, if it can be cached
+    fn cache_key(&self) -> Option<(&WorldCache, CacheKey)> {
+        let (cache, description) = self.cache.as_ref()?;
+        let fingerprint = self.generator.fingerprint()?;
+        Some((cache, CacheKey::new(&(description.clone() + &fingerprint))))
+    }
+
+    // The world stored in the cache, if it was raised before
+    fn cached(&self, progress: &Progress) -> Option<WorldData> {
+        let (cache, key) = self.cache_key()?;
+
+        progress.start("cache", 1);
+        let data = cache.load(&key).unwrap_or_else(|err| {
+            warn!("Could not read cached world: {}", err);
+            None
+        })?;
+        progress.advance(1);
+        Some(data)
+    }
+
+    // Elevation texture of a map raised by `generator`. Cube faces are sampled straight from
+    // the generator when it can, unless passes have since reshaped the map.
+    fn image(
+        &self,
+        generator: &dyn WorldGenerator,
+        map: &Heightmap,
+        reshaped: bool,
+        progress: &Progress,
+    ) -> Result<Image, GenerationError> {
+        let direct = self.layout == MapLayout::CubeSphere && !reshaped;
+        if !direct || generator.sample(Vec3::X).is_none() {
+            return Ok(self.layout.image(map, self.format));
+        }
+
+        let size = geo::cube_size(map.width, map.height);
+        let (width, height) = geo::cube_atlas_size(size);
+        let mut faces = vec![0.0; (width * height) as usize];
+        progress.start("cube faces", height);
+        fill_rows(&mut faces, width, bands(), progress, |y, texels| {
+            for (x, texel) in texels.iter_mut().enumerate() {
+                let (face, u, v) = geo::cube_texel(x as u32, y, size);
+                *texel = generator
+                    .sample(geo::cube_direction(face, u, v))
+                    .unwrap_or_default();
+            }
+        })?;
+
+        // Faces are laid out as `Heightmap::to_cube_image` lays them out
+        Ok(Heightmap::new(width, height, faces).to_image(self.format))
+    }
+
+    fn raise(
+        &self,
+        progress: &Progress,
+        previews: &Sender<Image>,
+    ) -> Result<WorldData, GenerationError> {
+        for preview in &self.previews {
+            let map = preview.get_heightmap(progress)?;
+            let image = self.image(preview.as_ref(), &map, false, progress)?;
+            // Nobody is watching if the task was dropped, which cancels it anyway
+            let _ = previews.send(image);
+        }
+
+        let mut data = self.generator.get_world_data(progress)?;
+        if !self.passes.is_empty() {
+            // Nothing else holds the map yet, so passes take it over without a copy
+            let mut map = Arc::try_unwrap(data.elevation).unwrap_or_else(|map| (*map).clone());
+            for pass in &self.passes {
+                map = pass.apply(map, progress)?;
+            }
+            data.elevation = Arc::new(map);
+        }
+        if self.generator.passes().climate {
+            data.compute_climate(&self.climate, progress)
+                .ok_or(GenerationError::Cancelled)?;
+        }
+
+        if let Some((cache, key)) = self.cache_key() {
+            if let Err(err) = cache.store(&key, &data) {
+                warn!("Could not cache world: {}", err);
+            }
+        }
+        Ok(data)
+    }
+}
+
+impl Job for WorldJob {
+    type Output = GeneratedWorld;
+
+    fn run(
+        self,
+        progress: &Progress,
+        previews: &Sender<Image>,
+    ) -> Result<GeneratedWorld, GenerationError> {
+        let data = match self.cached(progress) {
+            Some(data) => data,
+            None => self.raise(progress, previews)?,
+        };
+
+        // Quick to extract again, so cached worlds leave rivers out
+        let passes = self.generator.passes();
+        let rivers = match self.rivers.filter(|_| passes.water) {
+            Some(settings) => Some(
+                Rivers::extract(&data.elevation, &settings, progress)
+                    .ok_or(GenerationError::Cancelled)?,
+            ),
+            None => None,
+        };
+
+        let elevation = self.image(
+            self.generator.as_ref(),
+            &data.elevation,
+            !self.passes.is_empty(),
+            progress,
+        )?;
+        let stats = WorldStats::compute(&data, self.climate.sea_level);
+        // Gameplay samples the same texels the material displaces the sphere with
+        let planet = Planet::new(&data, &elevation, self.layout, self.format, RADIUS);
+        Ok(GeneratedWorld {
+            elevation,
+            layout: self.layout,
+            format: self.format,
+            data,
+            rivers,
+            stats,
+            passes,
+            planet,
+        })
+    }
+}
+
+// What a job ended with
+type Outcome<J> = Result<<J as Job>::Output, GenerationError>;
+
+// Runs a job on a thread of its own once first polled, resolving to its outcome
+pub(crate) struct GenerationTask<J: Job = WorldJob> {
+    // Taken by the thread when it starts
+    job: Mutex<Option<(J, Sender<Outcome<J>>)>>,
+    receiver: Receiver<Outcome<J>>,
+    preview_sender: Sender<Image>,
+    preview_receiver: Previews,
+    progress: Arc<Progress>,
+}
+
+impl<J: Job> GenerationTask<J> {
+    pub fn from_job(job: J) -> Self {
         let (tx, rx) = channel();
+        let (preview_tx, preview_rx) = channel();
         Self {
-            generator,
-            sender: tx,
+            job: Mutex::new(Some((job, tx))),
             receiver: rx,
-            spawned: Mutex::new(false),
+            preview_sender: preview_tx,
+            preview_receiver: Previews(Arc::new(Mutex::new(preview_rx))),
+            progress: Arc::new(Progress::default()),
         }
     }
+
+    // The job, to set it up until the task is first polled
+    fn job(&mut self) -> &mut J {
+        let (job, _) = self
+            .job
+            .get_mut()
+            .expect("Lock failed")
+            .as_mut()
+            .expect("Task already started");
+        job
+    }
+
+    pub fn progress(&self) -> Arc<Progress> {
+        self.progress.clone()
+    }
+
+    pub fn previews(&self) -> Previews {
+        self.preview_receiver.clone()
+    }
 }
 
-impl<T: WorldGenerator + Send + Clone + 'static> Future for GenerationTask<T> {
-    type Output = Option<Image>;
+impl GenerationTask {
+    pub fn new(generator: Box<dyn WorldGenerator>) -> Self {
+        Self::from_job(WorldJob {
+            generator: generator.into(),
+            previews: Vec::new(),
+            passes: Vec::new(),
+            rivers: None,
+            climate: ClimateSettings::default(),
+            layout: MapLayout::Equirectangular,
+            format: ElevationFormat::Float32,
+            cache: None,
+        })
+    }
+
+    // Raise a quick preview of the elevation with another generator before the full map,
+    // after any previously added previews. Passes are left out of previews.
+    pub fn with_preview(mut self, generator: Box<dyn WorldGenerator>) -> Self {
+        self.job().previews.push(generator.into());
+        self
+    }
+
+    // Run a pass over the generated map, after any previously added passes
+    pub fn with_pass(mut self, pass: impl MapPass + Send + Sync + 'static) -> Self {
+        self.job().passes.push(Arc::new(pass));
+        self
+    }
+
+    // Extract rivers from the map once all passes are done
+    pub fn with_rivers(mut self, settings: RiverSettings) -> Self {
+        self.job().rivers = Some(settings);
+        self
+    }
+
+    pub fn with_climate(mut self, settings: ClimateSettings) -> Self {
+        self.job().climate = settings;
+        self
+    }
+
+    pub fn with_layout(mut self, layout: MapLayout) -> Self {
+        self.job().layout = layout;
+        self
+    }
+
+    pub fn with_format(mut self, format: ElevationFormat) -> Self {
+        self.job().format = format;
+        self
+    }
+
+    // Read the world back from the cache instead of raising it if it was raised before,
+    // or store it once raised. `description` must name the generator and every setting used
+    pub fn with_cache(mut self, cache: WorldCache, description: impl Into<String>) -> Self {
+        self.job().cache = Some((cache, description.into()));
+        self
+    }
+}
+
+// Dropping the task (e.g. by despawning its entity) stops the generating thread
+impl<J: Job> Drop for GenerationTask<J> {
+    fn drop(&mut self) {
+        self.progress.cancel();
+    }
+}
+
+// Message a thread panicked with
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => payload
+            .downcast_ref::<&str>()
+            .map_or("unknown panic", |message| message)
+            .to_string(),
+    }
+}
+
+impl<J: Job> Future for GenerationTask<J> {
+    type Output = Outcome<J>;
 
     fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
         let res = self.receiver.try_recv();
-        if let Ok(img) = res {
-            return Poll::Ready(Some(img));
+        if let Ok(result) = res {
+            return Poll::Ready(result);
         }
 
-        if let Err(std::sync::mpsc::TryRecvError::Disconnected) = res {
-            return Poll::Ready(None);
+        // The thread always sends a result, unless it died without unwinding
+        if let Err(TryRecvError::Disconnected) = res {
+            return Poll::Ready(Err(GenerationError::Panicked(
+                "Generation stopped without a result".into(),
+            )));
         }
 
-        let mut spawned = self.spawned.lock().expect("Lock failed");
+        let job = self.job.lock().expect("Lock failed").take();
 
-        if !*spawned {
-            let tx = self.sender.clone();
+        if let Some((job, tx)) = job {
             let waker = ctx.waker().clone();
-            let gen = self.generator.clone();
+            let preview_tx = self.preview_sender.clone();
+            let progress = self.progress.clone();
             std::thread::spawn(move || {
-                tx.send(gen.get_elevation_map())
-                    .expect("Failed to send elevation map");
+                let result =
+                    panic::catch_unwind(AssertUnwindSafe(|| job.run(&progress, &preview_tx)))
+                        .unwrap_or_else(|payload| {
+                            Err(GenerationError::Panicked(panic_message(payload)))
+                        });
+
+                // The receiver is gone if the task was dropped, so there is nobody to tell
+                let _ = tx.send(result);
                 waker.wake();
             });
-            *spawned = true;
         }
 
         Poll::Pending
@@ -71,135 +727,596 @@ impl<T: WorldGenerator + Send + Clone + 'static> Future for GenerationTask<T> {
 
 #[derive(Clone, Debug, Default)]
 pub(super) struct SimplexGenerator {
-    width: u32,
-    height: u32,
+    settings: WorldGenSettings,
     gen: OpenSimplex,
 }
 
 impl PartialEq<SimplexGenerator> for SimplexGenerator {
     fn eq(&self, other: &SimplexGenerator) -> bool {
-        self.width == other.width
-            && self.height == other.height
-            && self.gen.seed() == other.gen.seed()
+        self.settings == other.settings && self.gen.seed() == other.gen.seed()
     }
 }
 
-impl Eq for SimplexGenerator {}
-
 impl SimplexGenerator {
-    fn get_map(&self) -> Vec<f32> {
-        let mut image = Vec::with_capacity((self.width * self.height) as usize);
-
-        for i in 0..self.width {
-            let i = i as f32 / self.width as f32;
-            for j in 0..self.height {
-                let j = j as f32 / self.height as f32 * 4.0;
-
-                let theta = (i - 0.5) * std::f32::consts::PI;
-                let phi = j * std::f32::consts::TAU;
-
-                let x = super::RADIUS * theta.cos() * phi.sin();
-                let y = super::RADIUS * theta.sin() * phi.sin();
-                let z = super::RADIUS * phi.cos();
-
-                let mut acc = 0.0;
-                let mut min_acc = 0.0;
-
-                for harmonic in 0..8 {
-                    let weight = 1. - harmonic as f32 / 8.;
-                    let proj = (
-                        x / (harmonic + 1) as f32,
-                        y / (harmonic + 1) as f32,
-                        z / (harmonic + 1) as f32,
-                    );
-                    acc +=
-                        self.gen.get([proj.0 as f64, proj.1 as f64, proj.2 as f64]) as f32 * weight;
-                    min_acc -= weight;
-                }
+    pub fn new(settings: &WorldGenSettings) -> Self {
+        SimplexGenerator {
+            settings: *settings,
+            gen: OpenSimplex::new().set_seed(settings.seed),
+        }
+    }
 
-                image.push(acc / min_acc * SCALE);
+    fn get_map(&self, progress: &Progress) -> Result<Vec<f32>, GenerationError> {
+        self.get_bands(bands(), progress)
+    }
+
+    // The map computed in `bands` bands of rows, the same whatever their number
+    fn get_bands(&self, bands: usize, progress: &Progress) -> Result<Vec<f32>, GenerationError> {
+        let WorldGenSettings { width, height, .. } = self.settings;
+        let mut image = vec![0.0; (width * height) as usize];
+
+        progress.start("elevation", height);
+        fill_rows(&mut image, width, bands, progress, |y, row| {
+            let latitude = geo::latitude(y as f32, height);
+            for (x, texel) in row.iter_mut().enumerate() {
+                let point = geo::direction(latitude, geo::longitude(x as f32, width));
+                *texel = self.octaves(point.x, point.y, point.z);
             }
-        }
+        })?;
 
-        image
+        Ok(image)
     }
 
-    fn get_bytes(inp: &[f32]) -> Vec<u8> {
-        let mut out = Vec::with_capacity(inp.len() * 4);
+    // Sum of octaves of noise at a point on the unit sphere, normalized to the amplitude
+    pub(super) fn octaves(&self, x: f32, y: f32, z: f32) -> f32 {
+        let mut acc = 0.0;
+        let mut norm = 0.0;
+        let mut frequency = self.settings.frequency;
+        let mut weight = 1.0;
+
+        for _ in 0..self.settings.octaves {
+            let proj = [
+                (x * frequency) as f64,
+                (y * frequency) as f64,
+                (z * frequency) as f64,
+            ];
+            acc += self.gen.get(proj) as f32 * weight;
+            norm += weight;
 
-        for f in inp {
-            out.extend_from_slice(&f.to_le_bytes());
+            frequency *= self.settings.lacunarity;
+            weight *= self.settings.persistence;
         }
 
-        out
+        if norm == 0.0 {
+            return 0.0;
+        }
+
+        acc / norm * self.settings.amplitude
     }
 }
 
 impl WorldGenerator for SimplexGenerator {
-    fn new(width: u32, height: u32) -> Self {
-        SimplexGenerator {
-            width,
-            height,
-            gen: OpenSimplex::new().set_seed(
-                SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .expect("Time moved backwards")
-                    .as_secs() as u32,
-            ),
-        }
+    fn get_heightmap(&self, progress: &Progress) -> Result<Heightmap, GenerationError> {
+        let map = self.get_map(progress)?;
+        Ok(Heightmap::new(
+            self.settings.width,
+            self.settings.height,
+            map,
+        ))
     }
 
-    fn get_elevation_map(&self) -> Image {
-        let img = self.get_map();
-        let img = Self::get_bytes(&img[..]);
-        Image::new(
-            Extent3d {
-                width: self.width,
-                height: self.height,
-                ..default()
-            },
-            TextureDimension::D2,
-            img,
-            TextureFormat::R32Float,
-        )
+    fn with_size(&self, width: u32, height: u32) -> Box<dyn WorldGenerator> {
+        Box::new(SimplexGenerator::new(&self.settings.resized(width, height)))
+    }
+
+    fn sample(&self, point: Vec3) -> Option<f32> {
+        Some(self.octaves(point.x, point.y, point.z))
     }
 }
 
 #[cfg(test)]
 mod test {
+    use super::super::fixtures::settings;
+    use super::{Progress, WorldGenSettings};
+
+    #[test]
+    fn settings_fit_memory_budget() {
+        use super::ElevationFormat;
+
+        let full = WorldGenSettings::default();
+        assert_eq!(full.within_budget(), full);
+
+        // Three f32 layers in the world, the elevation the planet decodes, rivers, water, biomes
+        // and plates, two copies of the texture, the hypsometry sort order, and a preview of a
+        // sixteenth of the texels as a map and two copies of its texture
+        let small = settings(0, 400, 200);
+        let layers = 12 + 4 + 10 + 3 + 4;
+        assert_eq!(
+            small.memory(),
+            400 * 200 * (layers + 2 * 2) + 400 * 200 / 16 * (4 + 2 * 2)
+        );
+        let half = WorldGenSettings {
+            format: ElevationFormat::Float32,
+            ..small
+        };
+        assert_eq!(
+            half.memory(),
+            400 * 200 * (layers + 2 * 4) + 400 * 200 / 16 * (4 + 2 * 4)
+        );
+
+        let budget = WorldGenSettings {
+            memory_budget: small.memory() / 4,
+            ..small
+        };
+        let fitted = budget.within_budget();
+        assert!(fitted.memory() <= fitted.memory_budget);
+        assert!(fitted.width >= 199 && fitted.width <= 200);
+        assert_eq!(fitted.width, fitted.height * 2);
+
+        let tiny = WorldGenSettings {
+            memory_budget: 0,
+            ..small
+        };
+        assert_eq!(tiny.within_budget().width, 1);
+    }
+
+    #[test]
+    fn settings_are_validated() {
+        use super::{GenerationError, MapLayout};
+
+        assert_eq!(settings(0, 400, 200).validate(), Ok(()));
+        // The cube faces of a square map are laid out wider than the map
+        let limited = WorldGenSettings {
+            max_texture_size: 1100,
+            ..settings(0, 1000, 1000)
+        };
+        assert_eq!(limited.validate(), Ok(()));
+        for invalid in [
+            settings(0, 0, 200),
+            settings(0, 400, 0),
+            settings(0, 100_000, 100_000),
+            WorldGenSettings {
+                frequency: f32::NAN,
+                ..settings(0, 400, 200)
+            },
+            WorldGenSettings {
+                max_texture_size: 300,
+                ..settings(0, 400, 200)
+            },
+            WorldGenSettings {
+                layout: MapLayout::CubeSphere,
+                ..limited
+            },
+        ] {
+            assert!(matches!(
+                invalid.validate(),
+                Err(GenerationError::InvalidSettings(_))
+            ));
+        }
+    }
+
     #[test]
     fn is_simplexgenerator_sized() {
-        use super::{SimplexGenerator, WorldGenerator};
+        use super::SimplexGenerator;
 
-        let gen = SimplexGenerator::new(500, 500);
-        let map = gen.get_map();
+        let gen = SimplexGenerator::new(&settings(0, 500, 500));
+        let map = gen.get_map(&Progress::default()).unwrap();
         assert_eq!(map.len(), 500 * 500);
 
-        let gen = SimplexGenerator::new(200, 150);
-        let map = gen.get_map();
+        let gen = SimplexGenerator::new(&settings(0, 200, 150));
+        let map = gen.get_map(&Progress::default()).unwrap();
         assert_eq!(map.len(), 200 * 150);
     }
 
     #[test]
     fn is_simplexgenerator_clamped() {
-        use super::{SimplexGenerator, WorldGenerator, SCALE};
+        use super::SimplexGenerator;
 
-        let gen = SimplexGenerator::new(200, 150);
-        let map = gen.get_map();
+        let settings = settings(0, 200, 150);
+        let gen = SimplexGenerator::new(&settings);
+        let map = gen.get_map(&Progress::default()).unwrap();
 
         for f in map {
-            assert!(f <= SCALE);
-            assert!(f >= -SCALE);
+            assert!(f <= settings.amplitude);
+            assert!(f >= -settings.amplitude);
         }
     }
 
     #[test]
     fn is_simplexgenerator_worldgenerator() {
+        use super::{ElevationFormat, Layer, SimplexGenerator, WorldGenerator};
+        use bevy::prelude::*;
+
+        let gen = SimplexGenerator::new(&settings(0, 500, 500));
+        let data = gen.get_world_data(&Progress::default()).unwrap();
+        assert_eq!(
+            data.layer(Layer::Elevation)
+                .to_image(ElevationFormat::Float32)
+                .size(),
+            Vec2::new(500., 500.)
+        );
+        assert!(data.plates.is_none());
+    }
+
+    #[test]
+    fn is_simplexgenerator_reproducible() {
+        use super::{SimplexGenerator, WorldGenerator};
+
+        let settings = WorldGenSettings {
+            seed: 1234,
+            ..settings(0, 120, 240)
+        };
+        let first = SimplexGenerator::new(&settings)
+            .get_world_data(&Progress::default())
+            .unwrap();
+        let second = SimplexGenerator::new(&settings)
+            .get_world_data(&Progress::default())
+            .unwrap();
+        assert_eq!(first.elevation, second.elevation);
+
+        let other = SimplexGenerator::new(&WorldGenSettings {
+            seed: 4321,
+            ..settings
+        })
+        .get_world_data(&Progress::default())
+        .unwrap();
+        assert_ne!(first.elevation, other.elevation);
+    }
+
+    #[test]
+    fn simplexgenerator_follows_settings() {
+        use super::SimplexGenerator;
+
+        let settings = WorldGenSettings {
+            octaves: 3,
+            amplitude: 0.25,
+            ..settings(0, 100, 200)
+        };
+        let map = SimplexGenerator::new(&settings)
+            .get_map(&Progress::default())
+            .unwrap();
+        assert!(map.iter().all(|f| f.abs() <= 0.25));
+
+        let flat = SimplexGenerator::new(&WorldGenSettings {
+            octaves: 0,
+            ..settings
+        })
+        .get_map(&Progress::default())
+        .unwrap();
+        assert!(flat.iter().all(|f| *f == 0.0));
+    }
+
+    #[test]
+    fn simplexgenerator_samples_the_sphere() {
         use super::{SimplexGenerator, WorldGenerator};
+
+        let gen = SimplexGenerator::new(&settings(0, 64, 32));
+        let map = gen.get_heightmap(&Progress::default()).unwrap();
+
+        for (x, y) in [(0, 0), (17, 9), (63, 16), (40, 31)] {
+            let point = map.direction(x as f32, y as f32);
+            let expected = gen.octaves(point.x, point.y, point.z);
+            assert_eq!(map.get(x, y), expected);
+        }
+
+        // The map wraps around once, so the seam is as smooth as anywhere else
+        let step = |x: isize| {
+            (0..32)
+                .map(|y| (map.get(x, y) - map.get(x + 1, y)).abs())
+                .sum::<f32>()
+        };
+        assert!(step(63) < 2.0 * step(31));
+    }
+
+    #[test]
+    fn simplexgenerator_reports_progress() {
+        use super::SimplexGenerator;
+
+        let progress = Progress::default();
+        assert_eq!(progress.fraction(), 0.0);
+
+        SimplexGenerator::new(&settings(0, 50, 100))
+            .get_map(&progress)
+            .unwrap();
+        assert_eq!(progress.stage(), "elevation");
+        assert_eq!(progress.rows_done(), 100);
+        assert_eq!(progress.fraction(), 1.0);
+    }
+
+    #[test]
+    fn simplexgenerator_bands_match_serial() {
+        use super::SimplexGenerator;
+
+        // Bands that divide the rows evenly, that don't, and more bands than rows
+        let gen = SimplexGenerator::new(&settings(0, 60, 30));
+        let serial = gen.get_bands(1, &Progress::default()).unwrap();
+        for bands in [2, 7, 30, 64, super::bands()] {
+            let progress = Progress::default();
+            assert_eq!(gen.get_bands(bands, &progress).unwrap(), serial);
+            assert_eq!(progress.rows_done(), 30);
+        }
+    }
+
+    // Run with `cargo test --release -- --ignored` to check the speedup
+    #[test]
+    #[ignore]
+    fn bench_simplexgenerator_bands() {
+        use super::SimplexGenerator;
+        use std::time::Instant;
+
+        let gen = SimplexGenerator::new(&settings(0, 3000, 1500));
+        let time = |bands| {
+            let start = Instant::now();
+            gen.get_bands(bands, &Progress::default()).unwrap();
+            start.elapsed()
+        };
+
+        let serial = time(1);
+        let parallel = time(super::bands());
+        if super::bands() > super::BANDS_PER_THREAD {
+            assert!(
+                parallel < serial,
+                "1 band: {:?}, {} bands: {:?}",
+                serial,
+                super::bands(),
+                parallel
+            );
+        }
+    }
+
+    #[test]
+    fn simplexgenerator_stops_when_cancelled() {
+        use super::{SimplexGenerator, WorldGenerator};
+
+        let progress = Progress::default();
+        progress.cancel();
+
+        let gen = SimplexGenerator::new(&settings(0, 50, 100));
+        assert_eq!(
+            gen.get_world_data(&progress),
+            Err(super::GenerationError::Cancelled)
+        );
+        assert_eq!(progress.rows_done(), 0);
+    }
+
+    #[test]
+    fn task_lays_out_elevation() {
+        use super::{ElevationFormat, GenerationTask, MapLayout, SimplexGenerator};
+        use crate::generate_world::geo;
+        use bevy::prelude::*;
+
+        let gen = SimplexGenerator::new(&settings(0, 64, 32));
+        let world = futures_lite::future::block_on(
+            GenerationTask::new(Box::new(gen))
+                .with_layout(MapLayout::CubeSphere)
+                .with_format(ElevationFormat::Float16),
+        )
+        .unwrap();
+
+        let size = geo::cube_size(64, 32) as f32;
+        assert_eq!(world.layout, MapLayout::CubeSphere);
+        assert_eq!(world.format, ElevationFormat::Float16);
+        assert_eq!(world.elevation.size(), Vec2::new(size * 3., size * 2.));
+        assert_eq!(world.elevation.data.len(), (size * size * 6.) as usize * 2);
+        // The world data stays equirectangular for sampling on the CPU
+        assert_eq!(world.data.elevation.data.len(), 64 * 32);
+    }
+
+    #[test]
+    fn cube_faces_are_sampled_from_generator() {
+        use super::{ElevationFormat, GenerationTask, MapLayout, SimplexGenerator, WorldGenerator};
+        use crate::generate_world::geo;
+
+        let gen = SimplexGenerator::new(&settings(0, 64, 32));
+        let world = futures_lite::future::block_on(
+            GenerationTask::new(Box::new(gen.clone()))
+                .with_layout(MapLayout::CubeSphere)
+                .with_format(ElevationFormat::Float32),
+        )
+        .unwrap();
+
+        // Every texel holds the noise at its centre, not a blend of the map's texels
+        let size = geo::cube_size(64, 32);
+        let texels: Vec<f32> = world
+            .elevation
+            .data
+            .chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect();
+        let (width, _) = geo::cube_atlas_size(size);
+        for (i, texel) in texels.iter().enumerate() {
+            let (x, y) = (i as u32 % width, i as u32 / width);
+            let (face, column, row) = ((y / size * 3 + x / size) as usize, x % size, y % size);
+            let point = geo::cube_direction(
+                face,
+                (column as f32 + 0.5) / size as f32,
+                (row as f32 + 0.5) / size as f32,
+            );
+            assert_eq!(*texel, gen.sample(point).unwrap());
+        }
+    }
+
+    #[test]
+    fn task_sends_previews_before_world() {
+        use super::{GenerationTask, SimplexGenerator, WorldGenerator};
+        use bevy::prelude::*;
+
+        let gen = SimplexGenerator::new(&settings(0, 64, 32));
+        let task = GenerationTask::new(Box::new(gen.clone()))
+            .with_preview(gen.with_size(4, 2))
+            .with_preview(gen.with_size(16, 8));
+        let previews = task.previews();
+        assert!(previews.latest().is_none());
+
+        let world = futures_lite::future::block_on(task).unwrap();
+        assert_eq!(world.elevation.size(), Vec2::new(64., 32.));
+
+        // Previews arrive coarsest first, the latest is the most detailed
+        let sizes: Vec<_> = previews
+            .0
+            .lock()
+            .unwrap()
+            .try_iter()
+            .map(|img| img.size())
+            .collect();
+        assert_eq!(sizes, vec![Vec2::new(4., 2.), Vec2::new(16., 8.)]);
+    }
+
+    #[test]
+    fn previews_keep_the_latest() {
+        use super::{GenerationTask, MapLayout, SimplexGenerator, WorldGenerator};
+        use bevy::prelude::*;
+
+        let gen = SimplexGenerator::new(&settings(0, 64, 32));
+        let task = GenerationTask::new(Box::new(gen.clone()))
+            .with_layout(MapLayout::CubeSphere)
+            .with_preview(gen.with_size(4, 2))
+            .with_preview(gen.with_size(16, 8));
+        let previews = task.previews();
+        futures_lite::future::block_on(task).unwrap();
+
+        // Previews share the layout of the final texture
+        let size = crate::generate_world::geo::cube_size(16, 8) as f32;
+        assert_eq!(
+            previews.latest().unwrap().size(),
+            Vec2::new(size * 3., size * 2.)
+        );
+        assert!(previews.latest().is_none());
+    }
+
+    #[test]
+    fn dropping_task_cancels_generation() {
+        use super::{GenerationTask, SimplexGenerator};
+
+        let task = GenerationTask::new(Box::new(SimplexGenerator::new(&settings(0, 50, 100))));
+        let progress = task.progress();
+        assert!(!progress.is_cancelled());
+
+        drop(task);
+        assert!(progress.is_cancelled());
+    }
+
+    #[test]
+    fn task_reports_failures() {
+        use super::{GenerationError, GenerationTask, Job, SimplexGenerator};
         use bevy::prelude::*;
+        use std::sync::mpsc::Sender;
+
+        // Jobs hand back whatever they produce
+        struct Count(u32);
+
+        impl Job for Count {
+            type Output = u32;
+
+            fn run(self, _: &Progress, _: &Sender<Image>) -> Result<u32, GenerationError> {
+                match self.0 {
+                    0 => Err(GenerationError::InvalidSettings("nothing to count".into())),
+                    1 => panic!("counted to one"),
+                    n => Ok(n),
+                }
+            }
+        }
+
+        let run = |n| futures_lite::future::block_on(GenerationTask::from_job(Count(n)));
+        assert_eq!(run(3), Ok(3));
+        assert_eq!(
+            run(0),
+            Err(GenerationError::InvalidSettings("nothing to count".into()))
+        );
+        assert_eq!(
+            run(1),
+            Err(GenerationError::Panicked("counted to one".into()))
+        );
+
+        let task = GenerationTask::new(Box::new(SimplexGenerator::new(&settings(0, 50, 100))));
+        task.progress().cancel();
+        assert_eq!(
+            futures_lite::future::block_on(task).err(),
+            Some(GenerationError::Cancelled)
+        );
+    }
+
+    #[test]
+    fn craters_skip_water_and_climate() {
+        use super::{GenerationTask, Passes, WorldGenerator};
+        use crate::generate_world::{craters::CraterGenerator, rivers::RiverSettings};
+
+        let gen = CraterGenerator::new(&settings(0, 64, 32), 50, 2.0);
+        assert_eq!(gen.passes(), Passes::NONE);
+        let task = GenerationTask::new(Box::new(gen)).with_rivers(RiverSettings::default());
+        let world = futures_lite::future::block_on(task).unwrap();
+
+        assert_eq!(world.passes, Passes::NONE);
+        assert_eq!(world.rivers, None);
+        assert!(world.data.temperature.data.iter().all(|t| *t == 0.0));
+        assert!(world.data.moisture.data.iter().all(|m| *m == 0.0));
+    }
+
+    #[test]
+    fn task_runs_passes_and_extracts_rivers() {
+        use super::{GenerationError, GenerationTask, MapPass, SimplexGenerator};
+        use crate::generate_world::{heightmap::Heightmap, rivers::RiverSettings};
+
+        struct Flatten;
+
+        impl MapPass for Flatten {
+            fn apply(&self, map: Heightmap, _: &Progress) -> Result<Heightmap, GenerationError> {
+                let len = map.data.len();
+                Ok(Heightmap::new(map.width, map.height, vec![0.5; len]))
+            }
+        }
+
+        let gen = SimplexGenerator::new(&settings(0, 64, 32));
+        let task = GenerationTask::new(Box::new(gen))
+            .with_pass(Flatten)
+            .with_rivers(RiverSettings::default());
+        let world = futures_lite::future::block_on(task).unwrap();
+
+        assert!(world
+            .elevation
+            .data
+            .chunks(4)
+            .all(|f| f == 0.5f32.to_le_bytes()));
+        let rivers = world.rivers.unwrap();
+        assert_eq!(rivers.accumulation.len(), 64 * 32);
+        // The climate is derived from the final elevation
+        assert_eq!(world.data.elevation.data, vec![0.5; 64 * 32]);
+        assert!(world.data.temperature.data.iter().all(|t| *t < 30.0));
+        // So are the statistics, all land above the default sea level
+        assert_eq!(world.stats.land_fraction, 1.0);
+    }
+
+    #[test]
+    fn task_reads_back_cached_world() {
+        use super::{GenerationTask, SimplexGenerator, WorldCache, WorldGenerator};
+        use crate::generate_world::import::{test::Scratch, ImportGenerator};
+
+        let dir = Scratch::new("task-cache");
+        let cache = WorldCache {
+            directory: dir.0.clone(),
+            max_bytes: u64::MAX,
+        };
+        let task = |gen: Box<dyn WorldGenerator>| {
+            let task = GenerationTask::new(gen).with_cache(cache.clone(), "same world");
+            let progress = task.progress();
+            (futures_lite::future::block_on(task).unwrap(), progress)
+        };
+
+        let first = SimplexGenerator::new(&settings(0, 64, 32));
+        let (raised, progress) = task(Box::new(first));
+        assert_eq!(progress.stage(), "climate");
+
+        // Another seed under the same description reads the first world back
+        let second = SimplexGenerator::new(&WorldGenSettings {
+            seed: 99,
+            ..settings(0, 64, 32)
+        });
+        let (cached, progress) = task(Box::new(second));
+        assert_eq!(progress.stage(), "cache");
+        assert_eq!(cached.data, raised.data);
+        assert_eq!(cached.elevation.data, raised.elevation.data);
 
-        let gen = SimplexGenerator::new(500, 500);
-        let img = gen.get_elevation_map();
-        assert_eq!(img.size(), Vec2::new(500., 500.));
+        // Generators that can't tell their worlds apart are never cached
+        let import = ImportGenerator::new(&settings(0, 64, 32));
+        assert_eq!(import.fingerprint(), None);
     }
 }
@@ -0,0 +1,359 @@
+// Conversions between texels, latitude and longitude, and points on the unit sphere.
+// Mirrored by assets/shaders/geo.wgsl, which must be kept in step with this file.
+//
+// Latitude runs from -PI/2 at the south pole (row 0, z = -1) to PI/2 at the north pole,
+// longitude from -PI at the left edge of the map to PI at the right, with 0 on the x axis.
+
+use std::f32::consts::{FRAC_PI_4, PI, TAU};
+
+use bevy::prelude::*;
+
+// Latitude in radians at a (fractional) row
+pub(crate) fn latitude(y: f32, height: u32) -> f32 {
+    ((y + 0.5) / height as f32 - 0.5) * PI
+}
+
+// Row at a latitude in radians, inverse of `latitude`
+pub(crate) fn row(latitude: f32, height: u32) -> f32 {
+    (latitude / PI + 0.5) * height as f32 - 0.5
+}
+
+// Longitude in radians at a (fractional) column
+pub(crate) fn longitude(x: f32, width: u32) -> f32 {
+    ((x + 0.5) / width as f32 - 0.5) * TAU
+}
+
+// Point on the unit sphere at a latitude and longitude
+pub(crate) fn direction(latitude: f32, longitude: f32) -> Vec3 {
+    Vec3::new(
+        latitude.cos() * longitude.cos(),
+        latitude.cos() * longitude.sin(),
+        latitude.sin(),
+    )
+}
+
+// Latitude and longitude of a point on the unit sphere, inverse of `direction`
+pub(crate) fn lat_lon(direction: Vec3) -> (f32, f32) {
+    (
+        direction.z.clamp(-1.0, 1.0).asin(),
+        direction.y.atan2(direction.x),
+    )
+}
+
+// Texture coordinates of a point on the unit sphere, `sphere_uv` in the shader
+pub(crate) fn sphere_uv(direction: Vec3) -> Vec2 {
+    let (latitude, longitude) = lat_lon(direction);
+    Vec2::new(longitude / TAU + 0.5, latitude / PI + 0.5)
+}
+
+// Cube faces, as the axis they face and the axes `u` and `v` run along on them.
+// Faces fill the cube-sphere atlas in this order, left to right then top to bottom.
+const CUBE_FACES: [(Vec3, Vec3, Vec3); 6] = [
+    (Vec3::X, Vec3::Y, Vec3::Z),
+    (Vec3::NEG_X, Vec3::NEG_Y, Vec3::Z),
+    (Vec3::Y, Vec3::NEG_X, Vec3::Z),
+    (Vec3::NEG_Y, Vec3::X, Vec3::Z),
+    (Vec3::Z, Vec3::Y, Vec3::NEG_X),
+    (Vec3::NEG_Z, Vec3::Y, Vec3::X),
+];
+
+// Faces across and down the cube-sphere atlas. Stacking all six in one column would make the
+// texture six faces tall, beyond what GPUs allow long before the faces themselves are too large.
+pub(crate) const CUBE_COLUMNS: u32 = 3;
+pub(crate) const CUBE_ROWS: u32 = 2;
+
+// Texel coordinates on a face are spread by angle rather than along the face,
+// which evens out texel sizes between the centre and the edges of a face
+fn warp(s: f32) -> f32 {
+    (s * FRAC_PI_4).tan()
+}
+
+fn unwarp(s: f32) -> f32 {
+    s.atan() / FRAC_PI_4
+}
+
+// Point on the unit sphere at coordinates in [0, 1] on a cube face
+pub(crate) fn cube_direction(face: usize, u: f32, v: f32) -> Vec3 {
+    let (normal, u_axis, v_axis) = CUBE_FACES[face];
+    (normal + u_axis * warp(u * 2.0 - 1.0) + v_axis * warp(v * 2.0 - 1.0)).normalize()
+}
+
+// Cube face a point on the unit sphere lies on, with its coordinates on it, inverse of `cube_direction`
+pub(crate) fn cube_face(direction: Vec3) -> (usize, f32, f32) {
+    let abs = direction.abs();
+    let face = if abs.x >= abs.y && abs.x >= abs.z {
+        if direction.x > 0.0 {
+            0
+        } else {
+            1
+        }
+    } else if abs.y >= abs.z {
+        if direction.y > 0.0 {
+            2
+        } else {
+            3
+        }
+    } else if direction.z > 0.0 {
+        4
+    } else {
+        5
+    };
+
+    let (normal, u_axis, v_axis) = CUBE_FACES[face];
+    let depth = direction.dot(normal);
+    (
+        face,
+        (unwarp(direction.dot(u_axis) / depth) + 1.0) / 2.0,
+        (unwarp(direction.dot(v_axis) / depth) + 1.0) / 2.0,
+    )
+}
+
+// Coordinates of a point on the unit sphere in the cube-sphere atlas, faces `size` texels wide,
+// `cube_uv` in the shader. Samples are kept half a texel inside their face.
+pub(crate) fn cube_uv(direction: Vec3, size: f32) -> Vec2 {
+    let (face, u, v) = cube_face(direction);
+    let margin = 0.5 / size;
+    let (column, row) = (face as u32 % CUBE_COLUMNS, face as u32 / CUBE_COLUMNS);
+    Vec2::new(
+        (column as f32 + u.clamp(margin, 1.0 - margin)) / CUBE_COLUMNS as f32,
+        (row as f32 + v.clamp(margin, 1.0 - margin)) / CUBE_ROWS as f32,
+    )
+}
+
+// Face and coordinates on it of the centre of texel (`x`, `y`) of the cube-sphere atlas, faces
+// `size` texels wide
+pub(crate) fn cube_texel(x: u32, y: u32, size: u32) -> (usize, f32, f32) {
+    let face = (y / size * CUBE_COLUMNS + x / size) as usize;
+    (
+        face,
+        ((x % size) as f32 + 0.5) / size as f32,
+        ((y % size) as f32 + 0.5) / size as f32,
+    )
+}
+
+// Edge of the cube faces holding as many texels as an equirectangular map
+pub(crate) fn cube_size(width: u32, height: u32) -> u32 {
+    ((width as f32 * height as f32 / CUBE_FACES.len() as f32).sqrt() as u32).max(1)
+}
+
+// Width and height of the cube-sphere atlas, faces `size` texels wide
+pub(crate) fn cube_atlas_size(size: u32) -> (u32, u32) {
+    (size * CUBE_COLUMNS, size * CUBE_ROWS)
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::heightmap::Heightmap;
+    use bevy::prelude::*;
+
+    #[test]
+    fn geo_round_trips() {
+        use super::{direction, lat_lon, latitude, longitude, row, sphere_uv};
+
+        for y in 0..16 {
+            for x in 0..32 {
+                let (lat, lon) = (latitude(y as f32, 16), longitude(x as f32, 32));
+                assert!((row(lat, 16) - y as f32).abs() < 1e-4);
+                let uv = sphere_uv(direction(lat, lon));
+                assert!((uv.x * 32.0 - 0.5 - x as f32).abs() < 1e-4);
+                assert!((uv.y * 16.0 - 0.5 - y as f32).abs() < 1e-4);
+
+                let point = direction(lat, lon);
+                assert!((point.length() - 1.0).abs() < 1e-6);
+                let (back_lat, back_lon) = lat_lon(point);
+                assert!((back_lat - lat).abs() < 1e-4);
+                assert!((back_lon - lon).abs() < 1e-4);
+            }
+        }
+    }
+
+    #[test]
+    fn geo_poles_are_on_z() {
+        use super::{direction, latitude};
+        use std::f32::consts::FRAC_PI_2;
+
+        assert!((latitude(-0.5, 8) + FRAC_PI_2).abs() < 1e-6);
+        assert!((direction(-FRAC_PI_2, 1.0) - Vec3::new(0.0, 0.0, -1.0)).length() < 1e-6);
+        assert!((direction(FRAC_PI_2, -2.0) - Vec3::new(0.0, 0.0, 1.0)).length() < 1e-6);
+        assert!((direction(0.0, 0.0) - Vec3::new(1.0, 0.0, 0.0)).length() < 1e-6);
+    }
+
+    // The vertex shader samples a texel at `sphere_uv` of the vertex, then moves the vertex out
+    // along its direction by the sampled elevation. Following the same steps on the CPU, every
+    // texel must land back on its own centre.
+    #[test]
+    fn displacement_lands_on_sampled_texel() {
+        use super::sphere_uv;
+
+        let (width, height) = (48u32, 24u32);
+        let map = Heightmap::new(
+            width,
+            height,
+            (0..width * height)
+                .map(|i| (i as f32 * 0.37).sin())
+                .collect(),
+        );
+        let radius = 3.0;
+
+        for y in 0..height {
+            for x in 0..width {
+                let vertex = map.direction(x as f32, y as f32) * radius;
+
+                // What the vertex shader does
+                let uv = sphere_uv(vertex.normalize());
+                let texel = (
+                    (uv.x * width as f32).floor() as isize,
+                    (uv.y * height as f32).floor() as isize,
+                );
+                let displaced = vertex.normalize() * (radius + map.get(texel.0, texel.1));
+
+                // What the CPU thinks is there
+                let (lat, lon) = (map.latitude(y as f32), map.longitude(x as f32));
+                let expected =
+                    super::direction(lat, lon) * (radius + map.get(x as isize, y as isize));
+
+                assert_eq!(texel, (x as isize, y as isize));
+                assert!((displaced - expected).length() < 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn cube_faces_round_trip() {
+        use super::{cube_direction, cube_face, cube_texel, cube_uv};
+
+        for face in 0..6 {
+            for (u, v) in [(0.5, 0.5), (0.1, 0.9), (0.99, 0.01), (0.3, 0.6)] {
+                let point = cube_direction(face, u, v);
+                assert!((point.length() - 1.0).abs() < 1e-6);
+
+                let (back, back_u, back_v) = cube_face(point);
+                assert_eq!(back, face);
+                assert!((back_u - u).abs() < 1e-4);
+                assert!((back_v - v).abs() < 1e-4);
+
+                let uv = cube_uv(point, 64.0);
+                assert!((uv.x * 3.0 - (face % 3) as f32 - u).abs() < 1e-4);
+                assert!((uv.y * 2.0 - (face / 3) as f32 - v).abs() < 1e-4);
+
+                // The texel found there has its centre within half a texel of the point
+                let (x, y) = ((uv.x * 64.0 * 3.0) as u32, (uv.y * 64.0 * 2.0) as u32);
+                let (texel_face, texel_u, texel_v) = cube_texel(x, y, 64);
+                assert_eq!(texel_face, face);
+                assert!((texel_u - u).abs() <= 0.5 / 64.0 + 1e-4);
+                assert!((texel_v - v).abs() <= 0.5 / 64.0 + 1e-4);
+            }
+        }
+
+        // Faces meet along their edges
+        assert!((cube_direction(0, 1.0, 0.5) - cube_direction(2, 0.0, 0.5)).length() < 1e-5);
+        assert!((cube_direction(0, 0.5, 1.0) - cube_direction(4, 0.5, 0.0)).length() < 1e-5);
+    }
+
+    #[test]
+    fn cube_texels_are_even() {
+        use super::{cube_direction, cube_size};
+
+        let size = cube_size(64, 32);
+        assert_eq!(size, 18);
+
+        // The largest texel is less than twice the smallest, where the poles of an
+        // equirectangular map squeeze texels to nothing
+        let spacing = |u: f32| {
+            let step = 1.0 / size as f32;
+            cube_direction(0, u, 0.5).angle_between(cube_direction(0, u + step, 0.5))
+        };
+        let (centre, edge) = (spacing(0.5), spacing(0.0));
+        assert!(centre / edge < 2.0 && edge / centre < 2.0);
+    }
+
+    // Keep the constants and formulas of the shader in step with this file, running its
+    // functions at points all over the sphere, along the edges of the cube faces and at the poles
+    #[test]
+    fn shader_mirrors_geo() {
+        use super::super::fixtures::Shader;
+        use super::{cube_uv, direction, lat_lon, sphere_uv, unwarp};
+
+        let shader = Shader::load("shaders/geo.wgsl");
+        let close = |gpu: Vec<f32>, cpu: &[f32]| {
+            gpu.len() == cpu.len() && gpu.iter().zip(cpu).all(|(a, b)| (a - b).abs() < 1e-5)
+        };
+
+        let mut points: Vec<Vec3> = (0..500)
+            .map(|i| direction((i as f32 * 0.71).sin() * 1.5, i as f32 * 2.3 % 6.2 - 3.1))
+            .collect();
+        points.extend([
+            Vec3::X,
+            Vec3::NEG_X,
+            Vec3::Y,
+            Vec3::NEG_Y,
+            Vec3::Z,
+            Vec3::NEG_Z,
+            Vec3::new(1.0, 1.0, 0.3).normalize(),
+            Vec3::new(-1.0, 0.2, -1.0).normalize(),
+            Vec3::new(0.4, -1.0, 1.0).normalize(),
+            Vec3::new(1.0, 1.0, 1.0).normalize(),
+        ]);
+        for point in points {
+            let (latitude, longitude) = lat_lon(point);
+            let args: &[&[f32]] = &[&point.to_array()];
+            assert!(close(shader.call("lat_lon", args), &[latitude, longitude]));
+            assert!(close(
+                shader.call("sphere_uv", args),
+                &[sphere_uv(point).x, sphere_uv(point).y]
+            ));
+            for size in [1.0, 18.0, 64.0] {
+                let uv = cube_uv(point, size);
+                assert!(
+                    close(
+                        shader.call("cube_uv", &[&point.to_array(), &[size]]),
+                        &[uv.x, uv.y]
+                    ),
+                    "cube_uv differs at {:?} with faces {} texels wide",
+                    point,
+                    size
+                );
+            }
+        }
+
+        for i in 0..=20 {
+            let s = i as f32 / 10.0 - 1.0;
+            assert!(close(shader.call("unwarp", &[&[s]]), &[unwarp(s)]));
+        }
+    }
+
+    // The shaders can't be run here, but they can be checked the way the GPU driver would.
+    // Importing modules define constants of their own, as `bevy_pbr::utils` does `PI`.
+    #[test]
+    fn geo_shader_validates() {
+        use naga::{
+            front::wgsl,
+            valid::{Capabilities, ValidationFlags, Validator},
+        };
+
+        let shader = std::fs::read_to_string(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/assets/shaders/geo.wgsl"
+        ))
+        .expect("Missing geo shader")
+        .replace("#define_import_path planet::geo", "");
+        let source = format!(
+            "let PI: f32 = 3.141592653589793;
+            {}
+            @vertex
+            fn vertex(@location(0) position: vec3<f32>) -> @builtin(position) vec4<f32> {{
+                let uv = sphere_uv(normalize(position)) + cube_uv(normalize(position), 64.0);
+                return vec4<f32>(uv, PI, 1.0);
+            }}",
+            shader
+        );
+
+        let module = wgsl::parse_str(&source).unwrap_or_else(|err| {
+            err.emit_to_stderr(&source);
+            panic!("geo.wgsl doesn't parse");
+        });
+        Validator::new(ValidationFlags::all(), Capabilities::empty())
+            .validate(&module)
+            .expect("geo.wgsl doesn't validate");
+    }
+}
@@ -0,0 +1,492 @@
+use bevy::{
+    prelude::*,
+    render::{
+        render_resource::{Extent3d, TextureDimension, TextureFormat},
+        settings::WgpuFeatures,
+    },
+};
+
+use super::geo;
+
+// How elevation is stored in the textures handed to the material
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ElevationFormat {
+    Float32,
+    // Half the memory, precise to about a thousandth of the amplitude
+    Float16,
+    // Elevations between `min` and `max` spread evenly over 16 bits, clamped outside of them.
+    // Needs the TEXTURE_FORMAT_16BIT_NORM feature of the GPU, see `supported`.
+    Unorm16 { min: f32, max: f32 },
+}
+
+impl ElevationFormat {
+    pub fn texture_format(self) -> TextureFormat {
+        match self {
+            ElevationFormat::Float32 => TextureFormat::R32Float,
+            ElevationFormat::Float16 => TextureFormat::R16Float,
+            ElevationFormat::Unorm16 { .. } => TextureFormat::R16Unorm,
+        }
+    }
+
+    // The format itself if a GPU with `features` can sample it, or else Float16
+    pub fn supported(self, features: WgpuFeatures) -> Self {
+        match self {
+            ElevationFormat::Unorm16 { .. }
+                if !features.contains(WgpuFeatures::TEXTURE_FORMAT_16BIT_NORM) =>
+            {
+                ElevationFormat::Float16
+            }
+            format => format,
+        }
+    }
+
+    // Bytes per texel
+    pub fn size(self) -> usize {
+        match self {
+            ElevationFormat::Float32 => 4,
+            ElevationFormat::Float16 | ElevationFormat::Unorm16 { .. } => 2,
+        }
+    }
+
+    // Offset and scale the shader applies to sampled texels to get elevations back
+    pub fn decode(self) -> Vec2 {
+        match self {
+            ElevationFormat::Unorm16 { min, max } => Vec2::new(min, max - min),
+            _ => Vec2::new(0.0, 1.0),
+        }
+    }
+
+    fn encode(self, value: f32, bytes: &mut Vec<u8>) {
+        match self {
+            ElevationFormat::Float32 => bytes.extend_from_slice(&value.to_le_bytes()),
+            ElevationFormat::Float16 => bytes.extend_from_slice(&f16_bits(value).to_le_bytes()),
+            ElevationFormat::Unorm16 { min, max } => {
+                let unorm = ((value - min) / (max - min)).clamp(0.0, 1.0);
+                bytes.extend_from_slice(&((unorm * u16::MAX as f32).round() as u16).to_le_bytes())
+            }
+        }
+    }
+
+    // Value the shader samples from an encoded texel, before `decode` is applied
+    pub(crate) fn read(self, bytes: &[u8]) -> f32 {
+        match self {
+            ElevationFormat::Float32 => {
+                f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+            }
+            ElevationFormat::Float16 => f16_value(u16::from_le_bytes([bytes[0], bytes[1]])),
+            ElevationFormat::Unorm16 { .. } => {
+                u16::from_le_bytes([bytes[0], bytes[1]]) as f32 / u16::MAX as f32
+            }
+        }
+    }
+}
+
+// Equirectangular elevation map, stored row by row.
+// Columns wrap around the sphere, rows run from one pole to the other.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Heightmap {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<f32>,
+}
+
+impl Heightmap {
+    pub fn new(width: u32, height: u32, data: Vec<f32>) -> Self {
+        assert_eq!(
+            data.len(),
+            (width * height) as usize,
+            "Heightmap data does not match its size"
+        );
+
+        Heightmap {
+            width,
+            height,
+            data,
+        }
+    }
+
+    // Index of a texel, wrapping columns and clamping rows
+    pub fn index(&self, x: isize, y: isize) -> usize {
+        let x = x.rem_euclid(self.width as isize);
+        let y = y.clamp(0, self.height as isize - 1);
+        y as usize * self.width as usize + x as usize
+    }
+
+    pub fn get(&self, x: isize, y: isize) -> f32 {
+        self.data[self.index(x, y)]
+    }
+
+    // Latitude in radians at a (fractional) row
+    pub fn latitude(&self, y: f32) -> f32 {
+        geo::latitude(y, self.height)
+    }
+
+    // Row at a latitude in radians, inverse of `latitude`
+    pub fn row(&self, latitude: f32) -> f32 {
+        geo::row(latitude, self.height)
+    }
+
+    // Longitude in radians at a (fractional) column
+    pub fn longitude(&self, x: f32) -> f32 {
+        geo::longitude(x, self.width)
+    }
+
+    // Value of the texel under a point on the unit sphere, the same one the shader shows there
+    pub fn sample(&self, direction: Vec3) -> f32 {
+        let uv = geo::sphere_uv(direction);
+        self.get(
+            (uv.x * self.width as f32).floor() as isize,
+            (uv.y * self.height as f32).floor() as isize,
+        )
+    }
+
+    // Value at a point on the unit sphere, interpolated between the four surrounding texels
+    pub fn interpolate(&self, direction: Vec3) -> f32 {
+        let uv = geo::sphere_uv(direction);
+        let (x, y) = (
+            uv.x * self.width as f32 - 0.5,
+            uv.y * self.height as f32 - 0.5,
+        );
+        let (x0, y0) = (x.floor(), y.floor());
+        let (u, v) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as isize, y0 as isize);
+
+        self.get(x0, y0) * (1.0 - u) * (1.0 - v)
+            + self.get(x0 + 1, y0) * u * (1.0 - v)
+            + self.get(x0, y0 + 1) * (1.0 - u) * v
+            + self.get(x0 + 1, y0 + 1) * u * v
+    }
+
+    // Point on the unit sphere at a (fractional) texel, with the poles on the z axis
+    pub fn direction(&self, x: f32, y: f32) -> Vec3 {
+        geo::direction(self.latitude(y), self.longitude(x))
+    }
+
+    // Share of the planet's surface covered by a texel in a row
+    pub fn texel_area(&self, y: isize) -> f32 {
+        let row = std::f32::consts::PI / self.height as f32;
+        let column = std::f32::consts::TAU / self.width as f32;
+        self.latitude(y as f32).cos() * row * column / (4.0 * std::f32::consts::PI)
+    }
+
+    pub fn to_image(&self, format: ElevationFormat) -> Image {
+        let mut bytes = Vec::with_capacity(self.data.len() * format.size());
+        for value in &self.data {
+            format.encode(*value, &mut bytes);
+        }
+
+        elevation_image(self.width, self.height, bytes, format)
+    }
+
+    // Resample the map onto the six faces of a cube, laid out side by side in one texture.
+    // Faces hold as many texels as the map, spread evenly over the sphere.
+    pub fn to_cube_image(&self, format: ElevationFormat) -> Image {
+        let size = geo::cube_size(self.width, self.height);
+        let (width, height) = geo::cube_atlas_size(size);
+        let mut bytes = Vec::with_capacity((width * height) as usize * format.size());
+
+        for y in 0..height {
+            for x in 0..width {
+                let (face, u, v) = geo::cube_texel(x, y, size);
+                format.encode(
+                    self.interpolate(geo::cube_direction(face, u, v)),
+                    &mut bytes,
+                );
+            }
+        }
+
+        elevation_image(width, height, bytes, format)
+    }
+}
+
+fn elevation_image(width: u32, height: u32, bytes: Vec<u8>, format: ElevationFormat) -> Image {
+    Image::new(
+        Extent3d {
+            width,
+            height,
+            ..default()
+        },
+        TextureDimension::D2,
+        bytes,
+        format.texture_format(),
+    )
+}
+
+// Bits of the nearest half-precision float, rounding ties to even
+fn f16_bits(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32 - 127 + 15;
+    let mantissa = bits & 0x7f_ffff;
+
+    if value.is_nan() {
+        return sign | 0x7e00;
+    }
+    if exponent >= 0x1f {
+        return sign | 0x7c00;
+    }
+    // Too small even for a subnormal
+    if exponent < -10 {
+        return sign;
+    }
+
+    // Normal numbers drop the low 13 bits of the mantissa, subnormals also shift in the implicit 1
+    let (high, mantissa, shift) = if exponent > 0 {
+        ((exponent as u32) << 10, mantissa, 13)
+    } else {
+        (0, mantissa | 0x80_0000, (14 - exponent) as u32)
+    };
+    let half = 1 << (shift - 1);
+    let rest = mantissa & ((1 << shift) - 1);
+    let mut result = high | (mantissa >> shift);
+    // A carry out of the mantissa correctly moves on to the next exponent, or infinity
+    if rest > half || (rest == half && result & 1 == 1) {
+        result += 1;
+    }
+
+    sign | result as u16
+}
+
+// Value of a half-precision float, exact as every half fits in a single-precision float
+fn f16_value(bits: u16) -> f32 {
+    let sign = if bits & 0x8000 == 0 { 1.0 } else { -1.0 };
+    let exponent = ((bits >> 10) & 0x1f) as i32;
+    let mantissa = (bits & 0x3ff) as f32;
+
+    match exponent {
+        0 => sign * mantissa * 2f32.powi(-24),
+        0x1f if mantissa == 0.0 => sign * f32::INFINITY,
+        0x1f => f32::NAN,
+        _ => sign * (1.0 + mantissa / 1024.0) * 2f32.powi(exponent - 15),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ElevationFormat;
+
+    #[test]
+    fn heightmap_wraps_columns() {
+        use super::Heightmap;
+
+        let map = Heightmap::new(4, 3, (0..12).map(|f| f as f32).collect());
+        assert_eq!(map.get(-1, 0), 3.0);
+        assert_eq!(map.get(4, 1), 4.0);
+        assert_eq!(map.get(1, -2), 1.0);
+        assert_eq!(map.get(1, 7), 9.0);
+    }
+
+    #[test]
+    fn heightmap_rows_are_latitudes() {
+        use super::Heightmap;
+
+        let map = Heightmap::new(8, 4, vec![0.0; 32]);
+        assert!((map.latitude(-0.5) + std::f32::consts::FRAC_PI_2).abs() < 1e-6);
+        assert!((map.latitude(3.5) - std::f32::consts::FRAC_PI_2).abs() < 1e-6);
+
+        for y in 0..4 {
+            let y = y as f32;
+            assert!((map.row(map.latitude(y)) - y).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn heightmap_samples_nearest_texel() {
+        use super::Heightmap;
+
+        let map = Heightmap::new(4, 3, (0..12).map(|f| f as f32).collect());
+        assert_eq!(map.sample(map.direction(2.0, 1.0)), 6.0);
+        assert_eq!(map.sample(map.direction(3.2, 0.2)), 3.0);
+        // Points past the seam wrap around
+        assert_eq!(map.sample(map.direction(4.1, 2.0)), 8.0);
+    }
+
+    #[test]
+    fn heightmap_interpolates_between_texels() {
+        use super::Heightmap;
+
+        let map = Heightmap::new(4, 3, (0..12).map(|f| f as f32).collect());
+        assert!((map.interpolate(map.direction(1.0, 1.0)) - 5.0).abs() < 1e-4);
+        assert!((map.interpolate(map.direction(1.5, 1.0)) - 5.5).abs() < 1e-4);
+        assert!((map.interpolate(map.direction(1.0, 1.5)) - 7.0).abs() < 1e-4);
+        // Halfway across the seam
+        assert!((map.interpolate(map.direction(3.5, 0.0)) - 1.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn heightmap_to_cube_image() {
+        use super::{super::geo, Heightmap};
+        use bevy::prelude::*;
+
+        // Elevation rising from the south pole to the north pole
+        let map = Heightmap::new(64, 32, (0..64 * 32).map(|i| (i / 64) as f32).collect());
+        let img = map.to_cube_image(ElevationFormat::Float32);
+        let size = geo::cube_size(64, 32);
+        let (width, height) = geo::cube_atlas_size(size);
+        assert_eq!(img.size(), Vec2::new(size as f32 * 3., size as f32 * 2.));
+
+        // Every texel of the atlas holds the map's value where the shader looks it up
+        let atlas = |x: u32, y: u32| {
+            let idx = (y * width + x) as usize * 4;
+            f32::from_le_bytes(img.data[idx..idx + 4].try_into().unwrap())
+        };
+        let texel = |face: u32, x: u32, y: u32| atlas(face % 3 * size + x, face / 3 * size + y);
+        for face in 0..6 {
+            let (u, v) = (0.3, 0.7);
+            let (x, y) = ((u * size as f32) as u32, (v * size as f32) as u32);
+            let point = geo::cube_direction(
+                face,
+                (x as f32 + 0.5) / size as f32,
+                (y as f32 + 0.5) / size as f32,
+            );
+            let uv = geo::cube_uv(point, size as f32);
+            let (atlas_x, atlas_y) = ((uv.x * width as f32) as u32, (uv.y * height as f32) as u32);
+            assert_eq!(atlas(atlas_x, atlas_y), texel(face as u32, x, y));
+            assert!((texel(face as u32, x, y) - map.interpolate(point)).abs() < 1e-4);
+        }
+        // The +z face looks at the north pole
+        assert!(texel(4, size / 2, size / 2) > 30.0);
+        assert!(texel(5, size / 2, size / 2) < 1.0);
+    }
+
+    #[test]
+    fn heightmap_directions_are_on_the_sphere() {
+        use super::Heightmap;
+
+        let map = Heightmap::new(8, 4, vec![0.0; 32]);
+        assert!((map.direction(0.0, -0.5).z + 1.0).abs() < 1e-6);
+        assert!((map.direction(3.5, 1.5).x - 1.0).abs() < 1e-6);
+        // The seam meets itself
+        assert!((map.direction(-0.5, 1.5) - map.direction(7.5, 1.5)).length() < 1e-6);
+    }
+
+    #[test]
+    fn heightmap_to_image() {
+        use super::Heightmap;
+        use bevy::prelude::*;
+
+        let map = Heightmap::new(8, 4, vec![0.5; 32]);
+        let img = map.to_image(ElevationFormat::Float32);
+        assert_eq!(img.size(), Vec2::new(8., 4.));
+        assert_eq!(&img.data[0..4], &0.5f32.to_le_bytes());
+    }
+
+    #[test]
+    fn heightmap_to_half_precision() {
+        use super::{Heightmap, TextureFormat};
+
+        let map = Heightmap::new(4, 1, vec![0.5, -0.25, 1.0, 0.8]);
+        let img = map.to_image(ElevationFormat::Float16);
+        assert_eq!(img.texture_descriptor.format, TextureFormat::R16Float);
+        assert_eq!(img.data.len(), 8);
+        assert_eq!(&img.data[0..2], &0x3800u16.to_le_bytes());
+        assert_eq!(&img.data[2..4], &0xb400u16.to_le_bytes());
+
+        let format = ElevationFormat::Unorm16 {
+            min: -1.0,
+            max: 1.0,
+        };
+        let img = map.to_image(format);
+        assert_eq!(img.texture_descriptor.format, TextureFormat::R16Unorm);
+        // The shader gets elevations back from the normalized texels
+        let decode = format.decode();
+        for (texel, expected) in img.data.chunks(2).zip(&map.data) {
+            let unorm = u16::from_le_bytes([texel[0], texel[1]]) as f32 / u16::MAX as f32;
+            assert!((decode.x + unorm * decode.y - expected).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn f16_bits_round_to_nearest() {
+        use super::f16_bits;
+
+        assert_eq!(f16_bits(0.0), 0x0000);
+        assert_eq!(f16_bits(-0.0), 0x8000);
+        assert_eq!(f16_bits(1.0), 0x3c00);
+        assert_eq!(f16_bits(-2.0), 0xc000);
+        assert_eq!(f16_bits(0.1), 0x2e66);
+        assert_eq!(f16_bits(65504.0), 0x7bff);
+        // Subnormals, overflow and special values
+        assert_eq!(f16_bits(1e-5), 0x00a8);
+        assert_eq!(f16_bits(1e-9), 0x0000);
+        assert_eq!(f16_bits(1e6), 0x7c00);
+        assert_eq!(f16_bits(f32::NEG_INFINITY), 0xfc00);
+        assert_eq!(f16_bits(f32::NAN) & 0x7c00, 0x7c00);
+        // Ties go to the even mantissa
+        assert_eq!(f16_bits(1.0 + 1.0 / 2048.0), 0x3c00);
+        assert_eq!(f16_bits(1.0 + 3.0 / 2048.0), 0x3c02);
+    }
+
+    #[test]
+    fn texels_read_back_as_the_shader_sees_them() {
+        use super::{f16_bits, f16_value};
+
+        for bits in [
+            0x0000, 0x8000, 0x3c00, 0xc000, 0x2e66, 0x7bff, 0x00a8, 0xfc00,
+        ] {
+            assert_eq!(f16_bits(f16_value(bits)), bits);
+        }
+        assert!(f16_value(0x7e00).is_nan());
+
+        for format in [
+            ElevationFormat::Float32,
+            ElevationFormat::Float16,
+            ElevationFormat::Unorm16 {
+                min: -1.0,
+                max: 1.0,
+            },
+        ] {
+            for value in [-0.8, -0.1, 0.0, 0.37, 0.8] {
+                let mut bytes = Vec::new();
+                format.encode(value, &mut bytes);
+                let decode = format.decode();
+                let read = decode.x + format.read(&bytes) * decode.y;
+                assert!((read - value).abs() < 1e-3, "{:?} read {}", format, read);
+            }
+        }
+    }
+
+    #[test]
+    fn unorm16_falls_back_without_gpu_support() {
+        use super::WgpuFeatures;
+
+        let unorm = ElevationFormat::Unorm16 {
+            min: -1.0,
+            max: 1.0,
+        };
+        assert_eq!(
+            unorm.supported(WgpuFeatures::TEXTURE_FORMAT_16BIT_NORM),
+            unorm
+        );
+        assert_eq!(
+            unorm.supported(WgpuFeatures::empty()),
+            ElevationFormat::Float16
+        );
+        assert_eq!(
+            ElevationFormat::Float32.supported(WgpuFeatures::empty()),
+            ElevationFormat::Float32
+        );
+    }
+
+    // Encoding writes straight into the texture's bytes, sized up front so they never grow.
+    // tests/peak_allocation.rs bounds the memory taken on the side.
+    #[test]
+    fn heightmap_images_are_encoded_in_place() {
+        use super::Heightmap;
+
+        let map = Heightmap::new(256, 128, vec![0.5; 256 * 128]);
+        let formats = [
+            ElevationFormat::Float32,
+            ElevationFormat::Float16,
+            ElevationFormat::Unorm16 { min: 0.0, max: 1.0 },
+        ];
+        for format in formats {
+            let img = map.to_image(format);
+            assert_eq!(img.data.len(), 256 * 128 * format.size());
+            assert_eq!(img.data.capacity(), img.data.len());
+
+            let img = map.to_cube_image(format);
+            assert_eq!(img.data.capacity(), img.data.len());
+        }
+    }
+}
@@ -0,0 +1,382 @@
+use super::{
+    generation::{GenerationError, MapPass, Progress},
+    heightmap::Heightmap,
+    stats::row_weights,
+};
+
+// Remaps elevations by their rank, so a chosen share of the surface lies above the sea level and
+// the elevations above and below it follow chosen curves. Whatever raised the map only decides
+// which places are higher than others.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Hypsometry {
+    // Share of the surface above the sea level, in [0, 1]
+    pub land_fraction: f32,
+    pub sea_level: f32,
+    pub curves: HypsometricCurves,
+}
+
+impl Default for Hypsometry {
+    fn default() -> Self {
+        Hypsometry {
+            land_fraction: 0.3,
+            sea_level: 0.0,
+            curves: HypsometricCurves::default(),
+        }
+    }
+}
+
+// Elevations the surface is remapped to, from the deepest trench to the highest peak
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct HypsometricCurves {
+    // Elevation of the deepest and the highest point after remapping
+    pub lowest: f32,
+    pub highest: f32,
+    // Sea floor from the deepest point to the coast, as points mapping the share of the ocean
+    // below to the share of the depth risen from the deepest point, both in [0, 1]
+    pub ocean: Vec<(f32, f32)>,
+    // Land from the coast to the highest point, as points mapping the share of the land below
+    // to the share of the height risen from the sea level, both in [0, 1]
+    pub land: Vec<(f32, f32)>,
+}
+
+impl Default for HypsometricCurves {
+    fn default() -> Self {
+        HypsometricCurves {
+            lowest: -0.8,
+            highest: 0.8,
+            // A few trenches, wide abyssal plains, then the continental slope and shelf
+            ocean: vec![
+                (0.0, 0.0),
+                (0.05, 0.45),
+                (0.75, 0.65),
+                (0.9, 0.9),
+                (1.0, 1.0),
+            ],
+            // Mostly lowlands, with mountains on a small share of the land
+            land: vec![(0.0, 0.0), (0.6, 0.1), (0.9, 0.3), (1.0, 1.0)],
+        }
+    }
+}
+
+impl HypsometricCurves {
+    // Curves through points within [0, 1], sorted by input, between a lowest and a highest point
+    pub fn validate(&self) -> Result<(), GenerationError> {
+        let invalid = |err: String| Err(GenerationError::InvalidSettings(err));
+        let finite = self.lowest.is_finite() && self.highest.is_finite();
+        if !finite || self.lowest >= self.highest {
+            return invalid(format!(
+                "Lowest elevation {} must lie below the highest {}",
+                self.lowest, self.highest
+            ));
+        }
+        for (name, points) in [("ocean", &self.ocean), ("land", &self.land)] {
+            let share = |f: f32| (0.0..=1.0).contains(&f);
+            if points.is_empty()
+                || !points
+                    .iter()
+                    .all(|(input, output)| share(*input) && share(*output))
+            {
+                return invalid(format!("The {} curve needs points within [0, 1]", name));
+            }
+            if points.windows(2).any(|pair| pair[0].0 > pair[1].0) {
+                return invalid(format!("The {} curve's points must be sorted", name));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Piecewise linear curve through points sorted by input, flat beyond the first and last one
+fn curve(value: f32, points: &[(f32, f32)]) -> f32 {
+    let upper = match points.iter().position(|(input, _)| *input > value) {
+        Some(0) => return points[0].1,
+        None => return points.last().map_or(value, |(_, output)| *output),
+        Some(upper) => upper,
+    };
+    let ((x0, y0), (x1, y1)) = (points[upper - 1], points[upper]);
+    y0 + (y1 - y0) * (value - x0) / (x1 - x0)
+}
+
+impl Hypsometry {
+    // Remapping to the share of land given on the command line with `--land-fraction <share>`.
+    // None if no share was given, leaving maps as they were raised. A share that isn't a number
+    // is kept as NaN, so the world fails to be raised rather than keeping its land unasked.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Option<Self> {
+        let mut args = args.into_iter();
+        let mut land_fraction = None;
+        while let Some(arg) = args.next() {
+            if arg == "--land-fraction" {
+                land_fraction = Some(
+                    args.next()
+                        .map_or(f32::NAN, |share| share.parse().unwrap_or(f32::NAN)),
+                );
+            }
+        }
+
+        land_fraction.map(|land_fraction| Hypsometry {
+            land_fraction,
+            ..Default::default()
+        })
+    }
+
+    // A share of land within [0, 1], remapped along valid curves
+    pub fn validate(&self) -> Result<(), GenerationError> {
+        if !(0.0..=1.0).contains(&self.land_fraction) {
+            return Err(GenerationError::InvalidSettings(format!(
+                "Land fraction must be a share in [0, 1], got {}",
+                self.land_fraction
+            )));
+        }
+        self.curves.validate()
+    }
+
+    // Elevation at a share of the surface below it
+    fn elevation(&self, rank: f32) -> f32 {
+        let curves = &self.curves;
+        let land = self.land_fraction.clamp(0.0, 1.0);
+        let ocean = 1.0 - land;
+        if rank < ocean {
+            let depth = curve(rank / ocean, &curves.ocean).clamp(0.0, 1.0);
+            curves.lowest + (self.sea_level - curves.lowest) * depth
+        } else {
+            let share = if land > 0.0 {
+                (rank - ocean) / land
+            } else {
+                0.0
+            };
+            let height = curve(share, &curves.land).clamp(0.0, 1.0);
+            self.sea_level + (curves.highest - self.sea_level) * height
+        }
+    }
+}
+
+impl MapPass for Hypsometry {
+    fn apply(&self, mut map: Heightmap, progress: &Progress) -> Result<Heightmap, GenerationError> {
+        let width = map.width as usize;
+        let weights = row_weights(&map);
+        let total: f64 = weights.iter().map(|weight| *weight as f64).sum::<f64>() * width as f64;
+        if total <= 0.0 {
+            return Ok(map);
+        }
+
+        progress.start("hypsometry", map.height * 2);
+        let mut order: Vec<u32> = (0..map.data.len() as u32).collect();
+        order.sort_unstable_by(|a, b| map.data[*a as usize].total_cmp(&map.data[*b as usize]));
+        if progress.is_cancelled() {
+            return Err(GenerationError::Cancelled);
+        }
+        progress.advance(map.height);
+
+        // Texels of equal elevation are ranked together by the surface below their middle, so
+        // flat ground stays flat and the sea level splits the surface at the requested share to
+        // within a texel
+        let mut below = 0.0f64;
+        let (mut start, mut done) = (0, 0);
+        while start < order.len() {
+            let value = map.data[order[start] as usize];
+            let end = order[start..]
+                .iter()
+                .position(|texel| map.data[*texel as usize] != value)
+                .map_or(order.len(), |len| start + len);
+            let group = &order[start..end];
+            start = end;
+
+            let weight: f64 = group
+                .iter()
+                .map(|texel| weights[*texel as usize / width] as f64)
+                .sum();
+            let elevation = self.elevation(((below + weight / 2.0) / total) as f32);
+            for texel in group {
+                map.data[*texel as usize] = elevation;
+            }
+            below += weight;
+
+            done += group.len();
+            if done >= width {
+                if progress.is_cancelled() {
+                    return Err(GenerationError::Cancelled);
+                }
+                progress.advance((done / width) as u32);
+                done %= width;
+            }
+        }
+
+        Ok(map)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{generation::Progress, heightmap::Heightmap};
+
+    // Rolling hills with a slope towards one pole, so rows hold different elevations
+    fn hills(width: u32, height: u32) -> Heightmap {
+        let mut data = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                let (x, y) = (x as f32, y as f32);
+                data.push(0.3 * (x * 0.2).cos() * (y * 0.15).sin() + 0.01 * y);
+            }
+        }
+
+        Heightmap::new(width, height, data)
+    }
+
+    #[test]
+    fn land_fraction_is_hit_at_sea_level() {
+        use super::super::{stats::WorldStats, world_data::WorldData};
+        use super::{Hypsometry, MapPass};
+
+        for (land_fraction, sea_level) in [(0.3, 0.0), (0.7, 0.2), (0.0, -0.1), (1.0, 0.0)] {
+            let hypsometry = Hypsometry {
+                land_fraction,
+                sea_level,
+                ..Default::default()
+            };
+            let map = hypsometry
+                .apply(hills(128, 64), &Progress::default())
+                .unwrap();
+            let stats = WorldStats::compute(&WorldData::new(map), sea_level);
+
+            assert!(
+                (stats.land_fraction - land_fraction).abs() < 1e-3,
+                "Asked for {} land, got {}",
+                land_fraction,
+                stats.land_fraction
+            );
+        }
+    }
+
+    #[test]
+    fn remap_keeps_order_and_range() {
+        use super::{Hypsometry, MapPass};
+
+        let raw = hills(96, 48);
+        let hypsometry = Hypsometry::default();
+        let map = hypsometry.apply(raw.clone(), &Progress::default()).unwrap();
+
+        let mut pairs: Vec<_> = raw.data.iter().zip(&map.data).collect();
+        pairs.sort_by(|a, b| a.0.total_cmp(b.0));
+        assert!(pairs.windows(2).all(|pair| pair[0].1 <= pair[1].1));
+
+        let curves = &hypsometry.curves;
+        assert!(map
+            .data
+            .iter()
+            .all(|f| (curves.lowest..=curves.highest).contains(f)));
+        let (min, max) = map
+            .data
+            .iter()
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), f| {
+                (min.min(*f), max.max(*f))
+            });
+        assert!(min < curves.lowest + 0.05);
+        assert!(max > curves.highest - 0.05);
+    }
+
+    #[test]
+    fn linear_curves_spread_elevations_evenly() {
+        use super::super::{stats::WorldStats, world_data::WorldData};
+        use super::{HypsometricCurves, Hypsometry, MapPass};
+
+        let hypsometry = Hypsometry {
+            land_fraction: 0.5,
+            curves: HypsometricCurves {
+                lowest: -1.0,
+                highest: 1.0,
+                ocean: vec![(0.0, 0.0), (1.0, 1.0)],
+                land: vec![(0.0, 0.0), (1.0, 1.0)],
+            },
+            ..Default::default()
+        };
+        let map = hypsometry
+            .apply(hills(128, 64), &Progress::default())
+            .unwrap();
+        let stats = WorldStats::compute(&WorldData::new(map), 0.0);
+
+        // Every percent of the surface spans the same elevation
+        for (percent, elevation) in stats.percentiles.iter().enumerate() {
+            let expected = percent as f32 / 50.0 - 1.0;
+            assert!(
+                (elevation - expected).abs() < 0.02,
+                "Percentile {} at {}, expected {}",
+                percent,
+                elevation,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn land_fraction_from_args() {
+        use super::{GenerationError, Hypsometry};
+
+        let args = |args: &[&str]| Hypsometry::from_args(args.iter().map(|arg| arg.to_string()));
+
+        // Off unless asked for
+        assert_eq!(args(&[]), None);
+        assert_eq!(args(&["--generator", "craters"]), None);
+
+        let hypsometry = args(&["--generator", "simplex", "--land-fraction", "0.4"]).unwrap();
+        assert_eq!(hypsometry.land_fraction, 0.4);
+        assert_eq!(hypsometry.curves, Hypsometry::default().curves);
+        assert_eq!(hypsometry.validate(), Ok(()));
+
+        // Shares that aren't numbers, or aren't shares, are asked for but can't be met
+        for share in ["most", "NaN", "inf", "-0.1", "1.5"] {
+            let hypsometry = args(&["--land-fraction", share]).unwrap();
+            assert!(matches!(
+                hypsometry.validate(),
+                Err(GenerationError::InvalidSettings(_))
+            ));
+        }
+        assert!(args(&["--land-fraction"]).unwrap().validate().is_err());
+    }
+
+    #[test]
+    fn curves_are_validated() {
+        use super::{HypsometricCurves, Hypsometry};
+
+        let valid = HypsometricCurves::default();
+        assert_eq!(valid.validate(), Ok(()));
+
+        let invalid = [
+            HypsometricCurves {
+                lowest: 1.0,
+                highest: -1.0,
+                ..valid.clone()
+            },
+            HypsometricCurves {
+                ocean: vec![],
+                ..valid.clone()
+            },
+            HypsometricCurves {
+                land: vec![(0.0, 0.0), (1.0, 1.5)],
+                ..valid.clone()
+            },
+            HypsometricCurves {
+                land: vec![(0.0, 0.0), (0.8, 0.5), (0.6, 1.0)],
+                ..valid.clone()
+            },
+        ];
+        for curves in invalid {
+            let hypsometry = Hypsometry {
+                curves,
+                ..Default::default()
+            };
+            assert!(hypsometry.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn cancelled_remap_stops() {
+        use super::{GenerationError, Hypsometry, MapPass};
+
+        let progress = Progress::default();
+        progress.cancel();
+        assert!(matches!(
+            Hypsometry::default().apply(hills(32, 16), &progress),
+            Err(GenerationError::Cancelled)
+        ));
+    }
+}
@@ -0,0 +1,439 @@
+use std::{
+    fmt,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use bevy::prelude::*;
+use once_cell::sync::OnceCell;
+
+use super::{
+    export::Sidecar,
+    generation::{bands, fill_rows, GenerationError, Progress, WorldGenSettings, WorldGenerator},
+    geo,
+    heightmap::Heightmap,
+};
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ImportError {
+    NoPath,
+    Missing(PathBuf),
+    Unreadable(PathBuf, String),
+    Malformed(PathBuf, String),
+    Unsupported(PathBuf, String),
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImportError::NoPath => write!(f, "No heightmap file to import was given"),
+            ImportError::Missing(path) => write!(f, "Heightmap {} does not exist", path.display()),
+            ImportError::Unreadable(path, err) => {
+                write!(f, "Could not read heightmap {}: {}", path.display(), err)
+            }
+            ImportError::Malformed(path, err) => {
+                write!(f, "Heightmap {} is malformed: {}", path.display(), err)
+            }
+            ImportError::Unsupported(path, err) => {
+                write!(f, "Heightmap {} is not supported: {}", path.display(), err)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ImportError {}
+
+// A file that can't be read is an I/O error, while a missing path or a file that can't be a
+// heightmap is a parameter no world can be raised from
+impl From<&ImportError> for GenerationError {
+    fn from(err: &ImportError) -> Self {
+        match err {
+            ImportError::Missing(_) | ImportError::Unreadable(..) => {
+                GenerationError::Io(err.to_string())
+            }
+            ImportError::NoPath | ImportError::Malformed(..) | ImportError::Unsupported(..) => {
+                GenerationError::InvalidSettings(err.to_string())
+            }
+        }
+    }
+}
+
+// Raises the world from an equirectangular heightmap on disk, with the north pole on the first
+// row, resampled to the size in the settings. Reads 8 and 16-bit grayscale PNG, spread between
+// `min` and `max` of a JSON sidecar next to the file or the amplitude otherwise, and raw
+// little-endian f32 sized by the sidecar or twice as wide as high otherwise.
+// Files written by `ElevationExport` read back as they were.
+#[derive(Debug, Clone)]
+pub(super) struct ImportGenerator {
+    settings: WorldGenSettings,
+    path: Option<PathBuf>,
+    // Loaded once, shared with the generators of previews
+    source: Arc<OnceCell<Result<Heightmap, ImportError>>>,
+}
+
+impl ImportGenerator {
+    pub fn new(settings: &WorldGenSettings) -> Self {
+        ImportGenerator {
+            settings: *settings,
+            path: None,
+            source: default(),
+        }
+    }
+
+    pub fn with_path(self, path: impl Into<PathBuf>) -> Self {
+        ImportGenerator {
+            path: Some(path.into()),
+            source: default(),
+            ..self
+        }
+    }
+
+    pub fn source(&self) -> Result<&Heightmap, &ImportError> {
+        let amplitude = self.settings.amplitude;
+        self.source
+            .get_or_init(|| match &self.path {
+                Some(path) => load(path, (-amplitude, amplitude)),
+                None => Err(ImportError::NoPath),
+            })
+            .as_ref()
+    }
+}
+
+impl WorldGenerator for ImportGenerator {
+    fn get_heightmap(&self, progress: &Progress) -> Result<Heightmap, GenerationError> {
+        progress.start("import", 1);
+        let source = self.source()?;
+        progress.advance(1);
+
+        let WorldGenSettings { width, height, .. } = self.settings;
+        let mut data = vec![0.0; (width * height) as usize];
+        progress.start("elevation", height);
+        fill_rows(&mut data, width, bands(), progress, |y, row| {
+            let latitude = geo::latitude(y as f32, height);
+            for (x, texel) in row.iter_mut().enumerate() {
+                let longitude = geo::longitude(x as f32, width);
+                *texel = source.interpolate(geo::direction(latitude, longitude));
+            }
+        })?;
+
+        Ok(Heightmap::new(width, height, data))
+    }
+
+    // The same file, resampled to another size
+    fn with_size(&self, width: u32, height: u32) -> Box<dyn WorldGenerator> {
+        Box::new(ImportGenerator {
+            settings: self.settings.resized(width, height),
+            ..self.clone()
+        })
+    }
+
+    // Files edited since keep the same path, so they are told apart by size and modification
+    // time, along with their sidecar
+    fn fingerprint(&self) -> Option<String> {
+        let path = self.path.as_ref()?;
+        let stamp = |path: &Path| {
+            let metadata = std::fs::metadata(path).ok()?;
+            Some((metadata.len(), metadata.modified().ok()?))
+        };
+        Some(format!(
+            "{:?} {:?} {:?}",
+            path,
+            stamp(path)?,
+            stamp(&path.with_extension("json"))
+        ))
+    }
+}
+
+// Read a heightmap file, spreading PNG gray levels over `range` unless a sidecar says otherwise
+fn load(path: &Path, range: (f32, f32)) -> Result<Heightmap, ImportError> {
+    let bytes = std::fs::read(path).map_err(|err| match err.kind() {
+        ErrorKind::NotFound => ImportError::Missing(path.into()),
+        _ => ImportError::Unreadable(path.into(), err.to_string()),
+    })?;
+    let sidecar = read_sidecar(&path.with_extension("json"))?;
+
+    let (width, height, data) = if bytes.starts_with(PNG_SIGNATURE) {
+        let range = (
+            sidecar.min.unwrap_or(range.0),
+            sidecar.max.unwrap_or(range.1),
+        );
+        decode_png(path, &bytes, range)?
+    } else {
+        decode_raw(path, &bytes, sidecar.width.zip(sidecar.height))?
+    };
+
+    // Files are north up, maps start at the south pole
+    let data = data
+        .chunks(width as usize)
+        .rev()
+        .flatten()
+        .copied()
+        .collect();
+    Ok(Heightmap::new(width, height, data))
+}
+
+fn decode_png(
+    path: &Path,
+    bytes: &[u8],
+    (min, max): (f32, f32),
+) -> Result<(u32, u32, Vec<f32>), ImportError> {
+    let malformed = |err: png::DecodingError| ImportError::Malformed(path.into(), err.to_string());
+
+    let mut decoder = png::Decoder::new(bytes);
+    decoder.set_transformations(png::Transformations::IDENTITY);
+    let mut reader = decoder.read_info().map_err(malformed)?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf).map_err(malformed)?;
+
+    let channels = match info.color_type {
+        png::ColorType::Grayscale => 1,
+        png::ColorType::GrayscaleAlpha => 2,
+        other => {
+            return Err(ImportError::Unsupported(
+                path.into(),
+                format!("{:?} instead of grayscale", other),
+            ))
+        }
+    };
+    let levels: Vec<f32> = match info.bit_depth {
+        png::BitDepth::Eight => buf
+            .iter()
+            .step_by(channels)
+            .map(|b| *b as f32 / 255.0)
+            .collect(),
+        png::BitDepth::Sixteen => buf
+            .chunks(2)
+            .step_by(channels)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]) as f32 / u16::MAX as f32)
+            .collect(),
+        other => {
+            return Err(ImportError::Unsupported(
+                path.into(),
+                format!("{:?} bits instead of 8 or 16", other),
+            ))
+        }
+    };
+
+    let data = levels
+        .into_iter()
+        .take((info.width * info.height) as usize)
+        .map(|level| min + level * (max - min))
+        .collect();
+    Ok((info.width, info.height, data))
+}
+
+fn decode_raw(
+    path: &Path,
+    bytes: &[u8],
+    size: Option<(u32, u32)>,
+) -> Result<(u32, u32, Vec<f32>), ImportError> {
+    let malformed = |err: String| ImportError::Malformed(path.into(), err);
+
+    if bytes.len() % 4 != 0 {
+        return Err(malformed(format!(
+            "{} bytes is not a whole number of f32",
+            bytes.len()
+        )));
+    }
+    let texels = bytes.len() / 4;
+
+    let (width, height) = match size {
+        Some(size) => size,
+        None => {
+            let height = ((texels / 2) as f64).sqrt().round() as u32;
+            (height * 2, height)
+        }
+    };
+    if width as usize * height as usize != texels || texels == 0 {
+        return Err(malformed(format!(
+            "{} values do not make a {}x{} map, give its size in a sidecar",
+            texels, width, height
+        )));
+    }
+
+    let data = bytes
+        .chunks(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect();
+    Ok((width, height, data))
+}
+
+// The JSON sidecar at `path`, or an empty one if there is none
+fn read_sidecar(path: &Path) -> Result<Sidecar, ImportError> {
+    let json = match std::fs::read(path) {
+        Ok(json) => json,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Sidecar::default()),
+        Err(err) => return Err(ImportError::Unreadable(path.into(), err.to_string())),
+    };
+    serde_json::from_slice(&json)
+        .map_err(|err| ImportError::Malformed(path.into(), err.to_string()))
+}
+
+#[cfg(test)]
+pub(super) mod test {
+    use super::super::{
+        export::ElevationExport,
+        fixtures::settings,
+        generation::{GenerationError, Progress, WorldGenerator},
+        heightmap::Heightmap,
+        stats::WorldStats,
+        world_data::WorldData,
+    };
+    use super::{ImportError, ImportGenerator};
+    use std::path::PathBuf;
+
+    // A directory of its own for each test, removed when dropped. Named after the process too, so
+    // test runs side by side don't share it
+    pub(crate) struct Scratch(pub PathBuf);
+
+    impl Scratch {
+        pub fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("urbanite-{}-{}", name, std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            Scratch(dir)
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn ramp() -> Heightmap {
+        Heightmap::new(
+            32,
+            16,
+            (0..32 * 16).map(|i| (i / 32) as f32 / 15.0 - 0.5).collect(),
+        )
+    }
+
+    #[test]
+    fn import_reads_exports_back() {
+        let dir = Scratch::new("exports");
+        let map = ramp();
+        // Statistics in the sidecar leave the range import reads alone
+        let stats = WorldStats::compute(&WorldData::new(map.clone()), 0.0);
+        let paths = ElevationExport::new(&map, 3)
+            .with_stats(&stats)
+            .save(&dir.0, "world")
+            .unwrap();
+
+        for path in &paths[0..2] {
+            let gen = ImportGenerator::new(&settings(0, 32, 16)).with_path(path);
+            let imported = gen.get_heightmap(&Progress::default()).unwrap();
+            for (a, b) in imported.data.iter().zip(&map.data) {
+                assert!(
+                    (a - b).abs() < 1e-4,
+                    "{} read {} for {}",
+                    path.display(),
+                    a,
+                    b
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn import_resamples() {
+        let dir = Scratch::new("resample");
+        let map = ramp();
+        let path = &ElevationExport::new(&map, 3).save(&dir.0, "world").unwrap()[1];
+
+        let gen = ImportGenerator::new(&settings(0, 64, 32)).with_path(path);
+        let progress = Progress::default();
+        let imported = gen.get_heightmap(&progress).unwrap();
+        assert_eq!((imported.width, imported.height), (64, 32));
+        assert_eq!(progress.rows_done(), 32);
+        // Still rising towards the north pole
+        assert!(imported.get(10, 0) < imported.get(10, 16));
+        assert!(imported.get(10, 16) < imported.get(10, 31));
+
+        // Previews share the loaded file instead of reading it again
+        std::fs::remove_file(path).unwrap();
+        let preview = gen.with_size(8, 4);
+        assert_eq!(preview.get_heightmap(&progress).unwrap().data.len(), 32);
+    }
+
+    #[test]
+    fn import_eight_bit_png() {
+        let dir = Scratch::new("png8");
+        let path = dir.0.join("hand-made.png");
+
+        // 4x2, black on the top row and white on the bottom
+        let file = std::fs::File::create(&path).unwrap();
+        let mut encoder = png::Encoder::new(file, 4, 2);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().unwrap();
+        writer
+            .write_image_data(&[0, 0, 0, 0, 255, 255, 255, 255])
+            .unwrap();
+        drop(writer);
+
+        // Without a sidecar gray levels span the amplitude
+        let settings = settings(0, 4, 2);
+        let gen = ImportGenerator::new(&settings).with_path(&path);
+        let map = gen.source().unwrap();
+        assert_eq!(map.get(0, 1), -settings.amplitude);
+        assert_eq!(map.get(0, 0), settings.amplitude);
+    }
+
+    #[test]
+    fn import_reports_errors() {
+        let dir = Scratch::new("errors");
+        let settings = settings(0, 8, 4);
+
+        let gen = ImportGenerator::new(&settings);
+        assert_eq!(gen.source(), Err(&ImportError::NoPath));
+        assert_eq!(
+            gen.get_heightmap(&Progress::default()),
+            Err(GenerationError::InvalidSettings(
+                ImportError::NoPath.to_string()
+            ))
+        );
+
+        let missing = dir.0.join("missing.png");
+        let gen = ImportGenerator::new(&settings).with_path(&missing);
+        assert_eq!(gen.source(), Err(&ImportError::Missing(missing)));
+        assert!(matches!(
+            gen.get_heightmap(&Progress::default()),
+            Err(GenerationError::Io(_))
+        ));
+
+        let odd = dir.0.join("odd.r32");
+        std::fs::write(&odd, [0u8; 5]).unwrap();
+        let gen = ImportGenerator::new(&settings).with_path(&odd);
+        assert!(matches!(gen.source(), Err(ImportError::Malformed(..))));
+        assert!(matches!(
+            gen.get_heightmap(&Progress::default()),
+            Err(GenerationError::InvalidSettings(_))
+        ));
+
+        // Three values can't be twice as wide as high
+        let short = dir.0.join("short.r32");
+        std::fs::write(&short, [0u8; 12]).unwrap();
+        let gen = ImportGenerator::new(&settings).with_path(&short);
+        assert!(matches!(gen.source(), Err(ImportError::Malformed(..))));
+
+        // A sidecar is read whole, and must be JSON
+        let sidecar = dir.0.join("short.json");
+        std::fs::write(&sidecar, "{\"width\": 3, \"height\": 1,").unwrap();
+        let gen = ImportGenerator::new(&settings).with_path(&short);
+        assert!(matches!(gen.source(), Err(ImportError::Malformed(path, _)) if *path == sidecar));
+        std::fs::write(&sidecar, "{\"width\": 3, \"height\": 1}").unwrap();
+        let gen = ImportGenerator::new(&settings).with_path(&short);
+        assert_eq!(gen.source().unwrap().data.len(), 3);
+
+        let broken = dir.0.join("broken.png");
+        std::fs::write(&broken, b"\x89PNG\r\n\x1a\nnot really").unwrap();
+        let gen = ImportGenerator::new(&settings).with_path(&broken);
+        let err = gen.source().unwrap_err();
+        assert!(matches!(err, ImportError::Malformed(..)));
+        assert!(err.to_string().contains("broken.png"));
+    }
+}
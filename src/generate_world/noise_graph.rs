@@ -0,0 +1,570 @@
+use std::sync::Arc;
+
+use bevy::{
+    asset::{AssetLoader, LoadContext, LoadedAsset},
+    math::Vec3,
+    reflect::TypeUuid,
+    utils::BoxedFuture,
+};
+use noise::{Billow, Fbm, MultiFractal, NoiseFn, OpenSimplex, RidgedMulti, Seedable, Worley};
+use serde::Deserialize;
+
+use super::{
+    generation::{bands, fill_rows, GenerationError, Progress, WorldGenSettings, WorldGenerator},
+    geo,
+    heightmap::Heightmap,
+};
+
+// Offsets of the three samples of the warping node, far enough apart to be unrelated
+const WARP_OFFSETS: [[f64; 3]; 3] = [[12.7, 4.1, 9.3], [3.9, 17.2, 1.6], [8.4, 2.8, 15.5]];
+
+fn default_octaves() -> usize {
+    6
+}
+
+// Node of a noise graph, sampled at points on the unit sphere. Sources give values in about
+// [-1, 1] and are seeded with the world seed plus their own `seed`, so that two sources of
+// the same kind can differ.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub(crate) enum NoiseNode {
+    OpenSimplex {
+        frequency: f64,
+        #[serde(default)]
+        seed: u32,
+    },
+    Perlin {
+        frequency: f64,
+        #[serde(default)]
+        seed: u32,
+    },
+    // Cellular noise, rising away from scattered feature points
+    Worley {
+        frequency: f64,
+        #[serde(default)]
+        seed: u32,
+    },
+    // Sharp crests where the noise crosses zero, for mountain ranges
+    RidgedMulti {
+        frequency: f64,
+        #[serde(default = "default_octaves")]
+        octaves: usize,
+        #[serde(default)]
+        seed: u32,
+    },
+    // Rounded lumps, for hills and dunes
+    Billow {
+        frequency: f64,
+        #[serde(default = "default_octaves")]
+        octaves: usize,
+        #[serde(default)]
+        seed: u32,
+    },
+    Constant(f64),
+    // `source` sampled at points pushed around by `by`, by up to `strength`
+    Warp {
+        source: Box<NoiseNode>,
+        by: Box<NoiseNode>,
+        strength: f64,
+    },
+    // Flattens `source` into steps between the given levels
+    Terrace {
+        source: Box<NoiseNode>,
+        steps: Vec<f64>,
+    },
+    Clamp {
+        source: Box<NoiseNode>,
+        min: f64,
+        max: f64,
+    },
+    // Maps `source` through straight lines between (input, output) points
+    Curve {
+        source: Box<NoiseNode>,
+        points: Vec<(f64, f64)>,
+    },
+    // `a` where `control` is -1, `b` where it is 1 and a mix in between
+    Blend {
+        a: Box<NoiseNode>,
+        b: Box<NoiseNode>,
+        control: Box<NoiseNode>,
+    },
+    // `a` where `control` is below `threshold`, `b` above, mixed within `falloff` of it
+    Select {
+        a: Box<NoiseNode>,
+        b: Box<NoiseNode>,
+        control: Box<NoiseNode>,
+        threshold: f64,
+        #[serde(default)]
+        falloff: f64,
+    },
+    ScaleBias {
+        source: Box<NoiseNode>,
+        scale: f64,
+        #[serde(default)]
+        bias: f64,
+    },
+}
+
+impl NoiseNode {
+    // Nodes this one reads from
+    fn inputs(&self) -> Vec<&NoiseNode> {
+        match self {
+            NoiseNode::Warp { source, by, .. } => vec![source, by],
+            NoiseNode::Terrace { source, .. }
+            | NoiseNode::Clamp { source, .. }
+            | NoiseNode::Curve { source, .. }
+            | NoiseNode::ScaleBias { source, .. } => vec![source],
+            NoiseNode::Blend { a, b, control } | NoiseNode::Select { a, b, control, .. } => {
+                vec![a, b, control]
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    // Seed the sources of the graph below this node for a world
+    fn sampler(&self, world_seed: u32) -> Sampler {
+        let seeded = |seed: &u32| world_seed.wrapping_add(*seed);
+        let source = |noise: Box<dyn NoiseFn<[f64; 3]> + Send + Sync>, frequency: &f64| {
+            Sampler::Source(noise, *frequency)
+        };
+
+        match self {
+            NoiseNode::OpenSimplex { frequency, seed } => source(
+                Box::new(OpenSimplex::new().set_seed(seeded(seed))),
+                frequency,
+            ),
+            // noise exports two different `Perlin`s, a single octave of Fbm is plain Perlin noise
+            NoiseNode::Perlin { frequency, seed } => source(
+                Box::new(Fbm::new().set_seed(seeded(seed)).set_octaves(1)),
+                frequency,
+            ),
+            NoiseNode::Worley { frequency, seed } => {
+                source(Box::new(Worley::new().set_seed(seeded(seed))), frequency)
+            }
+            NoiseNode::RidgedMulti {
+                frequency,
+                octaves,
+                seed,
+            } => source(
+                Box::new(
+                    RidgedMulti::new()
+                        .set_seed(seeded(seed))
+                        .set_octaves(*octaves),
+                ),
+                frequency,
+            ),
+            NoiseNode::Billow {
+                frequency,
+                octaves,
+                seed,
+            } => source(
+                Box::new(Billow::new().set_seed(seeded(seed)).set_octaves(*octaves)),
+                frequency,
+            ),
+            node => Sampler::Modifier(
+                node.clone(),
+                node.inputs()
+                    .into_iter()
+                    .map(|input| input.sampler(world_seed))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+// Graph with its sources seeded, ready to sample
+enum Sampler {
+    Source(Box<dyn NoiseFn<[f64; 3]> + Send + Sync>, f64),
+    // A node reading from other nodes, sampled in the order of `NoiseNode::inputs`
+    Modifier(NoiseNode, Vec<Sampler>),
+}
+
+impl Sampler {
+    fn sample(&self, point: [f64; 3]) -> f64 {
+        let (node, inputs) = match self {
+            Sampler::Source(noise, frequency) => return noise.get(point.map(|p| p * frequency)),
+            Sampler::Modifier(node, inputs) => (node, inputs),
+        };
+        let input = |i: usize| inputs[i].sample(point);
+
+        match node {
+            NoiseNode::Constant(value) => *value,
+            NoiseNode::Warp { strength, .. } => {
+                let mut warped = point;
+                for (p, offset) in warped.iter_mut().zip(WARP_OFFSETS) {
+                    let shifted = [
+                        point[0] + offset[0],
+                        point[1] + offset[1],
+                        point[2] + offset[2],
+                    ];
+                    *p += inputs[1].sample(shifted) * strength;
+                }
+                inputs[0].sample(warped)
+            }
+            NoiseNode::Terrace { steps, .. } => terrace(input(0), steps),
+            NoiseNode::Clamp { min, max, .. } => input(0).clamp(*min, *max),
+            NoiseNode::Curve { points, .. } => curve(input(0), points),
+            NoiseNode::Blend { .. } => {
+                let t = (input(2) + 1.0) / 2.0;
+                input(0) * (1.0 - t) + input(1) * t
+            }
+            NoiseNode::Select {
+                threshold, falloff, ..
+            } => {
+                let control = input(2);
+                if control <= threshold - falloff {
+                    input(0)
+                } else if control >= threshold + falloff {
+                    input(1)
+                } else {
+                    let t = smoothstep((control - threshold + falloff) / (2.0 * falloff));
+                    input(0) * (1.0 - t) + input(1) * t
+                }
+            }
+            NoiseNode::ScaleBias { scale, bias, .. } => input(0) * scale + bias,
+            // Sources are seeded into `Sampler::Source`
+            _ => 0.0,
+        }
+    }
+}
+
+fn smoothstep(t: f64) -> f64 {
+    t * t * (3.0 - 2.0 * t)
+}
+
+// Eases from each step up to the next, so that values bunch up on the steps
+fn terrace(value: f64, steps: &[f64]) -> f64 {
+    let upper = match steps.iter().position(|step| *step > value) {
+        Some(0) | None => return value.clamp(steps[0], steps[steps.len() - 1]),
+        Some(upper) => upper,
+    };
+    let (low, high) = (steps[upper - 1], steps[upper]);
+    let t = (value - low) / (high - low);
+    low + (high - low) * t * t
+}
+
+fn curve(value: f64, points: &[(f64, f64)]) -> f64 {
+    let upper = match points.iter().position(|(input, _)| *input > value) {
+        Some(0) => return points[0].1,
+        None => return points[points.len() - 1].1,
+        Some(upper) => upper,
+    };
+    let ((x0, y0), (x1, y1)) = (points[upper - 1], points[upper]);
+    y0 + (y1 - y0) * (value - x0) / (x1 - x0)
+}
+
+// Elevation described as a graph of noise sources and modifiers, read from `.worldgen.ron`
+// assets. The graph's output is taken to span [-1, 1] and scaled to the amplitude.
+#[derive(Debug, Clone, PartialEq, Deserialize, TypeUuid)]
+#[uuid = "8d4f0c27-5b3e-4a61-9f7d-2e6b1c8a0f53"]
+pub(crate) struct NoiseGraph {
+    pub root: NoiseNode,
+}
+
+impl NoiseGraph {
+    // Sort the steps and points of terraces and curves, rejecting any too short to sample
+    fn validate(mut self) -> Result<Self, String> {
+        fn check(node: &mut NoiseNode) -> Result<(), String> {
+            match node {
+                NoiseNode::Terrace { steps, .. } => {
+                    steps.sort_by(f64::total_cmp);
+                    if steps.len() < 2 {
+                        return Err("Terrace needs at least two steps".into());
+                    }
+                }
+                NoiseNode::Curve { points, .. } => {
+                    points.sort_by(|a, b| a.0.total_cmp(&b.0));
+                    if points.len() < 2 {
+                        return Err("Curve needs at least two points".into());
+                    }
+                }
+                _ => {}
+            }
+
+            match node {
+                NoiseNode::Warp { source, by, .. } => {
+                    check(source)?;
+                    check(by)
+                }
+                NoiseNode::Terrace { source, .. }
+                | NoiseNode::Clamp { source, .. }
+                | NoiseNode::Curve { source, .. }
+                | NoiseNode::ScaleBias { source, .. } => check(source),
+                NoiseNode::Blend { a, b, control } | NoiseNode::Select { a, b, control, .. } => {
+                    check(a)?;
+                    check(b)?;
+                    check(control)
+                }
+                _ => Ok(()),
+            }
+        }
+
+        check(&mut self.root)?;
+        Ok(self)
+    }
+
+    pub fn from_ron(bytes: &[u8]) -> Result<Self, String> {
+        ron::de::from_bytes::<NoiseGraph>(bytes)
+            .map_err(|err| err.to_string())?
+            .validate()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub(crate) struct NoiseGraphLoader;
+
+impl AssetLoader for NoiseGraphLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let graph = NoiseGraph::from_ron(bytes).map_err(bevy::asset::Error::msg)?;
+            load_context.set_default_asset(LoadedAsset::new(graph));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["worldgen.ron"]
+    }
+}
+
+// Raises the world from a noise graph
+#[derive(Clone)]
+pub(super) struct NoiseGraphGenerator {
+    settings: WorldGenSettings,
+    sampler: Arc<Sampler>,
+    // The graph written out, as graphs are edited under the same generator name
+    fingerprint: Arc<str>,
+}
+
+impl NoiseGraphGenerator {
+    pub fn new(settings: &WorldGenSettings, graph: &NoiseGraph) -> Self {
+        NoiseGraphGenerator {
+            settings: *settings,
+            sampler: Arc::new(graph.root.sampler(settings.seed)),
+            fingerprint: format!("{:?}", graph.root).into(),
+        }
+    }
+}
+
+impl NoiseGraphGenerator {
+    // Elevation at a point on the unit sphere
+    fn elevation(&self, point: Vec3) -> f32 {
+        let value = self
+            .sampler
+            .sample([point.x as f64, point.y as f64, point.z as f64]);
+        value.clamp(-1.0, 1.0) as f32 * self.settings.amplitude
+    }
+}
+
+impl WorldGenerator for NoiseGraphGenerator {
+    fn get_heightmap(&self, progress: &Progress) -> Result<Heightmap, GenerationError> {
+        let WorldGenSettings { width, height, .. } = self.settings;
+        let mut data = vec![0.0; (width * height) as usize];
+
+        progress.start("elevation", height);
+        fill_rows(&mut data, width, bands(), progress, |y, row| {
+            let latitude = geo::latitude(y as f32, height);
+            for (x, texel) in row.iter_mut().enumerate() {
+                let point = geo::direction(latitude, geo::longitude(x as f32, width));
+                *texel = self.elevation(point);
+            }
+        })?;
+
+        Ok(Heightmap::new(width, height, data))
+    }
+
+    fn with_size(&self, width: u32, height: u32) -> Box<dyn WorldGenerator> {
+        Box::new(NoiseGraphGenerator {
+            settings: self.settings.resized(width, height),
+            sampler: self.sampler.clone(),
+            fingerprint: self.fingerprint.clone(),
+        })
+    }
+
+    fn fingerprint(&self) -> Option<String> {
+        Some(self.fingerprint.to_string())
+    }
+
+    fn sample(&self, point: Vec3) -> Option<f32> {
+        Some(self.elevation(point))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{
+        fixtures::settings,
+        generation::{Progress, WorldGenerator},
+    };
+    use super::{NoiseGraph, NoiseGraphGenerator, NoiseNode};
+
+    fn sample(node: NoiseNode) -> f64 {
+        node.sampler(0).sample([0.3, -0.5, 0.8])
+    }
+
+    #[test]
+    fn graph_reads_ron() {
+        let graph = NoiseGraph::from_ron(
+            br#"(
+                root: Select(
+                    a: ScaleBias(source: Billow(frequency: 1.5), scale: 0.5, bias: -0.2),
+                    b: Warp(
+                        source: RidgedMulti(frequency: 2.0, octaves: 4, seed: 1),
+                        by: Perlin(frequency: 3.0),
+                        strength: 0.1,
+                    ),
+                    control: OpenSimplex(frequency: 1.0),
+                    threshold: 0.1,
+                ),
+            )"#,
+        )
+        .unwrap();
+
+        match &graph.root {
+            NoiseNode::Select {
+                a,
+                threshold,
+                falloff,
+                ..
+            } => {
+                assert_eq!(*threshold, 0.1);
+                assert_eq!(*falloff, 0.0);
+                assert!(matches!(**a, NoiseNode::ScaleBias { bias, .. } if bias == -0.2));
+            }
+            other => panic!("Read {:?}", other),
+        }
+
+        assert!(NoiseGraph::from_ron(b"(root: Sparkles(frequency: 1.0))").is_err());
+        assert!(
+            NoiseGraph::from_ron(b"(root: Terrace(source: Constant(0.0), steps: [0.5]))").is_err()
+        );
+    }
+
+    #[test]
+    fn graph_modifiers() {
+        use NoiseNode::*;
+
+        let constant = |value| Box::new(Constant(value));
+        assert_eq!(
+            sample(ScaleBias {
+                source: constant(0.5),
+                scale: 2.0,
+                bias: 0.25
+            }),
+            1.25
+        );
+        assert_eq!(
+            sample(Clamp {
+                source: constant(0.9),
+                min: -0.5,
+                max: 0.5
+            }),
+            0.5
+        );
+        assert_eq!(
+            sample(Curve {
+                source: constant(0.25),
+                points: vec![(0.0, 0.0), (0.5, 1.0)]
+            }),
+            0.5
+        );
+        assert_eq!(
+            sample(Blend {
+                a: constant(0.0),
+                b: constant(1.0),
+                control: constant(0.0)
+            }),
+            0.5
+        );
+
+        // Terraces ease from one step to the next, and hold still beyond the last
+        let terrace = |value| {
+            sample(Terrace {
+                source: constant(value),
+                steps: vec![0.0, 1.0],
+            })
+        };
+        assert_eq!(terrace(0.5), 0.25);
+        assert_eq!(terrace(1.5), 1.0);
+
+        let select = |control| {
+            sample(Select {
+                a: constant(-1.0),
+                b: constant(1.0),
+                control: constant(control),
+                threshold: 0.0,
+                falloff: 0.2,
+            })
+        };
+        assert_eq!(select(-0.5), -1.0);
+        assert_eq!(select(0.5), 1.0);
+        assert_eq!(select(0.0), 0.0);
+    }
+
+    #[test]
+    fn graph_sources_follow_seed() {
+        use NoiseNode::*;
+
+        for node in [
+            OpenSimplex {
+                frequency: 2.0,
+                seed: 0,
+            },
+            Perlin {
+                frequency: 2.3,
+                seed: 0,
+            },
+            Worley {
+                frequency: 2.0,
+                seed: 0,
+            },
+            RidgedMulti {
+                frequency: 2.0,
+                octaves: 3,
+                seed: 0,
+            },
+            Billow {
+                frequency: 2.3,
+                octaves: 3,
+                seed: 0,
+            },
+        ] {
+            let values = |seed| {
+                let sampler = node.sampler(seed);
+                (0..16)
+                    .map(|i| sampler.sample([i as f64 * 0.1, 0.4, -0.3]))
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(values(1), values(1), "{:?}", node);
+            assert_ne!(values(1), values(2), "{:?}", node);
+            assert!(values(1).iter().all(|v| v.is_finite()));
+        }
+    }
+
+    #[test]
+    fn graph_generator_raises_world() {
+        let graph =
+            NoiseGraph::from_ron(include_bytes!("../../assets/worldgen/default.worldgen.ron"))
+                .unwrap();
+        let settings = settings(3, 64, 32);
+        let gen = NoiseGraphGenerator::new(&settings, &graph);
+
+        let progress = Progress::default();
+        let map = gen.get_heightmap(&progress).unwrap();
+        assert_eq!(map.data.len(), 64 * 32);
+        assert_eq!(progress.rows_done(), 32);
+        assert!(map.data.iter().all(|f| f.abs() <= settings.amplitude));
+        assert!(map.data.iter().any(|f| *f > 0.0) && map.data.iter().any(|f| *f < 0.0));
+
+        let again = NoiseGraphGenerator::new(&settings, &graph)
+            .get_heightmap(&Progress::default())
+            .unwrap();
+        assert_eq!(map, again);
+
+        let preview = gen.with_size(16, 8).get_heightmap(&progress).unwrap();
+        assert_eq!((preview.width, preview.height), (16, 8));
+    }
+}
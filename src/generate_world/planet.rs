@@ -0,0 +1,464 @@
+use std::{
+    f32::consts::{FRAC_PI_2, PI},
+    sync::Arc,
+};
+
+use bevy::prelude::*;
+
+use super::{
+    generation::MapLayout,
+    geo,
+    heightmap::{ElevationFormat, Heightmap},
+    water::{Water, WaterMap},
+    world_data::{Layer, WorldData},
+};
+
+// How samples are interpolated between texels
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Filter {
+    // Between the four nearest texels, as the GPU samples the elevation texture
+    Bilinear,
+    // Catmull-Rom through the sixteen nearest texels, smooth across texel edges
+    Bicubic,
+}
+
+// Texels of a map, sampled at texture coordinates the way a GPU sampler does
+#[derive(Debug, Clone, PartialEq)]
+struct Grid {
+    map: Arc<Heightmap>,
+    // Columns wrap around rather than clamp to the edge, as rows always do
+    wrap: bool,
+}
+
+impl Grid {
+    fn texel(&self, x: i64, y: i64) -> f32 {
+        let (width, height) = (self.map.width as i64, self.map.height as i64);
+        let x = if self.wrap {
+            x.rem_euclid(width)
+        } else {
+            x.clamp(0, width - 1)
+        };
+        let y = y.clamp(0, height - 1);
+        self.map.data[(y * width + x) as usize]
+    }
+
+    fn sample(&self, uv: Vec2, filter: Filter) -> f32 {
+        let (x, y) = (
+            uv.x * self.map.width as f32 - 0.5,
+            uv.y * self.map.height as f32 - 0.5,
+        );
+        let (x0, y0) = (x.floor(), y.floor());
+        let (u, v) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+
+        match filter {
+            Filter::Bilinear => {
+                let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+                lerp(
+                    lerp(self.texel(x0, y0), self.texel(x0 + 1, y0), u),
+                    lerp(self.texel(x0, y0 + 1), self.texel(x0 + 1, y0 + 1), u),
+                    v,
+                )
+            }
+            Filter::Bicubic => {
+                let row = |y| catmull_rom([-1, 0, 1, 2].map(|dx| self.texel(x0 + dx, y)), u);
+                catmull_rom([-1, 0, 1, 2].map(|dy| row(y0 + dy)), v)
+            }
+        }
+    }
+}
+
+// Cubic through the middle two of four evenly spaced values, `t` of the way from the second
+fn catmull_rom([p0, p1, p2, p3]: [f32; 4], t: f32) -> f32 {
+    p1 + 0.5
+        * t
+        * (p2 - p0 + t * (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3 + t * (3.0 * (p1 - p2) + p3 - p0)))
+}
+
+// The generated world for gameplay to query on the CPU: its layers sampled by direction or by
+// latitude and longitude, and the surface the vertex shader raises the sphere to. Elevation is
+// decoded from the texture handed to the material and filtered as the shader does, so points on
+// the surface land where the player sees them. The other layers and the water are shared with
+// the world data and the water map, rather than copied.
+#[derive(Debug, Clone)]
+pub struct Planet {
+    // Radius of the sphere elevations are measured from
+    pub radius: f32,
+    pub layout: MapLayout,
+    pub format: ElevationFormat,
+    // Elevation texels as the shader samples them, before they are decoded
+    elevation: Grid,
+    temperature: Grid,
+    moisture: Grid,
+    // Oceans and lakes, lying flat at the sea level they were classified with
+    water: Option<WaterMap>,
+}
+
+impl Planet {
+    // Planet shown by a material given `elevation`, a texture laid out as `layout` and stored
+    // as `format`
+    pub(crate) fn new(
+        data: &WorldData,
+        elevation: &Image,
+        layout: MapLayout,
+        format: ElevationFormat,
+        radius: f32,
+    ) -> Self {
+        let size = elevation.texture_descriptor.size;
+        let texels = elevation
+            .data
+            .chunks_exact(format.size())
+            .map(|bytes| format.read(bytes))
+            .collect();
+
+        Planet {
+            radius,
+            layout,
+            format,
+            elevation: Grid {
+                map: Arc::new(Heightmap::new(size.width, size.height, texels)),
+                // Textures use the default sampler, clamping at the edges
+                wrap: false,
+            },
+            temperature: Grid {
+                map: data.temperature.clone(),
+                wrap: true,
+            },
+            moisture: Grid {
+                map: data.moisture.clone(),
+                wrap: true,
+            },
+            water: None,
+        }
+    }
+
+    // Flatten oceans and lakes to the sea level they were classified with, as the shader does.
+    // The cells are shared with `water`.
+    pub(crate) fn set_water(&mut self, water: &WaterMap) {
+        self.water = Some(water.clone());
+    }
+
+    // Value of a layer at a point on the unit sphere. Elevation is as stored in the texture,
+    // without water flattened to the sea level.
+    pub fn sample(&self, layer: Layer, direction: Vec3, filter: Filter) -> f32 {
+        let direction = direction.normalize();
+        match layer {
+            Layer::Elevation => {
+                let uv = match self.layout {
+                    MapLayout::Equirectangular => geo::sphere_uv(direction),
+                    MapLayout::CubeSphere => geo::cube_uv(
+                        direction,
+                        (self.elevation.map.width / geo::CUBE_COLUMNS) as f32,
+                    ),
+                };
+                let decode = self.format.decode();
+                decode.x + self.elevation.sample(uv, filter) * decode.y
+            }
+            Layer::Temperature => self.temperature.sample(geo::sphere_uv(direction), filter),
+            Layer::Moisture => self.moisture.sample(geo::sphere_uv(direction), filter),
+        }
+    }
+
+    // Value of a layer at a latitude and longitude in radians
+    pub fn sample_lat_lon(
+        &self,
+        layer: Layer,
+        latitude: f32,
+        longitude: f32,
+        filter: Filter,
+    ) -> f32 {
+        self.sample(layer, geo::direction(latitude, longitude), filter)
+    }
+
+    // Whether a point on the unit sphere is under an ocean or lake, as the shader tells them
+    pub fn is_water(&self, direction: Vec3) -> bool {
+        let water = match &self.water {
+            Some(water) => water,
+            None => return false,
+        };
+
+        // The water mask is sampled at the nearest texel
+        let uv = geo::sphere_uv(direction.normalize());
+        let x = ((uv.x * water.width as f32).floor() as i64).clamp(0, water.width as i64 - 1);
+        let y = ((uv.y * water.height as f32).floor() as i64).clamp(0, water.height as i64 - 1);
+        water.cells[(y * water.width as i64 + x) as usize] != Water::Land
+    }
+
+    // Elevation the vertex shader moves a point on the unit sphere out by, water lying flat
+    pub fn surface_elevation(&self, direction: Vec3) -> f32 {
+        let elevation = self.sample(Layer::Elevation, direction, Filter::Bilinear);
+        match &self.water {
+            Some(water) if self.is_water(direction) => elevation.max(water.sea_level),
+            _ => elevation,
+        }
+    }
+
+    // Point on the surface above a point on the unit sphere, relative to the planet's centre
+    pub fn surface_point(&self, direction: Vec3) -> Vec3 {
+        let direction = direction.normalize();
+        direction * (self.radius + self.surface_elevation(direction))
+    }
+
+    // Angle between neighbouring elevation texels, seen from the centre
+    fn texel_angle(&self) -> f32 {
+        match self.layout {
+            MapLayout::Equirectangular => PI / self.elevation.map.height as f32,
+            MapLayout::CubeSphere => {
+                FRAC_PI_2 * geo::CUBE_COLUMNS as f32 / self.elevation.map.width as f32
+            }
+        }
+    }
+
+    // Outward normal of the surface above a point on the unit sphere, from the surface a texel
+    // away on every side
+    pub fn normal(&self, direction: Vec3) -> Vec3 {
+        let direction = direction.normalize();
+        let axis = if direction.z.abs() < 0.9 {
+            Vec3::Z
+        } else {
+            Vec3::X
+        };
+        let east = direction.cross(axis).normalize();
+        let north = direction.cross(east);
+
+        let step = self.texel_angle();
+        let at = |offset: Vec3| self.surface_point(direction + offset * step);
+        let normal = (at(east) - at(-east))
+            .cross(at(north) - at(-north))
+            .normalize_or_zero();
+
+        if normal.dot(direction) < 0.0 {
+            -normal
+        } else {
+            normal
+        }
+    }
+
+    // Steepness of the surface above a point on the unit sphere, in radians from level
+    pub fn slope(&self, direction: Vec3) -> f32 {
+        let direction = direction.normalize();
+        self.normal(direction)
+            .dot(direction)
+            .clamp(-1.0, 1.0)
+            .acos()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::super::{
+        generation::MapLayout,
+        heightmap::{ElevationFormat, Heightmap},
+        world_data::WorldData,
+    };
+    use super::{Filter, Layer, Planet};
+    use bevy::prelude::*;
+
+    fn planet(map: Heightmap, layout: MapLayout, format: ElevationFormat) -> Planet {
+        let image = match layout {
+            MapLayout::Equirectangular => map.to_image(format),
+            MapLayout::CubeSphere => map.to_cube_image(format),
+        };
+        Planet::new(&WorldData::new(map), &image, layout, format, 3.0)
+    }
+
+    fn waves(width: u32, height: u32) -> Heightmap {
+        Heightmap::new(
+            width,
+            height,
+            (0..width * height)
+                .map(|i| 0.5 * (i as f32 * 0.37).sin())
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn texel_centres_sample_their_texel() {
+        let map = waves(48, 24);
+        let planet = planet(
+            map.clone(),
+            MapLayout::Equirectangular,
+            ElevationFormat::Float32,
+        );
+
+        for y in 0..map.height {
+            for x in 0..map.width {
+                let (lat, lon) = (map.latitude(y as f32), map.longitude(x as f32));
+                let expected = map.get(x as isize, y as isize);
+                for filter in [Filter::Bilinear, Filter::Bicubic] {
+                    let sampled = planet.sample_lat_lon(Layer::Elevation, lat, lon, filter);
+                    assert!((sampled - expected).abs() < 1e-4);
+                }
+            }
+        }
+
+        // Halfway between two texels is their mean, and the seam clamps like the GPU sampler
+        let between = planet.sample(Layer::Elevation, map.direction(10.5, 7.0), Filter::Bilinear);
+        assert!((between - (map.get(10, 7) + map.get(11, 7)) / 2.0).abs() < 1e-4);
+        let seam = planet.sample(
+            Layer::Elevation,
+            map.direction(map.width as f32 - 0.75, 7.0),
+            Filter::Bilinear,
+        );
+        assert!((seam - map.get(map.width as isize - 1, 7)).abs() < 1e-4);
+    }
+
+    // What the vertex shader does with a vertex of the sphere, written out texel by texel
+    #[test]
+    fn surface_matches_vertex_shader() {
+        use super::super::{fixtures::Shader, generation::Progress, geo, water::WaterMap};
+
+        let map = waves(64, 32);
+        let format = ElevationFormat::Unorm16 {
+            min: -0.5,
+            max: 0.5,
+        };
+        let image = map.to_image(format);
+        let mut planet = planet(map.clone(), MapLayout::Equirectangular, format);
+        let water = WaterMap::classify(&map, 0.1, &Progress::default()).unwrap();
+        planet.set_water(&water);
+
+        let shader = Shader::load("shaders/geo.wgsl");
+        let texel = |x: i64, y: i64| {
+            let (x, y) = (x.clamp(0, 63) as usize, y.clamp(0, 31) as usize);
+            let bytes = &image.data[(y * 64 + x) * 2..][..2];
+            u16::from_le_bytes([bytes[0], bytes[1]]) as f32 / u16::MAX as f32
+        };
+        for i in 0..500 {
+            let vertex = geo::direction((i as f32 * 0.71).sin() * 1.5, i as f32 * 2.3 % 6.2 - 3.1);
+
+            let uv = shader.call("sphere_uv", &[&vertex.to_array()]);
+            let uv = Vec2::new(uv[0], uv[1]);
+            let (x, y) = (uv.x * 64.0 - 0.5, uv.y * 32.0 - 0.5);
+            let (x0, y0) = (x.floor() as i64, y.floor() as i64);
+            let (u, v) = (x - x.floor(), y - y.floor());
+            let sampled = (texel(x0, y0) * (1.0 - u) + texel(x0 + 1, y0) * u) * (1.0 - v)
+                + (texel(x0, y0 + 1) * (1.0 - u) + texel(x0 + 1, y0 + 1) * u) * v;
+            let mut elevation = -0.5 + sampled * 1.0;
+            let mask = water.cells[(uv.y * 32.0).floor().min(31.0) as usize * 64
+                + (uv.x * 64.0).floor().min(63.0) as usize];
+            if mask != super::Water::Land {
+                elevation = elevation.max(0.1);
+            }
+
+            let expected = vertex * (3.0 + elevation);
+            assert!((planet.surface_point(vertex) - expected).length() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn cube_sphere_samples_its_atlas() {
+        use super::super::{fixtures::Shader, geo};
+
+        let map = waves(96, 48);
+        let planet = planet(map.clone(), MapLayout::CubeSphere, ElevationFormat::Float32);
+        let size = geo::cube_size(96, 48);
+        let shader = Shader::load("shaders/geo.wgsl");
+
+        // Atlas texel centres hold the map interpolated at their direction
+        for face in 0..6 {
+            for (x, y) in [(3, 5), (size / 2, size / 2), (size - 4, 2)] {
+                let (u, v) = (
+                    (x as f32 + 0.5) / size as f32,
+                    (y as f32 + 0.5) / size as f32,
+                );
+                let direction = geo::cube_direction(face, u, v);
+                let sampled = planet.sample(Layer::Elevation, direction, Filter::Bilinear);
+                assert!((sampled - map.interpolate(direction)).abs() < 1e-4);
+
+                // The vertex shader finds the same texel, given the atlas' width as the material
+                // shader divides it
+                let width = (planet.elevation.map.width / geo::CUBE_COLUMNS) as f32;
+                let uv = shader.call("cube_uv", &[&direction.to_array(), &[width]]);
+                let shaded = planet
+                    .elevation
+                    .sample(Vec2::new(uv[0], uv[1]), Filter::Bilinear);
+                assert!((shaded - sampled).abs() < 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn normals_follow_the_slope() {
+        // Flat ground faces straight out
+        let flat = planet(
+            Heightmap::new(64, 32, vec![0.2; 64 * 32]),
+            MapLayout::Equirectangular,
+            ElevationFormat::Float32,
+        );
+        let up = Vec3::new(0.6, 0.0, 0.8);
+        assert!((flat.normal(up) - up).length() < 1e-3);
+        assert!(flat.slope(up) < 1e-3);
+
+        // Ground rising towards the north pole leans south, by the rise over the radius
+        let rise = 0.5;
+        let mut map = Heightmap::new(128, 64, vec![0.0; 128 * 64]);
+        for y in 0..64 {
+            let latitude = map.latitude(y as f32);
+            for x in 0..128 {
+                map.data[y as usize * 128 + x] = rise * latitude;
+            }
+        }
+        let tilted = planet(map, MapLayout::Equirectangular, ElevationFormat::Float32);
+        let equator = Vec3::X;
+        let slope = tilted.slope(equator);
+        assert!(
+            (slope - (rise / 3.0).atan()).abs() < 0.01,
+            "Slope was {}",
+            slope
+        );
+        assert!(tilted.normal(equator).z < 0.0);
+    }
+
+    #[test]
+    fn planet_shares_layers() {
+        use super::super::{generation::Progress, water::WaterMap};
+
+        let data = WorldData::new(waves(32, 16));
+        let mut planet = Planet::new(
+            &data,
+            &data.elevation.to_image(ElevationFormat::Float32),
+            MapLayout::Equirectangular,
+            ElevationFormat::Float32,
+            3.0,
+        );
+        assert!(Arc::ptr_eq(&planet.temperature.map, &data.temperature));
+        assert!(Arc::ptr_eq(&planet.moisture.map, &data.moisture));
+
+        let water = WaterMap::classify(&data.elevation, 0.0, &Progress::default()).unwrap();
+        planet.set_water(&water);
+        assert!(Arc::ptr_eq(&planet.water.unwrap().cells, &water.cells));
+    }
+
+    #[test]
+    fn climate_layers_wrap_around() {
+        let mut data = WorldData::new(Heightmap::new(32, 16, vec![0.0; 32 * 16]));
+        for (i, texel) in Arc::make_mut(&mut data.temperature)
+            .data
+            .iter_mut()
+            .enumerate()
+        {
+            *texel = (i % 32) as f32;
+        }
+        let map = data.elevation.clone();
+        let planet = Planet::new(
+            &data,
+            &map.to_image(ElevationFormat::Float32),
+            MapLayout::Equirectangular,
+            ElevationFormat::Float32,
+            3.0,
+        );
+
+        // Across the seam, temperature blends the last column with the first
+        let seam = planet.sample(
+            Layer::Temperature,
+            map.direction(31.5, 8.0),
+            Filter::Bilinear,
+        );
+        assert!((seam - 15.5).abs() < 1e-3);
+        let inside = planet.sample(Layer::Temperature, map.direction(4.0, 8.0), Filter::Bicubic);
+        assert!((inside - 4.0).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,660 @@
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use super::{
+    continents::ContinentGenerator,
+    craters::CraterGenerator,
+    generation::{SimplexGenerator, WorldGenSettings, WorldGenerator},
+    import::ImportGenerator,
+    noise_graph::{NoiseGraph, NoiseGraphGenerator},
+    tectonics::TectonicGenerator,
+};
+
+// Noise graph built into the game, raising worlds until assets/worldgen/default.worldgen.ron has
+// loaded
+const BUNDLED_NOISE_GRAPH: &[u8] = include_bytes!("../../assets/worldgen/default.worldgen.ron");
+
+// Value given to a generator parameter
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParamValue {
+    Int(i64),
+    Float(f32),
+    Text(String),
+}
+
+impl ParamValue {
+    // Read text, e.g. from the command line, as a value of the same kind as this one
+    fn parse_like(&self, text: &str) -> Option<ParamValue> {
+        match self {
+            ParamValue::Int(_) => text.parse().ok().map(ParamValue::Int),
+            ParamValue::Float(_) => text.parse().ok().map(ParamValue::Float),
+            ParamValue::Text(_) => Some(ParamValue::Text(text.into())),
+        }
+    }
+
+    fn same_kind(&self, other: &ParamValue) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+}
+
+impl fmt::Display for ParamValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParamValue::Int(value) => write!(f, "{}", value),
+            ParamValue::Float(value) => write!(f, "{:?}", value),
+            ParamValue::Text(value) => write!(f, "{:?}", value),
+        }
+    }
+}
+
+// Parameter a generator takes on top of the `WorldGenSettings` shared by all generators
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParamSchema {
+    pub name: &'static str,
+    pub description: &'static str,
+    // Taken when no value is given, and the kind of value expected
+    pub default: ParamValue,
+}
+
+// Values of a generator's parameters, by name
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GeneratorParams(pub HashMap<String, ParamValue>);
+
+impl GeneratorParams {
+    pub fn with(mut self, name: impl Into<String>, value: ParamValue) -> Self {
+        self.0.insert(name.into(), value);
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ParamValue> {
+        self.0.get(name)
+    }
+
+    pub fn int(&self, name: &str) -> Option<i64> {
+        match self.get(name)? {
+            ParamValue::Int(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn float(&self, name: &str) -> Option<f32> {
+        match self.get(name)? {
+            ParamValue::Float(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn text(&self, name: &str) -> Option<&str> {
+        match self.get(name)? {
+            ParamValue::Text(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RegistryError {
+    UnknownGenerator(String),
+    UnknownParam(&'static str, String),
+    // Generator, parameter and the value it was given
+    InvalidParam(&'static str, &'static str, ParamValue),
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegistryError::UnknownGenerator(name) => write!(f, "No generator is called {}", name),
+            RegistryError::UnknownParam(generator, param) => {
+                write!(f, "Generator {} has no parameter {}", generator, param)
+            }
+            RegistryError::InvalidParam(generator, param, value) => write!(
+                f,
+                "{} is not a valid {} for generator {}",
+                value, param, generator
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RegistryError {}
+
+type Factory =
+    Arc<dyn Fn(&WorldGenSettings, &GeneratorParams) -> Box<dyn WorldGenerator> + Send + Sync>;
+
+// Generator known to the registry, with the parameters it takes
+#[derive(Clone)]
+pub struct GeneratorEntry {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub params: Vec<ParamSchema>,
+    factory: Factory,
+}
+
+impl fmt::Debug for GeneratorEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GeneratorEntry")
+            .field("name", &self.name)
+            .field("description", &self.description)
+            .field("params", &self.params)
+            .finish_non_exhaustive()
+    }
+}
+
+impl GeneratorEntry {
+    // Check values against the schema, reading text as the kind of value expected,
+    // and fill in defaults for the parameters left out
+    pub fn resolve(&self, params: &GeneratorParams) -> Result<GeneratorParams, RegistryError> {
+        if let Some(name) = params.0.keys().find(|name| {
+            self.params
+                .iter()
+                .all(|schema| schema.name != name.as_str())
+        }) {
+            return Err(RegistryError::UnknownParam(self.name, name.clone()));
+        }
+
+        let mut resolved = GeneratorParams::default();
+        for schema in &self.params {
+            let value = match params.get(schema.name) {
+                None => schema.default.clone(),
+                Some(value) if value.same_kind(&schema.default) => value.clone(),
+                Some(ParamValue::Text(text)) => {
+                    schema.default.parse_like(text).ok_or_else(|| {
+                        RegistryError::InvalidParam(
+                            self.name,
+                            schema.name,
+                            ParamValue::Text(text.clone()),
+                        )
+                    })?
+                }
+                Some(value) => {
+                    return Err(RegistryError::InvalidParam(
+                        self.name,
+                        schema.name,
+                        value.clone(),
+                    ))
+                }
+            };
+            resolved = resolved.with(schema.name, value);
+        }
+
+        Ok(resolved)
+    }
+}
+
+// Generators the world can be raised with, by name, in the order they were registered.
+// Crates building on the game register their own on this resource after adding its plugin.
+#[derive(Debug, Clone)]
+pub struct GeneratorRegistry {
+    entries: Vec<GeneratorEntry>,
+}
+
+impl Default for GeneratorRegistry {
+    // The generators coming with the game
+    fn default() -> Self {
+        let mut registry = GeneratorRegistry {
+            entries: Vec::new(),
+        };
+
+        let graph =
+            NoiseGraph::from_ron(BUNDLED_NOISE_GRAPH).expect("Bundled noise graph is invalid");
+        registry
+            .register_graph(graph)
+            .register(
+                "simplex",
+                "Octaves of simplex noise over the sphere",
+                Vec::new(),
+                |settings, _| Box::new(SimplexGenerator::new(settings)),
+            )
+            .register(
+                "tectonic",
+                "Continents raised where tectonic plates meet",
+                vec![ParamSchema {
+                    name: "plates",
+                    description: "Number of tectonic plates",
+                    default: ParamValue::Int(12),
+                }],
+                |settings, params| {
+                    let plates = params.int("plates").unwrap_or_default();
+                    Box::new(TectonicGenerator::new(
+                        settings,
+                        plates.clamp(1, u16::MAX as i64) as u32,
+                    ))
+                },
+            )
+            .register(
+                "continents",
+                "Continents and island chains laid out from a preset",
+                vec![
+                    ParamSchema {
+                        name: "preset",
+                        description: "One of continents, pangaea, two-continents or archipelago",
+                        default: ParamValue::Text("continents".into()),
+                    },
+                    ParamSchema {
+                        name: "continents",
+                        description: "Number of continents, or -1 for the preset's",
+                        default: ParamValue::Int(-1),
+                    },
+                    ParamSchema {
+                        name: "island_chains",
+                        description: "Number of island chains, or -1 for the preset's",
+                        default: ParamValue::Int(-1),
+                    },
+                ],
+                |settings, params| {
+                    let count = |name| {
+                        params
+                            .int(name)
+                            .filter(|count| *count >= 0)
+                            .map(|count| count.min(u16::MAX as i64) as u32)
+                    };
+                    Box::new(ContinentGenerator::new(
+                        settings,
+                        params.text("preset").unwrap_or_default(),
+                        count("continents"),
+                        count("island_chains"),
+                    ))
+                },
+            )
+            .register(
+                "craters",
+                "Airless moon or asteroid battered by impacts of every size",
+                vec![
+                    ParamSchema {
+                        name: "craters",
+                        description: "Number of craters",
+                        default: ParamValue::Int(3000),
+                    },
+                    ParamSchema {
+                        name: "exponent",
+                        description: "Slope of the cumulative crater size distribution",
+                        default: ParamValue::Float(2.0),
+                    },
+                ],
+                |settings, params| {
+                    let craters = params.int("craters").unwrap_or_default();
+                    Box::new(CraterGenerator::new(
+                        settings,
+                        craters.clamp(0, 1_000_000) as u32,
+                        params.float("exponent").unwrap_or_default(),
+                    ))
+                },
+            )
+            .register(
+                "import",
+                "Heightmap read from a PNG or raw f32 file",
+                vec![ParamSchema {
+                    name: "path",
+                    description: "Heightmap file, with an optional JSON sidecar next to it",
+                    default: ParamValue::Text(String::new()),
+                }],
+                |settings, params| {
+                    let generator = ImportGenerator::new(settings);
+                    match params.text("path") {
+                        Some(path) if !path.is_empty() => Box::new(generator.with_path(path)),
+                        _ => Box::new(generator),
+                    }
+                },
+            );
+
+        registry
+    }
+}
+
+impl GeneratorRegistry {
+    // Add a generator, replacing any registered under the same name
+    pub fn register(
+        &mut self,
+        name: &'static str,
+        description: &'static str,
+        params: Vec<ParamSchema>,
+        factory: impl Fn(&WorldGenSettings, &GeneratorParams) -> Box<dyn WorldGenerator>
+            + Send
+            + Sync
+            + 'static,
+    ) -> &mut Self {
+        let entry = GeneratorEntry {
+            name,
+            description,
+            params,
+            factory: Arc::new(factory),
+        };
+
+        match self.entries.iter_mut().find(|e| e.name == name) {
+            Some(existing) => *existing = entry,
+            None => self.entries.push(entry),
+        }
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&GeneratorEntry> {
+        self.entries.iter().find(|e| e.name == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &GeneratorEntry> {
+        self.entries.iter()
+    }
+
+    // The choice with every parameter filled in and read as its schema expects, so choices
+    // making the same generator compare equal
+    pub fn resolve(&self, choice: &GeneratorChoice) -> Result<GeneratorChoice, RegistryError> {
+        let entry = self
+            .get(&choice.name)
+            .ok_or_else(|| RegistryError::UnknownGenerator(choice.name.clone()))?;
+        Ok(GeneratorChoice {
+            name: choice.name.clone(),
+            params: entry.resolve(&choice.params)?,
+        })
+    }
+
+    // Register a noise graph as the "graph" generator, replacing the one before
+    pub(crate) fn register_graph(&mut self, graph: NoiseGraph) -> &mut Self {
+        self.register(
+            "graph",
+            "Noise graph read from assets/worldgen/default.worldgen.ron",
+            Vec::new(),
+            move |settings, _| Box::new(NoiseGraphGenerator::new(settings, &graph)),
+        )
+    }
+
+    // Make a generator by name, its parameters checked against its schema
+    pub fn create(
+        &self,
+        name: &str,
+        settings: &WorldGenSettings,
+        params: &GeneratorParams,
+    ) -> Result<Box<dyn WorldGenerator>, RegistryError> {
+        let entry = self
+            .get(name)
+            .ok_or_else(|| RegistryError::UnknownGenerator(name.into()))?;
+        let params = entry.resolve(params)?;
+        Ok((entry.factory)(settings, &params))
+    }
+
+    // Every generator and its parameters, one per line, for listing them to players
+    pub fn usage(&self) -> String {
+        let mut usage = String::new();
+        for entry in &self.entries {
+            usage += &format!("{}: {}\n", entry.name, entry.description);
+            for param in &entry.params {
+                usage += &format!(
+                    "  {}: {} (default {})\n",
+                    param.name, param.description, param.default
+                );
+            }
+        }
+        usage
+    }
+}
+
+// Generator to raise the world with, by its name in the `GeneratorRegistry`
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeneratorChoice {
+    pub name: String,
+    pub params: GeneratorParams,
+}
+
+// The noise graph, which players can edit while the world is shown
+impl Default for GeneratorChoice {
+    fn default() -> Self {
+        GeneratorChoice {
+            name: "graph".into(),
+            params: GeneratorParams::default(),
+        }
+    }
+}
+
+impl GeneratorChoice {
+    // Generator picked on the command line with `--generator <name>` and any number of
+    // `--param <name>=<value>`, values are read as the kind the generator expects once made.
+    // None if no generator was picked.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Option<Self> {
+        let mut name = None;
+        let mut params = GeneratorParams::default();
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--generator" => name = args.next(),
+                "--param" => {
+                    if let Some((param, value)) =
+                        args.next().as_deref().and_then(|p| p.split_once('='))
+                    {
+                        params = params.with(param, ParamValue::Text(value.into()));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        name.map(|name| GeneratorChoice { name, params })
+    }
+}
+
+// Like the command line picking it, with parameters sorted by name
+impl fmt::Display for GeneratorChoice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "--generator {}", self.name)?;
+        let mut params: Vec<_> = self.params.0.iter().collect();
+        params.sort_by_key(|(name, _)| *name);
+        for (name, value) in params {
+            write!(f, " --param {}={}", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{fixtures::settings, generation::Progress};
+    use super::{GeneratorParams, GeneratorRegistry, ParamValue, RegistryError};
+
+    #[test]
+    fn registry_lists_builtin_generators() {
+        use super::GeneratorChoice;
+
+        let registry = GeneratorRegistry::default();
+        let names: Vec<_> = registry.iter().map(|e| e.name).collect();
+        assert_eq!(
+            names,
+            vec![
+                "graph",
+                "simplex",
+                "tectonic",
+                "continents",
+                "craters",
+                "import"
+            ]
+        );
+        assert_eq!(names[0], GeneratorChoice::default().name);
+
+        let usage = registry.usage();
+        assert!(usage.contains("tectonic: "));
+        assert!(usage.contains("  plates: Number of tectonic plates (default 12)"));
+    }
+
+    #[test]
+    fn registry_creates_generators() {
+        let registry = GeneratorRegistry::default();
+
+        let gen = registry
+            .create("simplex", &settings(0, 32, 16), &GeneratorParams::default())
+            .unwrap();
+        let data = gen.get_world_data(&Progress::default()).unwrap();
+        assert_eq!(data.elevation.data.len(), 32 * 16);
+        assert!(data.plates.is_none());
+
+        // Text, as given on the command line, is read as the kind of value expected
+        let params = GeneratorParams::default().with("plates", ParamValue::Text("5".into()));
+        let gen = registry
+            .create("tectonic", &settings(0, 32, 16), &params)
+            .unwrap();
+        let plates = gen.get_world_data(&Progress::default()).unwrap().plates;
+        assert_eq!(plates.unwrap().plates.len(), 5);
+
+        // Previews are the same world at a lower resolution
+        let preview = gen.with_size(8, 4);
+        let map = preview.get_heightmap(&Progress::default()).unwrap();
+        assert_eq!((map.width, map.height), (8, 4));
+    }
+
+    #[test]
+    fn registry_checks_params() {
+        use super::GeneratorChoice;
+
+        let registry = GeneratorRegistry::default();
+        let create = |name: &str, params: GeneratorParams| {
+            registry.create(name, &settings(0, 32, 16), &params).err()
+        };
+
+        assert_eq!(
+            create("perlin", GeneratorParams::default()),
+            Some(RegistryError::UnknownGenerator("perlin".into()))
+        );
+        assert_eq!(
+            create(
+                "simplex",
+                GeneratorParams::default().with("plates", ParamValue::Int(3))
+            ),
+            Some(RegistryError::UnknownParam("simplex", "plates".into()))
+        );
+        assert_eq!(
+            create(
+                "tectonic",
+                GeneratorParams::default().with("plates", ParamValue::Text("many".into()))
+            ),
+            Some(RegistryError::InvalidParam(
+                "tectonic",
+                "plates",
+                ParamValue::Text("many".into())
+            ))
+        );
+        assert!(matches!(
+            create(
+                "tectonic",
+                GeneratorParams::default().with("plates", ParamValue::Float(2.5))
+            ),
+            Some(RegistryError::InvalidParam(..))
+        ));
+
+        let entry = registry.get("tectonic").unwrap();
+        let resolved = entry.resolve(&GeneratorParams::default()).unwrap();
+        assert_eq!(resolved.int("plates"), Some(12));
+
+        // Defaults spelled out, or given as text, resolve to the same choice
+        let choice = |params| GeneratorChoice {
+            name: "tectonic".into(),
+            params,
+        };
+        let implicit = registry.resolve(&choice(GeneratorParams::default()));
+        let explicit = registry.resolve(&choice(
+            GeneratorParams::default().with("plates", ParamValue::Text("12".into())),
+        ));
+        assert_eq!(implicit.unwrap(), explicit.unwrap());
+    }
+
+    #[test]
+    fn registry_takes_new_generators() {
+        use super::super::{
+            generation::{GenerationError, WorldGenerator},
+            heightmap::Heightmap,
+        };
+        use super::ParamSchema;
+
+        // A third-party generator, raising the whole world to one elevation
+        struct Plateau {
+            width: u32,
+            height: u32,
+            elevation: f32,
+        }
+
+        impl WorldGenerator for Plateau {
+            fn get_heightmap(&self, _: &Progress) -> Result<Heightmap, GenerationError> {
+                let len = (self.width * self.height) as usize;
+                Ok(Heightmap::new(
+                    self.width,
+                    self.height,
+                    vec![self.elevation; len],
+                ))
+            }
+
+            fn with_size(&self, width: u32, height: u32) -> Box<dyn WorldGenerator> {
+                Box::new(Plateau {
+                    width,
+                    height,
+                    elevation: self.elevation,
+                })
+            }
+        }
+
+        let mut registry = GeneratorRegistry::default();
+        registry.register(
+            "plateau",
+            "Flat everywhere",
+            vec![ParamSchema {
+                name: "elevation",
+                description: "Elevation of the plateau",
+                default: ParamValue::Float(0.1),
+            }],
+            |settings, params| {
+                Box::new(Plateau {
+                    width: settings.width,
+                    height: settings.height,
+                    elevation: params.float("elevation").unwrap_or_default(),
+                })
+            },
+        );
+        assert_eq!(registry.iter().last().unwrap().name, "plateau");
+
+        let params = GeneratorParams::default().with("elevation", ParamValue::Text("0.3".into()));
+        let map = registry
+            .create("plateau", &settings(0, 32, 16), &params)
+            .unwrap()
+            .get_heightmap(&Progress::default())
+            .unwrap();
+        assert!(map.data.iter().all(|f| *f == 0.3));
+    }
+
+    #[test]
+    fn choice_from_args() {
+        use super::GeneratorChoice;
+
+        let args =
+            |args: &[&str]| GeneratorChoice::from_args(args.iter().map(|arg| arg.to_string()));
+
+        assert_eq!(args(&[]), None);
+        assert_eq!(args(&["--param", "plates=3"]), None);
+
+        let choice = args(&["--generator", "tectonic", "--param", "plates=3"]).unwrap();
+        assert_eq!(choice.name, "tectonic");
+        assert_eq!(
+            choice.params.get("plates"),
+            Some(&ParamValue::Text("3".into()))
+        );
+
+        // Written back the same way, whatever order the parameters were given in
+        let both = args(&[
+            "--generator",
+            "import",
+            "--param",
+            "path=a",
+            "--param",
+            "b=1",
+        ]);
+        let swapped = args(&[
+            "--generator",
+            "import",
+            "--param",
+            "b=1",
+            "--param",
+            "path=a",
+        ]);
+        assert_eq!(
+            both.unwrap().to_string(),
+            "--generator import --param b=\"1\" --param path=\"a\""
+        );
+        assert_eq!(
+            swapped.unwrap().to_string(),
+            "--generator import --param b=\"1\" --param path=\"a\""
+        );
+    }
+}
@@ -0,0 +1,385 @@
+use std::{cmp::Reverse, collections::BinaryHeap};
+
+use bevy::{
+    prelude::*,
+    render::{
+        render_resource::{Extent3d, TextureDimension, TextureFormat},
+        texture::ImageSampler,
+    },
+};
+
+use super::{generation::Progress, heightmap::Heightmap};
+
+// Raise flooded texels by this much over their outlet, so filled lakes still drain
+const EPSILON: f32 = 1e-6;
+// Marks a texel draining nowhere, i.e. into the sea
+const SINK: u32 = u32::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct RiverSettings {
+    // Elevation below which texels are sea and collect all flow
+    pub sea_level: f32,
+    // Fraction of the planet's surface that must drain through a texel for it to be a river
+    pub threshold: f32,
+}
+
+impl Default for RiverSettings {
+    fn default() -> Self {
+        RiverSettings {
+            sea_level: 0.0,
+            threshold: 1e-4,
+        }
+    }
+}
+
+// River network extracted from a heightmap
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Rivers {
+    pub width: u32,
+    pub height: u32,
+    // Fraction of the planet's surface draining through each texel
+    pub accumulation: Vec<f32>,
+    // Texel each texel drains into, or `u32::MAX` for the sea
+    pub flow: Vec<u32>,
+    pub threshold: f32,
+    // Sea level the rivers flow down to
+    pub sea_level: f32,
+    // River courses from source to mouth or confluence, as (latitude, longitude) in radians
+    pub polylines: Vec<Vec<(f32, f32)>>,
+}
+
+// Elevation ordered for the priority queue, ties broken by index so results are deterministic
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Cell(f32, u32);
+
+impl Eq for Cell {}
+
+impl PartialOrd for Cell {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Cell {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.total_cmp(&other.0).then(self.1.cmp(&other.1))
+    }
+}
+
+fn coords(map: &Heightmap, idx: usize) -> (isize, isize) {
+    (
+        (idx % map.width as usize) as isize,
+        (idx / map.width as usize) as isize,
+    )
+}
+
+// The eight texels around a texel, with the distance to each in row spacings
+fn neighbours(map: &Heightmap, idx: usize) -> impl Iterator<Item = (usize, f32)> + '_ {
+    let (x, y) = coords(map, idx);
+    let column = 2.0 * map.height as f32 / map.width as f32 * map.latitude(y as f32).cos();
+
+    [
+        (-1, -1),
+        (0, -1),
+        (1, -1),
+        (-1, 0),
+        (1, 0),
+        (-1, 1),
+        (0, 1),
+        (1, 1),
+    ]
+    .into_iter()
+    .map(move |(dx, dy)| {
+        let distance = ((dx as f32 * column).powi(2) + (dy as f32).powi(2)).sqrt();
+        (map.index(x + dx, y + dy), distance)
+    })
+    // Rows are clamped at the poles, which would make a texel its own neighbour
+    .filter(move |(neighbour, _)| *neighbour != idx)
+}
+
+impl Rivers {
+    // Returns None if generation was cancelled through the progress handle
+    pub fn extract(map: &Heightmap, settings: &RiverSettings, progress: &Progress) -> Option<Self> {
+        let len = map.data.len();
+        let mut filled = map.data.clone();
+        let mut visited = vec![false; len];
+        let mut queue = BinaryHeap::new();
+
+        // The sea drains everything, so flooding starts from the coast.
+        // A planet without sea drains into its lowest point instead.
+        for (idx, height) in map.data.iter().enumerate() {
+            if *height < settings.sea_level {
+                visited[idx] = true;
+                if neighbours(map, idx).any(|(n, _)| map.data[n] >= settings.sea_level) {
+                    queue.push(Reverse(Cell(*height, idx as u32)));
+                }
+            }
+        }
+        if queue.is_empty() {
+            if let Some(lowest) = (0..len)
+                .min_by(|a, b| Cell(map.data[*a], *a as u32).cmp(&Cell(map.data[*b], *b as u32)))
+            {
+                visited[lowest] = true;
+                queue.push(Reverse(Cell(map.data[lowest], lowest as u32)));
+            }
+        }
+
+        // Priority flood, filling depressions as it climbs up from the outlets.
+        // Texels are reached in order of filled elevation, so every texel drains into an earlier one.
+        let mut order = Vec::with_capacity(len);
+        let mut flow = vec![SINK; len];
+
+        progress.start("rivers", map.height);
+        while let Some(Reverse(Cell(height, idx))) = queue.pop() {
+            let idx = idx as usize;
+            order.push(idx as u32);
+
+            if order.len() % map.width as usize == 0 {
+                if progress.is_cancelled() {
+                    return None;
+                }
+                progress.advance(1);
+            }
+
+            for (neighbour, _) in neighbours(map, idx) {
+                if !visited[neighbour] {
+                    visited[neighbour] = true;
+                    filled[neighbour] = filled[neighbour].max(height + EPSILON);
+                    queue.push(Reverse(Cell(filled[neighbour], neighbour as u32)));
+                }
+            }
+        }
+
+        // Steepest descent over the filled surface
+        for &idx in &order {
+            let idx = idx as usize;
+            if map.data[idx] < settings.sea_level {
+                continue;
+            }
+
+            let mut steepest = 0.0;
+            for (neighbour, distance) in neighbours(map, idx) {
+                let slope = (filled[idx] - filled[neighbour]) / distance;
+                if slope > steepest {
+                    steepest = slope;
+                    flow[idx] = neighbour as u32;
+                }
+            }
+        }
+
+        // Upstream texels are always later in the flood order, so walking it backwards
+        // passes every texel's whole catchment downstream before the texel itself moves on
+        let mut accumulation: Vec<f32> = (0..len)
+            .map(|idx| map.texel_area(coords(map, idx).1))
+            .collect();
+        for &idx in order.iter().rev() {
+            let downstream = flow[idx as usize];
+            if downstream != SINK {
+                accumulation[downstream as usize] += accumulation[idx as usize];
+            }
+        }
+
+        let mut rivers = Rivers {
+            width: map.width,
+            height: map.height,
+            accumulation,
+            flow,
+            threshold: settings.threshold,
+            sea_level: settings.sea_level,
+            polylines: Vec::new(),
+        };
+        rivers.polylines = rivers.trace(map);
+
+        Some(rivers)
+    }
+
+    pub fn is_river(&self, idx: usize) -> bool {
+        self.flow[idx] != SINK && self.accumulation[idx] >= self.threshold
+    }
+
+    // Follow every river from its source until it reaches the sea or joins another river
+    fn trace(&self, map: &Heightmap) -> Vec<Vec<(f32, f32)>> {
+        let len = self.flow.len();
+        let mut upstream = vec![false; len];
+        for idx in (0..len).filter(|idx| self.is_river(*idx)) {
+            upstream[self.flow[idx] as usize] = true;
+        }
+
+        let position = |idx: usize| {
+            let (x, y) = coords(map, idx);
+            (map.latitude(y as f32), map.longitude(x as f32))
+        };
+
+        let mut visited = vec![false; len];
+        let mut polylines = Vec::new();
+        for source in (0..len).filter(|idx| self.is_river(*idx) && !upstream[*idx]) {
+            let mut line = Vec::new();
+            let mut idx = source;
+
+            loop {
+                line.push(position(idx));
+                if visited[idx] || !self.is_river(idx) {
+                    break;
+                }
+                visited[idx] = true;
+                idx = self.flow[idx] as usize;
+            }
+
+            polylines.push(line);
+        }
+
+        polylines
+    }
+
+    // Overlay texture, brighter where more water flows
+    pub fn to_image(&self) -> Image {
+        let data = (0..self.flow.len())
+            .map(|idx| {
+                if !self.is_river(idx) {
+                    return 0;
+                }
+
+                let strength = 0.5 + (self.accumulation[idx] / self.threshold).log10() / 6.0;
+                (strength.min(1.0) * 255.0) as u8
+            })
+            .collect();
+
+        let mut image = Image::new(
+            Extent3d {
+                width: self.width,
+                height: self.height,
+                ..default()
+            },
+            TextureDimension::D2,
+            data,
+            TextureFormat::R8Unorm,
+        );
+        image.sampler_descriptor = ImageSampler::nearest();
+        image
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{generation::Progress, heightmap::Heightmap};
+    use super::{RiverSettings, Rivers};
+
+    // A valley running down the middle column into the sea at the bottom rows
+    fn valley(width: u32, height: u32) -> Heightmap {
+        let mut data = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                let across = (x as f32 - (width / 2) as f32).abs() / width as f32;
+                let along = (height - y) as f32 / height as f32;
+                data.push(if y + 2 >= height {
+                    -0.5
+                } else {
+                    0.1 * along + across
+                });
+            }
+        }
+
+        Heightmap::new(width, height, data)
+    }
+
+    #[test]
+    fn rivers_follow_valleys_to_the_sea() {
+        let map = valley(32, 32);
+        let rivers = Rivers::extract(
+            &map,
+            &RiverSettings {
+                sea_level: 0.0,
+                threshold: 0.01,
+            },
+            &Progress::default(),
+        )
+        .unwrap();
+
+        // The valley floor carries the most water, right before reaching the sea
+        let mouth = map.index(16, 29);
+        assert!(rivers.is_river(mouth));
+        let most = rivers
+            .accumulation
+            .iter()
+            .enumerate()
+            .filter(|(idx, _)| map.data[*idx] >= 0.0)
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .unwrap();
+        assert_eq!(most.0, mouth);
+
+        // Everything on land flows somewhere
+        for (idx, height) in map.data.iter().enumerate() {
+            assert_eq!(*height >= 0.0, rivers.flow[idx] != super::SINK);
+        }
+
+        // The river ends in the sea
+        let coast = map.latitude(30.0);
+        assert!(rivers
+            .polylines
+            .iter()
+            .any(|line| (line.last().unwrap().0 - coast).abs() < 1e-6));
+    }
+
+    #[test]
+    fn rivers_drain_depressions() {
+        let mut map = valley(32, 32);
+        // Dig a pit in the valley, it fills up and overflows instead of swallowing the river
+        let pit = map.index(16, 10);
+        map.data[pit] = 0.01;
+
+        let rivers = Rivers::extract(
+            &map,
+            &RiverSettings {
+                sea_level: 0.0,
+                threshold: 0.01,
+            },
+            &Progress::default(),
+        )
+        .unwrap();
+
+        assert!(rivers.accumulation[map.index(16, 20)] > rivers.accumulation[pit]);
+
+        // Following the flow out of the pit leads to the sea
+        let mut idx = pit;
+        while map.data[idx] >= 0.0 {
+            idx = rivers.flow[idx] as usize;
+        }
+        assert!(idx >= map.index(0, 30));
+    }
+
+    #[test]
+    fn rivers_conserve_area() {
+        let map = valley(48, 24);
+        let rivers =
+            Rivers::extract(&map, &RiverSettings::default(), &Progress::default()).unwrap();
+
+        // All land drains into the sea, covering the whole planet between them
+        let total: f32 = (0..map.data.len())
+            .filter(|idx| map.data[*idx] < 0.0)
+            .map(|idx| rivers.accumulation[idx])
+            .sum();
+        assert!((total - 1.0).abs() < 2e-3);
+    }
+
+    #[test]
+    fn rivers_to_image() {
+        use bevy::prelude::*;
+
+        let rivers = Rivers::extract(
+            &valley(32, 16),
+            &RiverSettings::default(),
+            &Progress::default(),
+        )
+        .unwrap();
+        let img = rivers.to_image();
+        assert_eq!(img.size(), Vec2::new(32., 16.));
+        assert!(img.data.iter().any(|b| *b > 0));
+    }
+
+    #[test]
+    fn rivers_stop_when_cancelled() {
+        let progress = Progress::default();
+        progress.cancel();
+        assert!(Rivers::extract(&valley(64, 32), &RiverSettings::default(), &progress).is_none());
+    }
+}
@@ -7,9 +7,14 @@ use bevy::{
     },
 };
 
+/// Conversions between the sphere and the planet's maps, imported as `planet::geo`
+pub const GEO_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 0x6a1f_3c58_92d4_e07b);
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct GenerationMaterialKey {
     normal_map: bool,
+    cube_sphere: bool,
     cull_mode: Option<Face>,
 }
 
@@ -131,6 +136,53 @@ pub struct GenerationMaterial {
 
     #[uniform(16)]
     pub quantizer: f32,
+
+    #[texture(17)]
+    #[sampler(18)]
+    pub river_texture: Option<Handle<Image>>,
+
+    /// Opacity of the river overlay, 0 hides it
+    #[uniform(19)]
+    pub river_strength: f32,
+
+    /// Land, lake and ocean mask, white for land
+    #[texture(20)]
+    #[sampler(21)]
+    pub water_texture: Option<Handle<Image>>,
+
+    /// Elevation water is flattened to
+    #[uniform(22)]
+    pub sea_level: f32,
+
+    /// Tectonic plates and their boundaries, the alpha channel weighs the overlay
+    #[texture(23)]
+    #[sampler(24)]
+    pub plate_texture: Option<Handle<Image>>,
+
+    /// Opacity of the plate overlay, 0 hides it
+    #[uniform(25)]
+    pub plate_strength: f32,
+
+    /// Biome of every texel, as an index into `biome_palette`
+    #[texture(26)]
+    #[sampler(27)]
+    pub biome_texture: Option<Handle<Image>>,
+
+    /// Color of each biome, one texel each
+    #[texture(28)]
+    #[sampler(29)]
+    pub biome_palette: Option<Handle<Image>>,
+
+    /// How much biome colors replace the base color, 0 hides them
+    #[uniform(30)]
+    pub biome_strength: f32,
+
+    /// Elevation textures are laid out as a cube-sphere atlas instead of equirectangular
+    pub cube_sphere: bool,
+
+    /// Offset and scale turning texels of the elevation textures back into elevations
+    #[uniform(31)]
+    pub elevation_decode: Vec2,
 }
 
 impl Default for GenerationMaterial {
@@ -166,6 +218,17 @@ impl Default for GenerationMaterial {
             elevation_other: None,
             interp: 0.0,
             quantizer: 1000.0,
+            river_texture: None,
+            river_strength: 0.0,
+            water_texture: None,
+            sea_level: 0.0,
+            plate_texture: None,
+            plate_strength: 0.0,
+            biome_texture: None,
+            biome_palette: None,
+            biome_strength: 0.0,
+            cube_sphere: false,
+            elevation_decode: Vec2::new(0.0, 1.0),
         }
     }
 }
@@ -236,6 +299,7 @@ impl From<&GenerationMaterial> for GenerationMaterialKey {
     fn from(material: &GenerationMaterial) -> Self {
         GenerationMaterialKey {
             normal_map: material.normal_map_texture.is_some(),
+            cube_sphere: material.cube_sphere,
             cull_mode: material.cull_mode,
         }
     }
@@ -256,6 +320,12 @@ impl Material for GenerationMaterial {
                 .shader_defs
                 .push(String::from("STANDARDMATERIAL_NORMAL_MAP"));
         }
+        if key.bind_group_data.cube_sphere {
+            descriptor
+                .vertex
+                .shader_defs
+                .push(String::from("CUBE_SPHERE"));
+        }
         descriptor.primitive.cull_mode = key.bind_group_data.cull_mode;
         if let Some(label) = &mut descriptor.label {
             *label = format!("pbr_{}", *label).into();
@@ -0,0 +1,55 @@
+use bevy::prelude::*;
+use noise::{NoiseFn, OpenSimplex, Seedable};
+use rand::Rng;
+use rand_chacha::ChaCha8Rng;
+
+// Random point uniformly distributed on the unit sphere
+pub(super) fn random_direction(rng: &mut ChaCha8Rng) -> Vec3 {
+    let z = rng.gen_range(-1.0f32..1.0);
+    let angle = rng.gen_range(0.0..std::f32::consts::TAU);
+    let r = (1.0 - z * z).sqrt();
+    Vec3::new(r * angle.cos(), r * angle.sin(), z)
+}
+
+// Smooth rise from 0 at `x = 0` to 1 at `x = edge`
+pub(super) fn smoothstep(edge: f32, x: f32) -> f32 {
+    let t = (x / edge).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+// Noise wobbling points on the unit sphere, so outlines traced around seeds aren't plain circles
+// or straight Voronoi edges
+#[derive(Clone, Debug)]
+pub(super) struct Warp {
+    noise: [OpenSimplex; 3],
+    // How far points wander, on the unit sphere
+    strength: f32,
+}
+
+impl Warp {
+    pub fn new(rng: &mut ChaCha8Rng, strength: f32) -> Self {
+        Warp {
+            noise: [
+                OpenSimplex::new().set_seed(rng.gen()),
+                OpenSimplex::new().set_seed(rng.gen()),
+                OpenSimplex::new().set_seed(rng.gen()),
+            ],
+            strength,
+        }
+    }
+
+    pub fn apply(&self, point: Vec3) -> Vec3 {
+        let sample = [
+            point.x as f64 * 2.0,
+            point.y as f64 * 2.0,
+            point.z as f64 * 2.0,
+        ];
+        let offset = Vec3::new(
+            self.noise[0].get(sample) as f32,
+            self.noise[1].get(sample) as f32,
+            self.noise[2].get(sample) as f32,
+        );
+
+        (point + offset * self.strength).normalize()
+    }
+}
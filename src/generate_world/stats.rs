@@ -0,0 +1,348 @@
+use std::{fmt, sync::mpsc::Sender};
+
+use bevy::prelude::Image;
+use serde::Serialize;
+
+use super::{
+    generation::{GenerationError, Job, Progress},
+    geo,
+    heightmap::Heightmap,
+    world_data::WorldData,
+};
+
+// Bins the elevation range is split into to find percentiles, fine enough to be exact to the
+// eye without sorting every texel
+const FINE_BINS: usize = 4096;
+// Bins of the histogram shown to players
+const HISTOGRAM_BINS: usize = 16;
+// Bands of latitude averaged over, from the south pole up
+const LATITUDE_BANDS: usize = 12;
+
+// Averages over a band of latitude
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub(crate) struct LatitudeBand {
+    // Bounds of the band, in degrees
+    pub south: f32,
+    pub north: f32,
+    pub elevation: f32,
+    pub temperature: f32,
+    pub moisture: f32,
+    pub land_fraction: f32,
+}
+
+// Statistics of a generated world, to tune generator settings by. Every share is a share of the
+// planet's surface, so rows crowded at the poles count for as little as the area they cover.
+// Written into export sidecars, where `min` and `max` are left to the range of the export.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct WorldStats {
+    pub sea_level: f32,
+    #[serde(rename = "lowest")]
+    pub min: f32,
+    #[serde(rename = "highest")]
+    pub max: f32,
+    pub mean: f32,
+    // Elevation below which lies each percent of the surface, from 0 to 100
+    pub percentiles: Vec<f32>,
+    // Share of the surface in each of equally wide bins between the lowest and highest elevation
+    pub histogram: Vec<f32>,
+    pub land_fraction: f32,
+    pub latitude_bands: Vec<LatitudeBand>,
+}
+
+// Share of the surface covered by each texel of a row of the map
+pub(super) fn row_weights(map: &Heightmap) -> Vec<f32> {
+    (0..map.height)
+        .map(|y| geo::latitude(y as f32, map.height).cos().max(0.0))
+        .collect()
+}
+
+impl WorldStats {
+    pub fn compute(data: &WorldData, sea_level: f32) -> Self {
+        let map = &data.elevation;
+        let weights = row_weights(map);
+        let rows = || map.data.chunks(map.width as usize).zip(&weights);
+
+        let (min, max) = map
+            .data
+            .iter()
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), f| {
+                (min.min(*f), max.max(*f))
+            });
+        let span = (max - min).max(f32::EPSILON);
+        let bin = |f: f32| (((f - min) / span * FINE_BINS as f32) as usize).min(FINE_BINS - 1);
+
+        let mut fine = vec![0.0f64; FINE_BINS];
+        let (mut total, mut sum, mut land) = (0.0f64, 0.0f64, 0.0f64);
+        for (row, weight) in rows() {
+            let weight = *weight as f64;
+            for f in row {
+                fine[bin(*f)] += weight;
+                total += weight;
+                sum += *f as f64 * weight;
+                if *f >= sea_level {
+                    land += weight;
+                }
+            }
+        }
+        let total = total.max(f64::MIN_POSITIVE);
+
+        // Elevation below which each percent of the surface lies, interpolated within its fine bin
+        let mut cumulative = 0.0;
+        let mut next = 0;
+        let mut percentiles = Vec::with_capacity(101);
+        for (i, count) in fine.iter().enumerate() {
+            let below = cumulative + count / total;
+            while next <= 100 && next as f64 / 100.0 <= below {
+                let within = if *count > 0.0 {
+                    (next as f64 / 100.0 - cumulative) * total / count
+                } else {
+                    0.0
+                };
+                let elevation = min + (i as f32 + within as f32) / FINE_BINS as f32 * span;
+                percentiles.push(elevation.clamp(min, max));
+                next += 1;
+            }
+            cumulative = below;
+        }
+        // Rounding may leave the top percentiles short of the last bin
+        percentiles.resize(101, max);
+
+        let histogram = fine
+            .chunks(FINE_BINS / HISTOGRAM_BINS)
+            .map(|bins| (bins.iter().sum::<f64>() / total) as f32)
+            .collect();
+
+        WorldStats {
+            sea_level,
+            min,
+            max,
+            mean: (sum / total) as f32,
+            percentiles,
+            histogram,
+            land_fraction: (land / total) as f32,
+            latitude_bands: Self::latitude_bands(data, sea_level),
+        }
+    }
+
+    fn latitude_bands(data: &WorldData, sea_level: f32) -> Vec<LatitudeBand> {
+        let map = &data.elevation;
+        let width = map.width as usize;
+        let weights = row_weights(map);
+
+        // Weight, then weighted sums of elevation, temperature, moisture and land
+        let mut sums = vec![[0.0f64; 5]; LATITUDE_BANDS];
+        for (y, weight) in weights.iter().enumerate() {
+            let latitude = geo::latitude(y as f32, map.height).to_degrees();
+            let band = (((latitude + 90.0) / 180.0 * LATITUDE_BANDS as f32) as usize)
+                .min(LATITUDE_BANDS - 1);
+            let weight = *weight as f64;
+
+            let [elevation, temperature, moisture] =
+                [&data.elevation, &data.temperature, &data.moisture]
+                    .map(|layer| &layer.data[y * width..(y + 1) * width]);
+            let sum = &mut sums[band];
+            for ((e, t), m) in elevation.iter().zip(temperature).zip(moisture) {
+                sum[0] += weight;
+                sum[1] += *e as f64 * weight;
+                sum[2] += *t as f64 * weight;
+                sum[3] += *m as f64 * weight;
+                if *e >= sea_level {
+                    sum[4] += weight;
+                }
+            }
+        }
+
+        let step = 180.0 / LATITUDE_BANDS as f32;
+        sums.iter()
+            .enumerate()
+            .map(|(i, sum)| {
+                let mean = |j: usize| (sum[j] / sum[0].max(f64::MIN_POSITIVE)) as f32;
+                LatitudeBand {
+                    south: -90.0 + i as f32 * step,
+                    north: -90.0 + (i + 1) as f32 * step,
+                    elevation: mean(1),
+                    temperature: mean(2),
+                    moisture: mean(3),
+                    land_fraction: mean(4),
+                }
+            })
+            .collect()
+    }
+
+    // Hypsometric curve: the elevation reached by each percent of the surface, from all of it
+    // at the lowest point to none of it at the highest
+    pub fn hypsometric(&self) -> impl Iterator<Item = (u32, f32)> + '_ {
+        self.percentiles
+            .iter()
+            .rev()
+            .enumerate()
+            .map(|(above, f)| (above as u32, *f))
+    }
+}
+
+// Report shown in the statistics panel
+impl fmt::Display for WorldStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Elevation {:.2} to {:.2}, mean {:.2}",
+            self.min, self.max, self.mean
+        )?;
+        writeln!(
+            f,
+            "Land {:.0}% at sea level {:.2}",
+            self.land_fraction * 100.0,
+            self.sea_level
+        )?;
+
+        write!(f, "Percentiles")?;
+        for percent in [5, 25, 50, 75, 95] {
+            write!(f, "  {}%: {:.2}", percent, self.percentiles[percent])?;
+        }
+        writeln!(f)?;
+
+        write!(f, "Surface above")?;
+        for (above, elevation) in self.hypsometric().filter(|(above, _)| above % 20 == 10) {
+            write!(f, "  {:.2}: {}%", elevation, above)?;
+        }
+        writeln!(f)?;
+
+        writeln!(f, "Histogram")?;
+        let bin = (self.max - self.min) / self.histogram.len() as f32;
+        for (i, share) in self.histogram.iter().enumerate() {
+            let bar = "#".repeat((share * 100.0).round() as usize);
+            writeln!(f, "  {:>6.2} {}", self.min + i as f32 * bin, bar)?;
+        }
+
+        writeln!(f, "Latitude")?;
+        for band in self.latitude_bands.iter().rev() {
+            writeln!(
+                f,
+                "  {:>4.0} to {:>4.0}: elevation {:>5.2}, land {:>3.0}%, {:>4.0}°C, humidity {:>3.0}%",
+                band.south,
+                band.north,
+                band.elevation,
+                band.land_fraction * 100.0,
+                band.temperature,
+                band.moisture * 100.0
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Statistics of a map at another sea level, measured on a thread of its own
+pub(crate) struct StatsJob {
+    pub data: WorldData,
+    pub sea_level: f32,
+}
+
+impl Job for StatsJob {
+    type Output = WorldStats;
+
+    fn run(self, progress: &Progress, _: &Sender<Image>) -> Result<WorldStats, GenerationError> {
+        progress.start("statistics", 1);
+        let stats = WorldStats::compute(&self.data, self.sea_level);
+        progress.advance(1);
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::WorldStats;
+    use crate::generate_world::{heightmap::Heightmap, world_data::WorldData};
+
+    // Rows from south to north, each at one elevation
+    fn banded(width: u32, rows: &[f32]) -> WorldData {
+        let data = rows
+            .iter()
+            .flat_map(|f| std::iter::repeat(*f).take(width as usize))
+            .collect();
+        WorldData::new(Heightmap::new(width, rows.len() as u32, data))
+    }
+
+    #[test]
+    fn stats_of_a_uniform_ramp() {
+        // Every elevation in [0, 1) equally often in every row
+        let width = 1000;
+        let row: Vec<f32> = (0..width).map(|x| x as f32 / width as f32).collect();
+        let data = WorldData::new(Heightmap::new(width, 4, row.repeat(4)));
+        let stats = WorldStats::compute(&data, 0.25);
+
+        assert_eq!((stats.min, stats.max), (0.0, 0.999));
+        assert!((stats.mean - 0.4995).abs() < 1e-4);
+        assert!((stats.land_fraction - 0.75).abs() < 1e-3);
+        for (percent, f) in stats.percentiles.iter().enumerate() {
+            assert!(
+                (f - percent as f32 / 100.0).abs() < 2e-3,
+                "{} {}",
+                percent,
+                f
+            );
+        }
+        assert_eq!(stats.histogram.len(), 16);
+        assert!((stats.histogram.iter().sum::<f32>() - 1.0).abs() < 1e-5);
+        assert!(stats
+            .histogram
+            .iter()
+            .all(|share| (share - 1.0 / 16.0).abs() < 2e-3));
+
+        // Half the surface is above the median
+        let hypsometric: Vec<_> = stats.hypsometric().collect();
+        assert_eq!(hypsometric[50], (50, stats.percentiles[50]));
+        assert_eq!(hypsometric[0].1, stats.percentiles[100]);
+    }
+
+    #[test]
+    fn stats_weigh_rows_by_area() {
+        // Sea over the two rows around the equator, land over the two near the poles
+        let data = banded(8, &[1.0, -1.0, -1.0, 1.0]);
+        let stats = WorldStats::compute(&data, 0.0);
+
+        // Polar rows cover less of the sphere than equatorial ones
+        let polar = (3.0 * std::f32::consts::PI / 8.0).cos();
+        let equatorial = (std::f32::consts::PI / 8.0).cos();
+        let land = polar / (polar + equatorial);
+        assert!((stats.land_fraction - land).abs() < 1e-5);
+        assert!((stats.mean - (land - (1.0 - land))).abs() < 1e-5);
+        assert!(stats.percentiles[50] < 0.0);
+    }
+
+    #[test]
+    fn stats_average_latitude_bands() {
+        let mut data = banded(4, &[-1.0, 0.5, 0.5, 1.0]);
+        Arc::make_mut(&mut data.temperature)
+            .data
+            .iter_mut()
+            .for_each(|t| *t = 20.0);
+        let stats = WorldStats::compute(&data, 0.0);
+
+        let bands = &stats.latitude_bands;
+        assert_eq!(bands.len(), 12);
+        assert_eq!((bands[0].south, bands[11].north), (-90.0, 90.0));
+        // Rows are 45° apart, centered at -67.5°, -22.5°, 22.5° and 67.5°
+        assert_eq!(bands[1].elevation, -1.0);
+        assert_eq!(bands[1].land_fraction, 0.0);
+        assert_eq!(bands[4].elevation, 0.5);
+        assert_eq!(bands[10].land_fraction, 1.0);
+        assert_eq!(bands[10].temperature, 20.0);
+        // Bands without a row are empty
+        assert_eq!(bands[0].land_fraction, 0.0);
+    }
+
+    #[test]
+    fn stats_serialize_to_json() {
+        let stats = WorldStats::compute(&banded(4, &[-1.0, 1.0]), 0.0);
+        let json = serde_json::to_value(&stats).unwrap();
+
+        assert_eq!(json["land_fraction"], 0.5);
+        assert_eq!(json["lowest"], -1.0);
+        assert_eq!(json["latitude_bands"].as_array().unwrap().len(), 12);
+        // Reads back with the keys import looks for left to the top level
+        assert!(json.get("min").is_none());
+        assert!(json.get("max").is_none());
+    }
+}
@@ -0,0 +1,424 @@
+use bevy::{
+    prelude::*,
+    render::{
+        render_resource::{Extent3d, TextureDimension, TextureFormat},
+        texture::ImageSampler,
+    },
+};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use super::{
+    generation::{
+        bands, fill_rows, GenerationError, Progress, SimplexGenerator, WorldGenSettings,
+        WorldGenerator,
+    },
+    heightmap::Heightmap,
+    sphere::{random_direction, smoothstep, Warp},
+    world_data::WorldData,
+};
+
+// Share of plates carrying continental crust
+const CONTINENTAL_FRACTION: f64 = 0.4;
+// Elevation of the crust away from any boundary, relative to the amplitude
+const CONTINENTAL_BASE: f32 = 0.25;
+const OCEANIC_BASE: f32 = -0.5;
+// Angular half-width of boundary features, in radians
+const FEATURE_WIDTH: f32 = 0.06;
+// Closing speeds between these are sliding past each other
+const TRANSFORM_SPEED: f32 = 0.2;
+// How far boundaries wander from the plain Voronoi cells, on the unit sphere
+const WARP: f32 = 0.2;
+// Share of the amplitude left to noise detail on top of the plates
+const DETAIL: f32 = 0.25;
+// Plates are told apart by a 16-bit id in `PlateMap`
+const MAX_PLATES: u32 = u16::MAX as u32 + 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Boundary {
+    Interior,
+    Convergent,
+    Divergent,
+    Transform,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Plate {
+    pub center: Vec3,
+    // Rotation of the plate around the planet's centre, as axis times angular speed
+    pub rotation: Vec3,
+    pub continental: bool,
+}
+
+impl Plate {
+    pub fn velocity(&self, point: Vec3) -> Vec3 {
+        self.rotation.cross(point)
+    }
+}
+
+// Plate layers produced alongside the heightmap
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlateMap {
+    pub width: u32,
+    pub height: u32,
+    pub plates: Vec<Plate>,
+    // Index into `plates` of the plate each texel belongs to
+    pub plate_ids: Vec<u16>,
+    pub boundaries: Vec<Boundary>,
+}
+
+impl PlateMap {
+    // Overlay texture, tinting plates with their boundaries picked out by type
+    pub fn to_image(&self) -> Image {
+        let mut data = Vec::with_capacity(self.plate_ids.len() * 4);
+
+        for (id, boundary) in self.plate_ids.iter().zip(self.boundaries.iter()) {
+            let color = match boundary {
+                Boundary::Convergent => [255, 60, 40, 255],
+                Boundary::Divergent => [40, 220, 255, 255],
+                Boundary::Transform => [255, 220, 40, 255],
+                Boundary::Interior => {
+                    // Spread neighbouring ids apart in hue
+                    let hash = (*id as u32).wrapping_mul(2654435761);
+                    [
+                        (hash >> 24) as u8,
+                        (hash >> 16) as u8,
+                        (hash >> 8) as u8,
+                        96,
+                    ]
+                }
+            };
+            data.extend_from_slice(&color);
+        }
+
+        let mut image = Image::new(
+            Extent3d {
+                width: self.width,
+                height: self.height,
+                ..default()
+            },
+            TextureDimension::D2,
+            data,
+            TextureFormat::Rgba8UnormSrgb,
+        );
+        image.sampler_descriptor = ImageSampler::nearest();
+        image
+    }
+}
+
+// Bell curve of a boundary feature, `distance` from where it peaks
+fn ridge(distance: f32, width: f32) -> f32 {
+    (-(distance / width).powi(2)).exp()
+}
+
+// Continents from plate tectonics: plates are seeded on the sphere and set moving,
+// then mountains, rifts and trenches are raised where they meet
+#[derive(Clone, Debug)]
+pub(super) struct TectonicGenerator {
+    settings: WorldGenSettings,
+    plates: Vec<Plate>,
+    warp: Warp,
+    detail: SimplexGenerator,
+}
+
+impl TectonicGenerator {
+    pub fn new(settings: &WorldGenSettings, plates: u32) -> Self {
+        let mut rng = ChaCha8Rng::seed_from_u64(settings.seed as u64);
+
+        let plates = (0..plates.clamp(1, MAX_PLATES))
+            .map(|_| Plate {
+                center: random_direction(&mut rng),
+                rotation: random_direction(&mut rng) * rng.gen_range(0.5..1.0),
+                continental: rng.gen_bool(CONTINENTAL_FRACTION),
+            })
+            .collect();
+
+        let warp = Warp::new(&mut rng, WARP);
+
+        TectonicGenerator {
+            settings: *settings,
+            plates,
+            warp,
+            detail: SimplexGenerator::new(&WorldGenSettings {
+                amplitude: settings.amplitude * DETAIL,
+                ..*settings
+            }),
+        }
+    }
+
+    // Nearest plate to a point, and the distance in radians to the boundary with the next nearest
+    fn nearest(&self, point: Vec3) -> (usize, usize, f32) {
+        let (mut first, mut second) = ((0, f32::MIN), (0, f32::MIN));
+
+        for (i, plate) in self.plates.iter().enumerate() {
+            let closeness = plate.center.dot(point);
+            if closeness > first.1 {
+                second = first;
+                first = (i, closeness);
+            } else if closeness > second.1 {
+                second = (i, closeness);
+            }
+        }
+
+        if self.plates.len() < 2 {
+            return (first.0, first.0, std::f32::consts::PI);
+        }
+
+        let distance = |closeness: f32| closeness.clamp(-1.0, 1.0).acos();
+        (
+            first.0,
+            second.0,
+            (distance(second.1) - distance(first.1)) / 2.0,
+        )
+    }
+
+    // Elevation and boundary type at a point on the unit sphere, before noise detail
+    fn sample(&self, point: Vec3) -> (u16, Boundary, f32) {
+        // Wobbled so plate outlines aren't straight Voronoi edges
+        let warped = self.warp.apply(point);
+        let (a, b, distance) = self.nearest(warped);
+        let (plate, other) = (&self.plates[a], &self.plates[b]);
+
+        let base = |plate: &Plate| {
+            if plate.continental {
+                CONTINENTAL_BASE
+            } else {
+                OCEANIC_BASE
+            }
+        };
+        // Crust levels meet halfway at the boundary
+        let crust = base(plate)
+            + (base(other) - base(plate)) / 2.0 * (1.0 - smoothstep(FEATURE_WIDTH * 2.0, distance));
+
+        if a == b {
+            return (a as u16, Boundary::Interior, crust);
+        }
+
+        // Speed at which the plates close on each other across the boundary
+        let towards = other.center - plate.center;
+        let normal = (towards - warped * towards.dot(warped)).normalize_or_zero();
+        let closing = (plate.velocity(warped) - other.velocity(warped)).dot(normal) / 2.0;
+
+        let kind = if closing > TRANSFORM_SPEED {
+            Boundary::Convergent
+        } else if closing < -TRANSFORM_SPEED {
+            Boundary::Divergent
+        } else {
+            Boundary::Transform
+        };
+
+        let strength = closing.abs().min(1.0);
+        let feature = match (kind, plate.continental, other.continental) {
+            // Continents collide into high ranges
+            (Boundary::Convergent, true, true) => 0.7 * ridge(distance, FEATURE_WIDTH),
+            // Oceanic crust dives under continents, raising mountains inland of a trench
+            (Boundary::Convergent, true, false) => {
+                0.5 * ridge(distance - FEATURE_WIDTH, FEATURE_WIDTH)
+            }
+            (Boundary::Convergent, false, true) => -0.5 * ridge(distance, FEATURE_WIDTH / 2.0),
+            // The older plate subducts under an island arc
+            (Boundary::Convergent, false, false) if a < b => {
+                -0.5 * ridge(distance, FEATURE_WIDTH / 2.0)
+            }
+            (Boundary::Convergent, false, false) => 0.4 * ridge(distance, FEATURE_WIDTH / 2.0),
+            // Spreading oceans build ridges, spreading continents tear into rifts
+            (Boundary::Divergent, false, false) => 0.2 * ridge(distance, FEATURE_WIDTH),
+            (Boundary::Divergent, _, _) => -0.3 * ridge(distance, FEATURE_WIDTH / 2.0),
+            _ => 0.0,
+        };
+
+        (a as u16, kind, crust + feature * strength)
+    }
+
+    // Plate, boundary and elevation at a point on the unit sphere, detail included
+    fn surface(&self, point: Vec3) -> (u16, Boundary, f32) {
+        let amplitude = self.settings.amplitude;
+        let (id, boundary, elevation) = self.sample(point);
+        let detail = self.detail.octaves(point.x, point.y, point.z);
+        let elevation =
+            (elevation * (1.0 - DETAIL) * amplitude + detail).clamp(-amplitude, amplitude);
+        (id, boundary, elevation)
+    }
+
+    pub fn generate(&self, progress: &Progress) -> Result<(Heightmap, PlateMap), GenerationError> {
+        let WorldGenSettings { width, height, .. } = self.settings;
+        let len = (width * height) as usize;
+        let mut map = Heightmap::new(width, height, vec![0.0; len]);
+
+        progress.start("plate tectonics", height);
+        let mut texels = vec![(0, Boundary::Interior, 0.0); len];
+        fill_rows(&mut texels, width, bands(), progress, |y, row| {
+            for (x, texel) in row.iter_mut().enumerate() {
+                *texel = self.surface(map.direction(x as f32, y as f32));
+            }
+        })?;
+
+        let mut plate_ids = Vec::with_capacity(len);
+        let mut boundaries = Vec::with_capacity(len);
+        for (elevation, (id, boundary, texel)) in map.data.iter_mut().zip(texels) {
+            *elevation = texel;
+            plate_ids.push(id);
+            boundaries.push(boundary);
+        }
+
+        // Only texels next to another plate are marked as lying on the boundary
+        for idx in 0..len {
+            let (x, y) = ((idx as u32 % width) as isize, (idx as u32 / width) as isize);
+            let edge = [(-1, 0), (1, 0), (0, -1), (0, 1)]
+                .iter()
+                .any(|(dx, dy)| plate_ids[map.index(x + dx, y + dy)] != plate_ids[idx]);
+            if !edge {
+                boundaries[idx] = Boundary::Interior;
+            }
+        }
+
+        let plates = PlateMap {
+            width,
+            height,
+            plates: self.plates.clone(),
+            plate_ids,
+            boundaries,
+        };
+
+        Ok((map, plates))
+    }
+}
+
+impl WorldGenerator for TectonicGenerator {
+    fn get_heightmap(&self, progress: &Progress) -> Result<Heightmap, GenerationError> {
+        self.generate(progress).map(|(map, _)| map)
+    }
+
+    // Plates are seeded the same way whatever the size of the map
+    fn with_size(&self, width: u32, height: u32) -> Box<dyn WorldGenerator> {
+        let settings = self.settings.resized(width, height);
+        Box::new(TectonicGenerator::new(&settings, self.plates.len() as u32))
+    }
+
+    fn sample(&self, point: Vec3) -> Option<f32> {
+        Some(self.surface(point).2)
+    }
+
+    fn get_world_data(&self, progress: &Progress) -> Result<WorldData, GenerationError> {
+        let (map, plates) = self.generate(progress)?;
+        Ok(WorldData {
+            plates: Some(plates),
+            ..WorldData::new(map)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{fixtures::settings, generation::Progress};
+
+    #[test]
+    fn tectonic_generator_is_reproducible() {
+        use super::TectonicGenerator;
+
+        let first = TectonicGenerator::new(&settings(5, 128, 64), 8)
+            .generate(&Progress::default())
+            .unwrap();
+        let second = TectonicGenerator::new(&settings(5, 128, 64), 8)
+            .generate(&Progress::default())
+            .unwrap();
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn tectonic_generator_is_clamped() {
+        use super::TectonicGenerator;
+        use crate::generate_world::generation::WorldGenerator;
+
+        let settings = settings(5, 128, 64);
+        let data = TectonicGenerator::new(&settings, 8)
+            .get_world_data(&Progress::default())
+            .unwrap();
+        assert!(data.plates.is_some());
+
+        let map = data.elevation;
+        assert_eq!(map.data.len(), 128 * 64);
+        assert!(map.data.iter().all(|f| f.abs() <= settings.amplitude));
+    }
+
+    #[test]
+    fn plate_count_fits_plate_ids() {
+        use super::{TectonicGenerator, MAX_PLATES};
+
+        let gen = TectonicGenerator::new(&settings(5, 128, 64), u32::MAX);
+        assert_eq!(gen.plates.len() as u32, MAX_PLATES);
+        assert_eq!(gen.plates.len() - 1, u16::MAX as usize);
+        assert_eq!(
+            TectonicGenerator::new(&settings(5, 128, 64), 0)
+                .plates
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn tectonic_generator_exports_plates() {
+        use super::{Boundary, TectonicGenerator};
+
+        let (map, plates) = TectonicGenerator::new(&settings(5, 128, 64), 8)
+            .generate(&Progress::default())
+            .unwrap();
+
+        assert_eq!(plates.plate_ids.len(), map.data.len());
+        assert_eq!(plates.boundaries.len(), map.data.len());
+        assert!(plates.plate_ids.iter().all(|id| (*id as usize) < 8));
+
+        // Every plate owns some ground and meets the others along boundaries
+        for id in 0..8 {
+            assert!(plates.plate_ids.contains(&id));
+        }
+        assert!(plates.boundaries.contains(&Boundary::Interior));
+        assert!(plates.boundaries.iter().any(|b| *b != Boundary::Interior));
+
+        // Boundary texels are exactly those with a neighbour on another plate
+        for (idx, boundary) in plates.boundaries.iter().enumerate() {
+            let (x, y) = ((idx % 128) as isize, (idx / 128) as isize);
+            let edge = [(-1, 0), (1, 0), (0, -1), (0, 1)].iter().any(|(dx, dy)| {
+                plates.plate_ids[map.index(x + dx, y + dy)] != plates.plate_ids[idx]
+            });
+            assert_eq!(*boundary != Boundary::Interior, edge);
+        }
+    }
+
+    #[test]
+    fn tectonic_generator_raises_continents() {
+        use super::TectonicGenerator;
+
+        let gen = TectonicGenerator::new(&settings(5, 128, 64), 12);
+        let (map, plates) = gen.generate(&Progress::default()).unwrap();
+
+        let mean = |continental: bool| {
+            let heights: Vec<f32> = map
+                .data
+                .iter()
+                .zip(plates.plate_ids.iter())
+                .filter(|(_, id)| plates.plates[**id as usize].continental == continental)
+                .map(|(h, _)| *h)
+                .collect();
+            heights.iter().sum::<f32>() / heights.len() as f32
+        };
+
+        assert!(plates.plates.iter().any(|p| p.continental));
+        assert!(plates.plates.iter().any(|p| !p.continental));
+        assert!(mean(true) > 0.0);
+        assert!(mean(false) < 0.0);
+    }
+
+    #[test]
+    fn tectonic_generator_stops_when_cancelled() {
+        use super::TectonicGenerator;
+        use crate::generate_world::generation::{GenerationError, WorldGenerator};
+
+        let progress = Progress::default();
+        progress.cancel();
+        assert_eq!(
+            TectonicGenerator::new(&settings(5, 128, 64), 4).get_heightmap(&progress),
+            Err(GenerationError::Cancelled)
+        );
+    }
+}
@@ -0,0 +1,297 @@
+use std::sync::{mpsc::Sender, Arc};
+
+use bevy::{
+    prelude::*,
+    render::{
+        render_resource::{Extent3d, TextureDimension, TextureFormat},
+        texture::ImageSampler,
+    },
+};
+
+use super::{
+    generation::{GenerationError, Job, Progress},
+    heightmap::Heightmap,
+    rivers::{RiverSettings, Rivers},
+};
+
+// Elevation of the water surface
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct SeaLevel(pub f32);
+
+impl Default for SeaLevel {
+    fn default() -> Self {
+        SeaLevel(0.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Water {
+    Land,
+    Ocean,
+    Lake,
+}
+
+// Bodies of water covering at least this fraction of the planet are oceans
+const OCEAN_FRACTION: f32 = 0.01;
+// Label of texels above sea level, which belong to no body of water
+const LAND: u32 = u32::MAX;
+
+// Classification of every texel of a heightmap into land, ocean and lakes
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct WaterMap {
+    pub width: u32,
+    pub height: u32,
+    pub sea_level: f32,
+    // Shared with the planet sampling them
+    pub cells: Arc<[Water]>,
+}
+
+impl WaterMap {
+    // Returns None if generation was cancelled through the progress handle
+    pub fn classify(map: &Heightmap, sea_level: f32, progress: &Progress) -> Option<Self> {
+        let len = map.data.len();
+        // Body of water each texel belongs to, labelled in place so no body is ever held as a
+        // list of its texels
+        let mut labels = vec![LAND; len];
+        let mut areas = Vec::new();
+        let mut stack = Vec::new();
+        let mut filled = 0;
+
+        // Flood fill every connected body of water below sea level
+        progress.start("water", map.height);
+        for start in 0..len {
+            if start % map.width as usize == 0 {
+                if progress.is_cancelled() {
+                    return None;
+                }
+                progress.advance(1);
+            }
+            if labels[start] != LAND || map.data[start] >= sea_level {
+                continue;
+            }
+
+            let body = areas.len() as u32;
+            let mut area = 0.0;
+            labels[start] = body;
+            stack.push(start);
+
+            while let Some(idx) = stack.pop() {
+                // A body can span most of the map, so the flood checks in as often as the scan
+                filled += 1;
+                if filled % map.width as usize == 0 && progress.is_cancelled() {
+                    return None;
+                }
+
+                let (x, y) = (
+                    (idx % map.width as usize) as isize,
+                    (idx / map.width as usize) as isize,
+                );
+                area += map.texel_area(y);
+
+                for (dx, dy) in [(-1, 0), (1, 0), (0, -1), (0, 1)] {
+                    let neighbour = map.index(x + dx, y + dy);
+                    if labels[neighbour] == LAND && map.data[neighbour] < sea_level {
+                        labels[neighbour] = body;
+                        stack.push(neighbour);
+                    }
+                }
+            }
+
+            areas.push(area);
+        }
+
+        // Small bodies are lakes, but a planet always gets at least one ocean
+        let largest = areas
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .map(|(i, _)| i);
+        let oceans: Vec<bool> = areas
+            .iter()
+            .enumerate()
+            .map(|(i, area)| *area >= OCEAN_FRACTION || Some(i) == largest)
+            .collect();
+
+        let cells = labels
+            .into_iter()
+            .map(|label| match label {
+                LAND => Water::Land,
+                body if oceans[body as usize] => Water::Ocean,
+                _ => Water::Lake,
+            })
+            .collect();
+
+        Some(WaterMap {
+            width: map.width,
+            height: map.height,
+            sea_level,
+            cells,
+        })
+    }
+
+    // Mask for the material, land is white so a missing mask shows no water
+    pub fn to_image(&self) -> Image {
+        let data = self
+            .cells
+            .iter()
+            .map(|water| match water {
+                Water::Land => 255,
+                Water::Lake => 128,
+                Water::Ocean => 0,
+            })
+            .collect();
+
+        let mut image = Image::new(
+            Extent3d {
+                width: self.width,
+                height: self.height,
+                ..default()
+            },
+            TextureDimension::D2,
+            data,
+            TextureFormat::R8Unorm,
+        );
+        image.sampler_descriptor = ImageSampler::nearest();
+        image
+    }
+}
+
+// Oceans and lakes at a sea level, along with the rivers running down to them, worked out on a
+// thread of its own as the whole map is flooded
+pub(crate) struct WaterJob {
+    pub elevation: Arc<Heightmap>,
+    pub sea_level: f32,
+    // Rivers to extract again, as they end where the sea now begins
+    pub rivers: Option<RiverSettings>,
+}
+
+impl Job for WaterJob {
+    type Output = (WaterMap, Option<Rivers>);
+
+    fn run(
+        self,
+        progress: &Progress,
+        _: &Sender<Image>,
+    ) -> Result<(WaterMap, Option<Rivers>), GenerationError> {
+        let water = WaterMap::classify(&self.elevation, self.sea_level, progress)
+            .ok_or(GenerationError::Cancelled)?;
+
+        let rivers = match self.rivers {
+            Some(settings) => {
+                let settings = RiverSettings {
+                    sea_level: self.sea_level,
+                    ..settings
+                };
+                Some(
+                    Rivers::extract(&self.elevation, &settings, progress)
+                        .ok_or(GenerationError::Cancelled)?,
+                )
+            }
+            None => None,
+        };
+
+        Ok((water, rivers))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{generation::Progress, heightmap::Heightmap};
+
+    // Land everywhere except a sea across the seam, deepest at the equator, and a single texel pit
+    fn coast(width: u32, height: u32) -> Heightmap {
+        let mut data = vec![0.5; (width * height) as usize];
+        let (w, h) = (width as isize, height as isize);
+
+        for y in 0..h {
+            for x in -4..4isize {
+                data[(y * w + x.rem_euclid(w)) as usize] = 0.1 * (y - h / 2).abs() as f32 - 1.5;
+            }
+        }
+        data[(height as usize / 2) * width as usize + width as usize / 2] = -0.05;
+
+        Heightmap::new(width, height, data)
+    }
+
+    #[test]
+    fn water_is_classified() {
+        use super::{Water, WaterMap};
+
+        let map = coast(64, 32);
+        let water = WaterMap::classify(&map, 0.0, &Progress::default()).unwrap();
+
+        assert_eq!(water.cells[map.index(0, 16)], Water::Ocean);
+        // The sea wraps around the seam as one body
+        assert_eq!(water.cells[map.index(-2, 3)], Water::Ocean);
+        assert_eq!(water.cells[map.index(32, 16)], Water::Lake);
+        assert_eq!(water.cells[map.index(16, 16)], Water::Land);
+    }
+
+    #[test]
+    fn sea_level_reclassifies() {
+        use super::{Water, WaterMap};
+
+        let map = coast(64, 32);
+        let low = WaterMap::classify(&map, -0.3, &Progress::default()).unwrap();
+        // Only the deepest part of the sea is left, and the pit is dry
+        assert_eq!(low.cells[map.index(0, 16)], Water::Ocean);
+        assert_eq!(low.cells[map.index(0, 2)], Water::Land);
+        assert_eq!(low.cells[map.index(32, 16)], Water::Land);
+
+        let high = WaterMap::classify(&map, 1.0, &Progress::default()).unwrap();
+        assert!(high.cells.iter().all(|w| *w == Water::Ocean));
+    }
+
+    #[test]
+    fn water_to_image() {
+        use super::WaterMap;
+        use bevy::prelude::*;
+
+        let map = coast(64, 32);
+        let img = WaterMap::classify(&map, 0.0, &Progress::default())
+            .unwrap()
+            .to_image();
+        assert_eq!(img.size(), Vec2::new(64., 32.));
+        assert_eq!(img.data[map.index(0, 16)], 0);
+        assert_eq!(img.data[map.index(32, 16)], 128);
+        assert_eq!(img.data[map.index(16, 16)], 255);
+    }
+
+    #[test]
+    fn water_job_moves_rivers_to_the_sea() {
+        use super::super::{generation::GenerationTask, rivers::RiverSettings};
+        use super::{WaterJob, WaterMap};
+        use std::sync::Arc;
+
+        let map = Arc::new(coast(64, 32));
+        let flood = |sea_level, rivers| {
+            futures_lite::future::block_on(GenerationTask::from_job(WaterJob {
+                elevation: map.clone(),
+                sea_level,
+                rivers,
+            }))
+            .unwrap()
+        };
+
+        let (water, rivers) = flood(-0.3, Some(RiverSettings::default()));
+        assert_eq!(
+            water,
+            WaterMap::classify(&map, -0.3, &Progress::default()).unwrap()
+        );
+        // What was sea at the lower level drains on to wherever the sea now begins
+        let rivers = rivers.unwrap();
+        assert_eq!(rivers.sea_level, -0.3);
+        assert_ne!(rivers.flow[map.index(0, 2)], u32::MAX);
+
+        assert_eq!(flood(0.0, None).1, None);
+    }
+
+    #[test]
+    fn water_stops_when_cancelled() {
+        use super::WaterMap;
+
+        let progress = Progress::default();
+        progress.cancel();
+        assert!(WaterMap::classify(&coast(64, 32), 0.0, &progress).is_none());
+    }
+}
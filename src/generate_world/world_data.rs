@@ -0,0 +1,265 @@
+use std::sync::Arc;
+
+use bevy::prelude::*;
+
+use super::{generation::Progress, heightmap::Heightmap, tectonics::PlateMap};
+
+// Latitudes, in radians, where the prevailing winds change direction
+const TRADE_WINDS: f32 = std::f32::consts::PI / 6.0;
+const POLAR_EASTERLIES: f32 = std::f32::consts::PI / 3.0;
+// Share of the moisture brought by the prevailing wind, the rest comes from the other way
+const PREVAILING: f32 = 0.75;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClimateSettings {
+    // Elevation below which texels are water, and source of all moisture
+    pub sea_level: f32,
+    // Temperatures at sea level, in degrees Celsius
+    pub equator_temperature: f32,
+    pub pole_temperature: f32,
+    // Cooling per unit of elevation above sea level, in degrees Celsius
+    pub lapse_rate: f32,
+    // Distance in radians over land after which air has lost most of its moisture
+    pub dry_distance: f32,
+    // Moisture lost per unit of elevation air is pushed up, making rain shadows behind mountains
+    pub orographic: f32,
+}
+
+impl Default for ClimateSettings {
+    fn default() -> Self {
+        ClimateSettings {
+            sea_level: 0.0,
+            equator_temperature: 30.0,
+            pole_temperature: -30.0,
+            lapse_rate: 40.0,
+            dry_distance: 0.25,
+            orographic: 10.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Layer {
+    Elevation,
+    // Mean temperature in degrees Celsius
+    Temperature,
+    // Relative humidity of the air, in [0, 1]
+    Moisture,
+}
+
+impl Layer {
+    pub const ALL: [Layer; 3] = [Layer::Elevation, Layer::Temperature, Layer::Moisture];
+}
+
+// Every layer of a generated world, sharing the equirectangular layout of the heightmap.
+// Layers are shared rather than copied with work done on them in the background.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorldData {
+    pub elevation: Arc<Heightmap>,
+    // Zero until the climate is computed
+    pub temperature: Arc<Heightmap>,
+    pub moisture: Arc<Heightmap>,
+    // Only generators simulating plate tectonics know about plates
+    pub plates: Option<PlateMap>,
+}
+
+impl WorldData {
+    pub fn new(elevation: Heightmap) -> Self {
+        let empty = Heightmap::new(
+            elevation.width,
+            elevation.height,
+            vec![0.0; elevation.data.len()],
+        );
+
+        WorldData {
+            temperature: Arc::new(empty.clone()),
+            moisture: Arc::new(empty),
+            elevation: Arc::new(elevation),
+            plates: None,
+        }
+    }
+
+    pub fn layer(&self, layer: Layer) -> &Heightmap {
+        match layer {
+            Layer::Elevation => &self.elevation,
+            Layer::Temperature => &self.temperature,
+            Layer::Moisture => &self.moisture,
+        }
+    }
+
+    // Value of a layer under a point on the unit sphere
+    pub fn sample(&self, layer: Layer, direction: Vec3) -> f32 {
+        self.layer(layer).sample(direction)
+    }
+
+    // Derive temperature and moisture from the elevation.
+    // Returns None if generation was cancelled through the progress handle
+    pub fn compute_climate(
+        &mut self,
+        settings: &ClimateSettings,
+        progress: &Progress,
+    ) -> Option<()> {
+        let (width, height) = (self.elevation.width, self.elevation.height);
+        let (temperature, moisture) = (
+            Arc::make_mut(&mut self.temperature),
+            Arc::make_mut(&mut self.moisture),
+        );
+
+        progress.start("climate", height);
+        for y in 0..height {
+            if progress.is_cancelled() {
+                return None;
+            }
+
+            let latitude = self.elevation.latitude(y as f32);
+            let row = (y * width) as usize..((y + 1) * width) as usize;
+            let elevation = &self.elevation.data[row.clone()];
+
+            // Colder towards the poles and higher up
+            let sea = settings.pole_temperature
+                + (settings.equator_temperature - settings.pole_temperature) * latitude.cos();
+            for (temperature, h) in temperature.data[row.clone()].iter_mut().zip(elevation) {
+                *temperature = sea - settings.lapse_rate * (h - settings.sea_level).max(0.0);
+            }
+
+            // Easterlies around the equator and the poles, westerlies in between
+            let wind = if latitude.abs() < TRADE_WINDS || latitude.abs() > POLAR_EASTERLIES {
+                -1
+            } else {
+                1
+            };
+            let spacing = std::f32::consts::TAU / width as f32 * latitude.cos();
+            let prevailing = carry_moisture(elevation, wind, spacing, settings);
+            let opposite = carry_moisture(elevation, -wind, spacing, settings);
+
+            for (x, moisture) in moisture.data[row].iter_mut().enumerate() {
+                *moisture = PREVAILING * prevailing[x] + (1.0 - PREVAILING) * opposite[x];
+            }
+            progress.advance(1);
+        }
+
+        Some(())
+    }
+}
+
+// Moisture of air blowing along a row in the direction `wind`, picking water up over the sea
+// and raining it out over land, all the more where it has to climb
+fn carry_moisture(
+    elevation: &[f32],
+    wind: isize,
+    spacing: f32,
+    settings: &ClimateSettings,
+) -> Vec<f32> {
+    let width = elevation.len() as isize;
+    let dry = (-spacing / settings.dry_distance).exp();
+    let mut moisture = vec![0.0; elevation.len()];
+
+    // Air going round the planet over land only is dry, otherwise start from the sea
+    let start = (0..width)
+        .find(|x| elevation[*x as usize] < settings.sea_level)
+        .unwrap_or(0);
+    let mut air = 0.0;
+    let mut previous = elevation[start as usize];
+
+    for step in 0..=width {
+        let x = (start + step * wind).rem_euclid(width) as usize;
+        let h = elevation[x];
+
+        if h < settings.sea_level {
+            air = 1.0;
+        } else {
+            let rise = (h - previous.max(settings.sea_level)).max(0.0);
+            air *= dry * (-rise * settings.orographic).exp();
+        }
+        moisture[x] = air;
+        previous = h;
+    }
+
+    moisture
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{
+        generation::Progress,
+        heightmap::{ElevationFormat, Heightmap},
+    };
+    use super::{ClimateSettings, Layer, WorldData};
+
+    // Sea on the western half, land rising into a ridge on the eastern half
+    fn continent(width: u32, height: u32) -> WorldData {
+        let mut data = Vec::with_capacity((width * height) as usize);
+        for _ in 0..height {
+            for x in 0..width {
+                data.push(if x < width / 2 {
+                    -0.5
+                } else if x == width * 3 / 4 {
+                    0.6
+                } else {
+                    0.1
+                });
+            }
+        }
+
+        let mut world = WorldData::new(Heightmap::new(width, height, data));
+        world
+            .compute_climate(&ClimateSettings::default(), &Progress::default())
+            .unwrap();
+        world
+    }
+
+    #[test]
+    fn temperature_falls_with_latitude_and_altitude() {
+        let world = continent(64, 32);
+        let map = &world.elevation;
+
+        // Poles are colder than the equator, at sea level and on land
+        assert!(world.temperature.get(0, 0) < world.temperature.get(0, 16));
+        assert!(world.temperature.get(40, 31) < world.temperature.get(40, 16));
+        // The ridge is colder than the plains next to it
+        assert!(world.temperature.get(48, 16) < world.temperature.get(40, 16));
+
+        let equator = world.sample(Layer::Temperature, map.direction(40.0, 15.5));
+        assert!(equator > 20.0 && equator <= 30.0);
+    }
+
+    #[test]
+    fn moisture_follows_the_wind() {
+        let world = continent(64, 32);
+
+        // Water is saturated, land dries out away from the coast
+        assert_eq!(world.moisture.get(10, 16), 1.0);
+        assert!(world.moisture.get(40, 16) < 1.0);
+        assert!(world.moisture.get(40, 16) > world.moisture.get(47, 16));
+        assert!(world.moisture.data.iter().all(|m| (0.0..=1.0).contains(m)));
+
+        // Behind the ridge, downwind of the westerlies, lies a rain shadow
+        let y = 24;
+        assert!(world.elevation.latitude(y as f32) > super::TRADE_WINDS);
+        assert!(world.elevation.latitude(y as f32) < super::POLAR_EASTERLIES);
+        assert!(world.moisture.get(49, y) < world.moisture.get(47, y) * 0.5);
+    }
+
+    #[test]
+    fn world_data_layers_to_image() {
+        use bevy::prelude::*;
+
+        let world = continent(64, 32);
+        for layer in Layer::ALL {
+            let img = world.layer(layer).to_image(ElevationFormat::Float32);
+            assert_eq!(img.size(), Vec2::new(64., 32.));
+            assert_eq!(&img.data[0..4], &world.layer(layer).data[0].to_le_bytes());
+        }
+    }
+
+    #[test]
+    fn climate_stops_when_cancelled() {
+        let progress = Progress::default();
+        progress.cancel();
+
+        let mut world = WorldData::new(Heightmap::new(8, 4, vec![0.0; 32]));
+        assert!(world
+            .compute_climate(&ClimateSettings::default(), &progress)
+            .is_none());
+    }
+}
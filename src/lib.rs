@@ -1,10 +1,19 @@
-use bevy::prelude::*;
+use bevy::{asset::AssetServerSettings, prelude::*};
 use heron::prelude::*;
 use iyes_loopless::prelude::*;
 
 mod generate_world;
 mod mainmenu;
 
+// Generators and the world data they raise, for crates adding their own generators, and the
+// planet gameplay samples
+pub use generate_world::{
+    Boundary, ClimateSettings, ElevationFormat, Filter, GenerationError, GeneratorChoice,
+    GeneratorEntry, GeneratorParams, GeneratorRegistry, Heightmap, Layer, MapLayout, ParamSchema,
+    ParamValue, Planet, Plate, PlateMap, Progress, RegistryError, WorldData, WorldGenSettings,
+    WorldGenerator,
+};
+
 // Plugin for the entire game
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Urbanite;
@@ -71,6 +80,11 @@ fn setup(mut commands: Commands, asst_server: Res<AssetServer>) {
 // Run the game
 pub fn run() {
     App::new()
+        // Pick up assets edited while the game runs, e.g. the noise graph raising the world
+        .insert_resource(AssetServerSettings {
+            watch_for_changes: true,
+            ..default()
+        })
         .add_plugins(DefaultPlugins)
         .add_plugin(Urbanite)
         .run();
@@ -1,5 +1,13 @@
-use crate::{GameState, UiRoot};
-use bevy::{app::AppExit, prelude::*};
+use std::marker::PhantomData;
+
+use crate::{
+    generate_world::{
+        GeneratorChoice, GeneratorParams, GeneratorRegistry, ParamValue, WorldCache,
+        CONTINENT_PRESETS,
+    },
+    GameState, UiRoot,
+};
+use bevy::{app::AppExit, ecs::system::SystemParam, prelude::*, tasks::IoTaskPool};
 use iyes_loopless::prelude::*;
 
 // Marker component for MainMenu UI items
@@ -29,6 +37,18 @@ struct QuitTag;
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Component)]
 struct PlayTag;
 
+// Enable a menu entity to pick the generator and layout of land the world is raised with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Component)]
+struct LayoutTag;
+
+// Enable a menu entity to clear the cache of generated worlds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Component)]
+struct ClearCacheTag;
+
+// Message shown once when the menu is next entered, e.g. why the last world failed
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct MenuMessage(pub String);
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub(crate) struct MainMenu;
 
@@ -45,6 +65,8 @@ impl Plugin for MainMenu {
                     .with_system(move_selection)
                     .with_system(selection_quit)
                     .with_system(selection_play)
+                    .with_system(selection_layout)
+                    .with_system(selection_clear_cache)
                     .with_system(rotate_cube)
                     .into(),
             )
@@ -96,6 +118,118 @@ fn selection_play(
     });
 }
 
+// Layouts offered on the menu, starting with the default one: every registered generator with its
+// default parameters, and continents once for each preset. Generators that need a value the menu
+// can't ask for, such as the file to import, are left to the command line.
+fn layouts(registry: &GeneratorRegistry) -> Vec<GeneratorChoice> {
+    let mut layouts: Vec<_> = registry
+        .iter()
+        .filter(|entry| {
+            !entry
+                .params
+                .iter()
+                .any(|schema| matches!(&schema.default, ParamValue::Text(text) if text.is_empty()))
+        })
+        .flat_map(|entry| {
+            let variants: Vec<_> = match entry.name {
+                "continents" => CONTINENT_PRESETS
+                    .iter()
+                    .map(|(preset, _)| {
+                        GeneratorParams::default()
+                            .with("preset", ParamValue::Text(preset.to_string()))
+                    })
+                    .collect(),
+                _ => vec![GeneratorParams::default()],
+            };
+            variants.into_iter().map(move |params| GeneratorChoice {
+                name: entry.name.into(),
+                params,
+            })
+        })
+        .collect();
+
+    // Stable, so the rest keep the order they were registered in
+    let default = GeneratorChoice::default();
+    layouts.sort_by_key(|choice| choice.name != default.name);
+    layouts
+}
+
+// Name of a layout as shown on the menu, the preset for continents and the generator otherwise
+fn layout_name(choice: &GeneratorChoice) -> String {
+    match choice.params.text("preset") {
+        Some(preset) if choice.name == "continents" => preset.into(),
+        _ => choice.name.clone(),
+    }
+}
+
+fn layout_text(choice: &GeneratorChoice) -> String {
+    format!("World: {}", layout_name(choice))
+}
+
+// Step through the layouts with Left and Right, or Return to go forward
+fn selection_layout(
+    keys: Res<Input<KeyCode>>,
+    registry: Res<GeneratorRegistry>,
+    mut choice: ResMut<GeneratorChoice>,
+    mut q: Query<&mut Text, (With<Selected>, With<LayoutTag>)>,
+) {
+    let step = if keys.just_pressed(KeyCode::Left) {
+        -1
+    } else if keys.just_pressed(KeyCode::Right) || keys.just_pressed(KeyCode::Return) {
+        1
+    } else {
+        return;
+    };
+
+    for mut text in &mut q {
+        let layouts = layouts(&registry);
+        let name = layout_name(&choice);
+        // Generators picked on the command line aren't offered, stepping from them starts over
+        let next = match layouts
+            .iter()
+            .position(|layout| layout_name(layout) == name)
+        {
+            Some(current) => (current as isize + step).rem_euclid(layouts.len() as isize),
+            None => 0,
+        };
+        *choice = layouts[next as usize].clone();
+
+        for section in &mut text.sections {
+            section.value = layout_text(&choice);
+        }
+    }
+}
+
+// Remove every cached world without holding up the menu
+fn selection_clear_cache(
+    keys: Res<Input<KeyCode>>,
+    cache: Option<Res<WorldCache>>,
+    mut q: Query<&mut Text, (With<Selected>, With<ClearCacheTag>)>,
+) {
+    // Nothing is cached without a cache directory
+    let cache = match cache {
+        Some(cache) if keys.just_pressed(KeyCode::Return) => cache,
+        _ => return,
+    };
+
+    for mut text in &mut q {
+        let cache = cache.clone();
+        IoTaskPool::get()
+            .spawn(async move {
+                let size = cache.size().unwrap_or_default();
+                match cache.clear() {
+                    Ok(()) => info!("Cleared {} MB of cached worlds", size >> 20),
+                    Err(err) => warn!("Could not clear cached worlds: {}", err),
+                }
+            })
+            .detach();
+
+        for section in &mut text.sections {
+            section.value = "Cache Cleared".into();
+        }
+    }
+}
+
 fn change_color(mut q: Query<(&mut Text, Added<Selected>)>) {
     let (mut text, _) = q.single_mut();
     for section in &mut text.sections {
@@ -125,14 +259,30 @@ fn rotate_cube(t: Res<Time>, mut q: Query<&mut Transform, With<Cube>>) {
     transform.rotate_axis(axis, speed * std::f32::consts::TAU * t.delta_seconds());
 }
 
+// What the menu's text is written with and about
+#[derive(SystemParam)]
+struct MenuText<'w, 's> {
+    uifont: Res<'w, crate::UiFont>,
+    choice: Res<'w, GeneratorChoice>,
+    message: Option<Res<'w, MenuMessage>>,
+    #[system_param(ignore)]
+    marker: PhantomData<&'s ()>,
+}
+
 fn menu_startup(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
-    uifont: Res<crate::UiFont>,
+    text: MenuText,
     q: Query<(Entity, &UiRoot)>,
     mut camera_q: Query<&mut Transform, With<crate::PlayerTag>>,
 ) {
+    let MenuText {
+        uifont,
+        choice,
+        message,
+        ..
+    } = text;
     let mut transform = camera_q.single_mut();
     *transform = Transform::from_xyz(-2.0, 2.5, 5.0).looking_at(Vec3::ZERO, Vec3::Z);
 
@@ -210,6 +360,60 @@ fn menu_startup(
         .insert(PlayTag)
         .id();
 
+    let layout = commands
+        .spawn_bundle(
+            TextBundle::from_section(
+                layout_text(&choice),
+                TextStyle {
+                    color: Color::WHITE,
+                    font: uifont.0.clone(),
+                    font_size: 24.0,
+                },
+            )
+            .with_text_alignment(TextAlignment::TOP_CENTER)
+            .with_style(Style {
+                position_type: PositionType::Absolute,
+                align_self: AlignSelf::FlexEnd,
+                position: UiRect {
+                    top: Val::Px(35.0),
+                    left: Val::Px(30.0),
+                    ..default()
+                },
+                ..default()
+            }),
+        )
+        .insert(LayoutTag)
+        .insert(MainMenuTag)
+        .insert(Previous(start))
+        .id();
+
+    let clear_cache = commands
+        .spawn_bundle(
+            TextBundle::from_section(
+                "Clear Cache",
+                TextStyle {
+                    color: Color::WHITE,
+                    font: uifont.0.clone(),
+                    font_size: 24.0,
+                },
+            )
+            .with_text_alignment(TextAlignment::TOP_CENTER)
+            .with_style(Style {
+                position_type: PositionType::Absolute,
+                align_self: AlignSelf::FlexEnd,
+                position: UiRect {
+                    top: Val::Px(65.0),
+                    left: Val::Px(30.0),
+                    ..default()
+                },
+                ..default()
+            }),
+        )
+        .insert(ClearCacheTag)
+        .insert(MainMenuTag)
+        .insert(Previous(layout))
+        .id();
+
     let quit = commands
         .spawn_bundle(
             TextBundle::from_section(
@@ -225,7 +429,7 @@ fn menu_startup(
                 position_type: PositionType::Absolute,
                 align_self: AlignSelf::FlexEnd,
                 position: UiRect {
-                    top: Val::Px(35.0),
+                    top: Val::Px(95.0),
                     left: Val::Px(30.0),
                     ..default()
                 },
@@ -235,13 +439,44 @@ fn menu_startup(
         .insert(QuitTag)
         .insert(MainMenuTag)
         .insert(Next(start))
-        .insert(Previous(start))
+        .insert(Previous(clear_cache))
         .id();
 
     commands
         .entity(start)
-        .insert(Next(quit))
+        .insert(Next(layout))
         .insert(Previous(quit));
+    commands.entity(layout).insert(Next(clear_cache));
+    commands.entity(clear_cache).insert(Next(quit));
 
-    commands.entity(uiroot).push_children(&[text, start, quit]);
+    commands
+        .entity(uiroot)
+        .push_children(&[text, start, layout, clear_cache, quit]);
+
+    if let Some(message) = message {
+        let message = commands
+            .spawn_bundle(
+                TextBundle::from_section(
+                    message.0.clone(),
+                    TextStyle {
+                        color: Color::ORANGE_RED,
+                        font: uifont.0.clone(),
+                        font_size: 24.0,
+                    },
+                )
+                .with_style(Style {
+                    position_type: PositionType::Absolute,
+                    position: UiRect {
+                        top: Val::Px(140.0),
+                        left: Val::Px(30.0),
+                        ..default()
+                    },
+                    ..default()
+                }),
+            )
+            .insert(MainMenuTag)
+            .id();
+        commands.entity(uiroot).add_child(message);
+        commands.remove_resource::<MenuMessage>();
+    }
 }
@@ -0,0 +1,62 @@
+// Counts allocations through a global allocator, which would count every test of the binary it
+// is in, so these tests get a binary of their own
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+};
+
+use urbanite::{ElevationFormat, Heightmap};
+
+// Counts the bytes allocated by each thread, so tests running in parallel don't see each other
+struct Counting;
+
+thread_local! {
+    static ALLOCATED: Cell<isize> = const { Cell::new(0) };
+    static PEAK: Cell<isize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATED.try_with(|allocated| {
+            allocated.set(allocated.get() + layout.size() as isize);
+            let _ = PEAK.try_with(|peak| peak.set(peak.get().max(allocated.get())));
+        });
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let _ =
+            ALLOCATED.try_with(|allocated| allocated.set(allocated.get() - layout.size() as isize));
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+// Most bytes held at once by this thread while running `f`, on top of what it held before
+fn peak_allocation<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let start = ALLOCATED.with(Cell::get);
+    PEAK.with(|peak| peak.set(start));
+    let result = f();
+    (result, (PEAK.with(Cell::get) - start) as usize)
+}
+
+// Encoding writes straight into the texture's bytes, without a copy of the map on the side
+#[test]
+fn heightmap_images_allocate_only_their_texels() {
+    let map = Heightmap::new(256, 128, vec![0.5; 256 * 128]);
+    let formats = [
+        ElevationFormat::Float32,
+        ElevationFormat::Float16,
+        ElevationFormat::Unorm16 { min: 0.0, max: 1.0 },
+    ];
+    for format in formats {
+        let (img, peak) = peak_allocation(|| map.to_image(format));
+        assert_eq!(img.data.len(), 256 * 128 * format.size());
+        assert!(peak <= img.data.len() + 4096, "{:?} took {}", format, peak);
+
+        let (img, peak) = peak_allocation(|| map.to_cube_image(format));
+        assert!(peak <= img.data.len() + 4096, "{:?} took {}", format, peak);
+    }
+}